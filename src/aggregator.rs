@@ -0,0 +1,128 @@
+use crate::bridge::{Bridge, BridgeMonitor};
+use crate::routing::RoutingMatrix;
+
+// Frames gathered from the inputs at a time; the buffers are sized for this
+// up front so that reading never allocates
+const MAX_BLOCK_FRAMES: usize = 1024;
+
+// Presents several independently clocked input devices as one multichannel
+// input and mixes it down to the output channels through a routing matrix.
+//
+// Every device gets its own `Bridge`; the virtual input channels are the
+// devices' channels laid side by side in the order the bridges were given.
+pub struct Aggregator {
+    inputs: Vec<Bridge>,
+    blocks: Vec<Vec<f32>>,
+    frames: Vec<f32>,
+    matrix: RoutingMatrix,
+}
+
+impl Aggregator {
+    pub fn new(inputs: Vec<Bridge>, matrix: RoutingMatrix) -> anyhow::Result<Self> {
+        let channels: usize = inputs.iter().map(Bridge::channels).sum();
+        anyhow::ensure!(channels > 0, "The inputs have no channels");
+        anyhow::ensure!(matrix.outputs() > 0, "The routing matrix has no output channels");
+        anyhow::ensure!(
+            channels == matrix.inputs(),
+            "The inputs have {channels} channels but the routing matrix expects {}",
+            matrix.inputs()
+        );
+        Ok(Self {
+            blocks: inputs.iter().map(|input| vec![0.0; MAX_BLOCK_FRAMES * input.channels()]).collect(),
+            inputs,
+            frames: vec![0.0; MAX_BLOCK_FRAMES * channels],
+            matrix,
+        })
+    }
+
+    pub fn input_channels(&self) -> usize {
+        self.matrix.inputs()
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.matrix.outputs()
+    }
+
+    pub fn monitors(&self) -> Vec<BridgeMonitor> {
+        self.inputs.iter().map(Bridge::monitor).collect()
+    }
+
+    // Fills all of `output`, interleaved with the matrix's output channels
+    pub fn read(&mut self, output: &mut [f32]) {
+        let outputs = self.output_channels();
+        for chunk in output.chunks_mut(MAX_BLOCK_FRAMES * outputs) {
+            self.read_block(chunk);
+        }
+    }
+
+    // Fills at most `MAX_BLOCK_FRAMES` frames of `output`
+    fn read_block(&mut self, output: &mut [f32]) {
+        let frames = output.len() / self.output_channels();
+        let width = self.input_channels();
+        let gathered = &mut self.frames[..frames * width];
+
+        let mut offset = 0;
+        for (input, block) in self.inputs.iter_mut().zip(&mut self.blocks) {
+            let channels = input.channels();
+            let block = &mut block[..frames * channels];
+            input.read(block);
+            for (frame, samples) in gathered.chunks_exact_mut(width).zip(block.chunks_exact(channels)) {
+                frame[offset..offset + channels].copy_from_slice(samples);
+            }
+            offset += channels;
+        }
+
+        self.matrix.process(gathered, output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::{BridgeConfigBuilder, Concealment};
+    use crate::routing::Route;
+
+    #[test]
+    fn test_aggregates_and_routes() {
+        let mut inputs = Vec::new();
+        let mut producers = Vec::new();
+        for (channels, rate) in [(1, 48000), (2, 44100)] {
+            let (bridge, producer) = Bridge::with_config(
+                BridgeConfigBuilder::default()
+                    .input_rate(rate)
+                    .output_rate(48000)
+                    .channels(channels)
+                    .target_fill(32)
+                    .capacity(8192)
+                    .concealment(Concealment::Silence)
+                    .drift_compensation(false)
+                    .build()
+                    .expect("Failed to build BridgeConfig"),
+            )
+            .unwrap();
+            inputs.push(bridge);
+            producers.push(producer);
+        }
+
+        // Device 0 to the left, the first channel of device 1 to the right
+        // and the second channel of device 1 to both at half level
+        let routes: Vec<Route> = ["0:0", "1:1", "2:0=0.5", "2:1=0.5"].iter().map(|s| s.parse().unwrap()).collect();
+        let mut aggregator = Aggregator::new(inputs, RoutingMatrix::with_routes(3, 2, &routes).unwrap()).unwrap();
+        assert!(Aggregator::new(Vec::new(), RoutingMatrix::spread(3, 2).unwrap()).is_err());
+        assert!(Aggregator::new(Vec::new(), RoutingMatrix::new(0, 2)).is_err());
+        assert!(Aggregator::new(Vec::new(), RoutingMatrix::new(0, 0)).is_err());
+
+        producers[0].push_slice(&[0.25; 5000]);
+        producers[1].push_slice(&[0.5, -0.5].repeat(5000));
+        // Longer than one block, so reading goes through it in pieces
+        let mut output = vec![0.0; 2 * 1500];
+        for _ in 0..3 {
+            aggregator.read(&mut output);
+        }
+
+        let last = &output[output.len() - 2..];
+        assert!((last[0] - 0.0).abs() < 1e-3, "{last:?}");
+        assert!((last[1] - 0.25).abs() < 1e-3, "{last:?}");
+        assert!(aggregator.monitors().iter().all(|m| m.xruns() == 0));
+    }
+}
@@ -0,0 +1,220 @@
+use derive_builder::Builder;
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::window::Window;
+
+#[derive(Builder)]
+pub struct AnalysisConfig {
+    sample_rate: u32,
+    channels: usize,
+    // Length of each analysed segment
+    #[builder(default = "Duration::from_millis(100)")]
+    window: Duration,
+    // Distance between the starts of consecutive segments
+    #[builder(default = "self.window.unwrap_or(Duration::from_millis(100))")]
+    hop: Duration,
+    // Segments quieter than this are reported as silent
+    #[builder(default = "-60.0")]
+    silence_threshold: f32,
+    // Samples at or above this magnitude count as clipped
+    #[builder(default = "0.999")]
+    clip_level: f32,
+}
+
+// Metrics of one segment. Level and spectrum come from the channels mixed
+// to mono; peak, DC offset and clipping look at every channel.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Segment {
+    pub index: usize,
+    // Seconds from the start of the file
+    pub start: f64,
+    pub rms: f32,
+    pub dbfs: f32,
+    pub peak: f32,
+    pub dc_offset: f32,
+    pub clipped: usize,
+    pub silent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dominant_frequency: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    pub duration: f64,
+    pub segments: usize,
+    pub peak: f32,
+    pub peak_dbfs: f32,
+    pub rms: f32,
+    pub dbfs: f32,
+    pub dc_offset: f32,
+    pub clipped: usize,
+    pub silent_segments: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Analysis {
+    pub summary: Summary,
+    pub segments: Vec<Segment>,
+}
+
+// Runs the `Window` metrics over interleaved samples, one segment per hop
+pub fn analyze(samples: &[f32], config: &AnalysisConfig) -> Analysis {
+    let channels = config.channels.max(1);
+    let sample_rate = config.sample_rate as f64;
+    let mono: Vec<f32> = samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    let window_frames = (config.window.as_secs_f64() * sample_rate) as usize;
+    let hop_frames = ((config.hop.as_secs_f64() * sample_rate) as usize).max(1);
+
+    let mut window = Window::with_duration(config.window, config.sample_rate);
+    let mut segments = Vec::new();
+    let mut added = 0;
+    let mut start = 0;
+    while window_frames > 0 && start + window_frames <= mono.len() {
+        let end = start + window_frames;
+        // The window keeps the latest samples, so only what is new since
+        // the last segment has to be added
+        window.add_samples(&mono[added.max(start)..end]);
+        added = end;
+
+        let interleaved = &samples[start * channels..end * channels];
+        let rms = window.calculate_rms().unwrap_or_default();
+        let dbfs = window.calculate_dbfs().unwrap_or(f32::NEG_INFINITY);
+        let silent = dbfs < config.silence_threshold;
+        let dominant_frequency = if silent {
+            None
+        } else {
+            window.calculate_spectrum().and_then(|spectrum| {
+                spectrum
+                    .into_iter()
+                    .skip(1)
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(frequency, _)| frequency)
+            })
+        };
+        segments.push(Segment {
+            index: segments.len(),
+            start: start as f64 / sample_rate,
+            rms,
+            dbfs,
+            peak: peak(interleaved),
+            dc_offset: mean(interleaved),
+            clipped: count_clipped(interleaved, config.clip_level),
+            silent,
+            dominant_frequency,
+        });
+        start += hop_frames;
+    }
+
+    let peak = peak(samples);
+    let rms = (mono.iter().map(|&s| s as f64 * s as f64).sum::<f64>() / mono.len().max(1) as f64).sqrt() as f32;
+    let to_dbfs = |value: f32| (20.0 * (value as f64 + 1e-10).log10()) as f32;
+    Analysis {
+        summary: Summary {
+            duration: mono.len() as f64 / sample_rate,
+            segments: segments.len(),
+            peak,
+            peak_dbfs: to_dbfs(peak),
+            rms,
+            dbfs: to_dbfs(rms),
+            dc_offset: mean(samples),
+            clipped: count_clipped(samples, config.clip_level),
+            silent_segments: segments.iter().filter(|segment| segment.silent).count(),
+        },
+        segments,
+    }
+}
+
+fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0, |peak, &s| peak.max(s.abs()))
+}
+
+fn mean(samples: &[f32]) -> f32 {
+    (samples.iter().map(|&s| s as f64).sum::<f64>() / samples.len().max(1) as f64) as f32
+}
+
+fn count_clipped(samples: &[f32], level: f32) -> usize {
+    samples.iter().filter(|s| s.abs() >= level).count()
+}
+
+impl Analysis {
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    // One row per segment; the summary is left to the caller
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("index,start,rms,dbfs,peak,dc_offset,clipped,silent,dominant_frequency\n");
+        for segment in &self.segments {
+            csv.push_str(&format!(
+                "{},{:.6},{:.6},{:.2},{:.6},{:.6},{},{},{}\n",
+                segment.index,
+                segment.start,
+                segment.rms,
+                segment.dbfs,
+                segment.peak,
+                segment.dc_offset,
+                segment.clipped,
+                segment.silent,
+                segment.dominant_frequency.map(|f| format!("{f:.1}")).unwrap_or_default(),
+            ));
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze_segments() {
+        // Half a second of a clipped 1 kHz tone over a DC offset on the left
+        // channel, then half a second of silence, in stereo at 8 kHz
+        let mut samples = Vec::new();
+        for i in 0..8000 {
+            let tone = if i < 4000 {
+                (1.2 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 8000.0).sin()).clamp(-1.0, 1.0)
+            } else {
+                0.0
+            };
+            samples.extend([tone + if i < 4000 { 0.1 } else { 0.0 }, tone]);
+        }
+        let analysis = analyze(
+            &samples,
+            &AnalysisConfigBuilder::default()
+                .sample_rate(8000)
+                .channels(2)
+                .window(Duration::from_millis(250))
+                .hop(Duration::from_millis(125))
+                .build()
+                .unwrap(),
+        );
+
+        assert_eq!(analysis.segments.len(), 7);
+        let first = &analysis.segments[0];
+        assert!(!first.silent);
+        assert_eq!(first.peak, 1.1);
+        assert!((first.dc_offset - 0.05).abs() < 1e-3);
+        // Both peaks of each 8-sample period clip on the right, only the
+        // positive one on the left
+        assert_eq!(first.clipped, 750);
+        let frequency = first.dominant_frequency.unwrap();
+        assert!((frequency - 1000.0).abs() < 8.0, "{frequency} Hz");
+
+        let straddling = &analysis.segments[3];
+        assert_eq!(straddling.start, 0.375);
+        assert!(!straddling.silent);
+        let last = &analysis.segments[6];
+        assert!(last.silent && last.dominant_frequency.is_none() && last.peak == 0.0);
+
+        assert_eq!(analysis.summary.segments, 7);
+        assert_eq!(analysis.summary.silent_segments, 3);
+        assert_eq!(analysis.summary.clipped, 1500);
+        assert_eq!(analysis.summary.duration, 1.0);
+        assert!(analysis.to_csv().lines().nth(1).unwrap().starts_with("0,0.000000,"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use cpal_toy::analysis::{analyze, AnalysisConfigBuilder};
+use cpal_toy::cli::parse_duration;
+use cpal_toy::render::read_wav;
+
+/// Reports level, peak, DC offset, clipping, silence and the dominant
+/// frequency of a WAV file, segment by segment
+#[derive(Parser)]
+struct Args {
+    /// WAV file to analyse
+    input: PathBuf,
+    /// Length of each segment, e.g. 100ms
+    #[arg(short, long, value_parser = parse_duration, default_value = "100ms")]
+    window: Duration,
+    /// Distance between segment starts; defaults to the window length
+    #[arg(long, value_parser = parse_duration)]
+    hop: Option<Duration>,
+    /// Segments below this level in dBFS are silent
+    #[arg(long, default_value_t = -60.0, allow_negative_numbers = true)]
+    silence_threshold: f32,
+    /// Samples at or above this magnitude count as clipped
+    #[arg(long, default_value_t = 0.999)]
+    clip_level: f32,
+    /// Report format
+    #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+    /// Write the report here instead of to stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// One row per segment
+    Csv,
+    /// Summary and segments
+    Json,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let wav = read_wav(&args.input)?;
+
+    let analysis = analyze(
+        &wav.samples,
+        &AnalysisConfigBuilder::default()
+            .sample_rate(wav.sample_rate)
+            .channels(wav.channels)
+            .window(args.window)
+            .hop(args.hop.unwrap_or(args.window))
+            .silence_threshold(args.silence_threshold)
+            .clip_level(args.clip_level)
+            .build()?,
+    );
+
+    let report = match args.format {
+        Format::Csv => analysis.to_csv(),
+        Format::Json => analysis.to_json()? + "\n",
+    };
+    match &args.output {
+        Some(path) => std::fs::write(path, report).with_context(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{report}"),
+    }
+
+    // The summary goes to stderr so that the report can be piped
+    let summary = &analysis.summary;
+    eprintln!(
+        "{}: {:.3} s, {} channels at {} Hz",
+        args.input.display(),
+        summary.duration,
+        wav.channels,
+        wav.sample_rate
+    );
+    eprintln!(
+        "peak {:.2} dBFS, RMS {:.2} dBFS, DC offset {:+.5}, {} clipped samples, {} of {} segments silent",
+        summary.peak_dbfs, summary.dbfs, summary.dc_offset, summary.clipped, summary.silent_segments, summary.segments
+    );
+
+    Ok(())
+}
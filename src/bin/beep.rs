@@ -1,92 +1,165 @@
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    FromSample, Sample, SizedSample,
-};
+use cpal::traits::{DeviceTrait, HostTrait};
 
-use cpal_toy::{TonePlayerConfigBuilder, TonePlayer};
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use cpal_toy::cli::{parse_duration, StepSpec};
+use cpal_toy::generator::{Generator, GeneratorConfigBuilder};
+use cpal_toy::playback::{play_sequence, tone_sequence};
+use cpal_toy::render::{render_to_file, RenderConfigBuilder, WavFormat};
 
-fn main() -> anyhow::Result<()> {
-
-    let host = cpal::default_host();
+// Played when no steps are given
+const DEFAULT_STEP: &str = "440+880@0.5+1320@0.5";
+const DEFAULT_RENDER_SAMPLE_RATE: u32 = 48000;
 
-    let device = host.default_output_device().ok_or(anyhow::anyhow!("Failed to get default device"))?;
-    println!("Output device: {}", device.name()?);
+/// Plays tones one step after another, or renders them to a WAV file.
+///
+/// Every step is TONE[+TONE...][:DURATION] and every tone FREQ[/WAVEFORM][@AMPLITUDE],
+/// with sine, square, triangle or sawtooth waveforms. `beep -d 1.5s 440:1s 440+660/square@0.3:500ms`
+/// plays a second of A4 and then half a second of a chord with a quieter square wave.
+/// The steps start over until --duration has passed.
+#[derive(Parser)]
+struct Args {
+    /// Steps to play in order; defaults to an A major chord
+    steps: Vec<StepSpec>,
+    /// How long to play or render, e.g. 5s, 250ms or 2.5
+    #[arg(short, long, value_parser = parse_duration, default_value = "8s")]
+    duration: Duration,
+    /// Duration of steps that do not give their own
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    step_duration: Duration,
+    /// Audio host, e.g. ALSA or JACK; defaults to the default host
+    #[arg(long)]
+    host: Option<String>,
+    /// Output device name; defaults to the host's default output device
+    #[arg(long)]
+    device: Option<String>,
+    /// Sample rate to open the device or render the file at
+    #[arg(long)]
+    sample_rate: Option<u32>,
+    /// Frames per callback; defaults to the device's choice
+    #[arg(long)]
+    buffer_size: Option<u32>,
+    /// Render to this WAV file instead of playing
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Channels of the rendered file
+    #[arg(long, default_value_t = 2, requires = "output")]
+    channels: u16,
+    /// Sample format of the rendered file
+    #[arg(long, value_enum, default_value_t = Format::F32, requires = "output")]
+    format: Format,
+}
 
-    let config = device.default_output_config().unwrap();
-    println!("Default output config: {config:?}");
-    println!("Calling from {:?}", std::thread::current().id());
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    I16,
+    I24,
+    I32,
+    F32,
+}
 
-    match config.sample_format() {
-        cpal::SampleFormat::I8 => run::<i8>(&device, &mut config.into()),
-        cpal::SampleFormat::I16 => run::<i16>(&device, &mut config.into()),
-        cpal::SampleFormat::I32 => run::<i32>(&device, &mut config.into()),
-        // cpal::SampleFormat::I48 => run::<I48>(&device, &config.into()),
-        cpal::SampleFormat::I64 => run::<i64>(&device, &mut config.into()),
-        cpal::SampleFormat::U8 => run::<u8>(&device, &mut config.into()),
-        cpal::SampleFormat::U16 => run::<u16>(&device, &mut config.into()),
-        // cpal::SampleFormat::U24 => run::<U24>(&device, &config.into()),
-        cpal::SampleFormat::U32 => run::<u32>(&device, &mut config.into()),
-        // cpal::SampleFormat::U48 => run::<U48>(&device, &config.into()),
-        cpal::SampleFormat::U64 => run::<u64>(&device, &mut config.into()),
-        cpal::SampleFormat::F32 => run::<f32>(&device, &mut config.into()),
-        cpal::SampleFormat::F64 => run::<f64>(&device, &mut config.into()),
-        sample_format => panic!("Unsupported sample format '{sample_format}'"),
+impl From<Format> for WavFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::I16 => WavFormat::I16,
+            Format::I24 => WavFormat::I24,
+            Format::I32 => WavFormat::I32,
+            Format::F32 => WavFormat::F32,
+        }
     }
 }
 
-pub fn run<T>(device: &cpal::Device, config: &mut cpal::StreamConfig) -> Result<(), anyhow::Error>
-where
-    T: SizedSample + FromSample<f32> + std::ops::AddAssign
-{
-    config.buffer_size = cpal::BufferSize::Fixed(32);
-    let mut player1 = TonePlayer::with_config(
-        TonePlayerConfigBuilder::default()
-            .frequency(440.0)
-            .sample_rate(config.sample_rate.0 as u32)
-            .channels(config.channels as usize)
-            .build()?,
-    );
-    let mut player2 = TonePlayer::with_config(
-        TonePlayerConfigBuilder::default()
-            .frequency(880.0)
-            .factor(0.5)
-            .sample_rate(config.sample_rate.0 as u32)
-            .channels(config.channels as usize)
-            .mix(true)
-            .build()?,
-    );
-    let mut player3 = TonePlayer::with_config(
-        TonePlayerConfigBuilder::default()
-            .frequency(1320.0)
-            .factor(0.5)
-            .sample_rate(config.sample_rate.0 as u32)
-            .channels(config.channels as usize)
-            .mix(true)
-            .build()?,
-    );
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    if args.steps.is_empty() {
+        args.steps.push(DEFAULT_STEP.parse()?);
+    }
+
+    if let Some(path) = &args.output {
+        let sample_rate = args.sample_rate.unwrap_or(DEFAULT_RENDER_SAMPLE_RATE);
+        let sequence = tone_sequence(&args.steps, args.step_duration, args.duration, sample_rate)?;
+        let config = RenderConfigBuilder::default()
+            .sample_rate(sample_rate)
+            .channels(args.channels)
+            .duration(args.duration)
+            .format(args.format.into())
+            .build()?;
+        let mut generator = Generator::with_config(
+            GeneratorConfigBuilder::default().channels(args.channels as usize).build()?,
+            sequence,
+        );
+        let frames = render_to_file(path, &config, |buffer| generator.fill_buffer(buffer))?;
+        println!("Rendered {frames} frames to {}", path.display());
+        return Ok(());
+    }
+
+    let host = match &args.host {
+        Some(name) => {
+            let id = cpal::available_hosts()
+                .into_iter()
+                .find(|id| id.name().eq_ignore_ascii_case(name))
+                .with_context(|| format!("No host named '{name}'"))?;
+            cpal::host_from_id(id)?
+        }
+        None => cpal::default_host(),
+    };
+
+    let device = match &args.device {
+        Some(name) => host
+            .output_devices()?
+            .find(|device| device.name().is_ok_and(|n| &n == name))
+            .with_context(|| format!("No output device named '{name}'"))?,
+        None => host.default_output_device().context("Failed to get default device")?,
+    };
+    println!("Output device: {}", device.name()?);
 
-    let err_fn = |err| eprintln!("an error occurred on stream: {err}");
+    let config = output_config(&device, args.sample_rate)?;
+    println!("Output config: {config:?}");
 
-    let stream = device.build_output_stream(
-        config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            write_data(data, &mut player1, &mut player2, &mut player3);
-        },
-        err_fn,
-        None,
-    )?;
-    stream.play()?;
+    let mut stream_config: cpal::StreamConfig = config.config();
+    if let Some(frames) = args.buffer_size {
+        stream_config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    let sequence = tone_sequence(&args.steps, args.step_duration, args.duration, stream_config.sample_rate.0)?;
 
-    std::thread::sleep(std::time::Duration::from_millis(8000));
+    let sleep = std::thread::sleep;
+    let timing = match config.sample_format() {
+        cpal::SampleFormat::I8 => play_sequence::<_, i8>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::I16 => play_sequence::<_, i16>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::I32 => play_sequence::<_, i32>(&device, &stream_config, sequence, sleep),
+        // cpal::SampleFormat::I48 => play_sequence::<_, I48>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::I64 => play_sequence::<_, i64>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::U8 => play_sequence::<_, u8>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::U16 => play_sequence::<_, u16>(&device, &stream_config, sequence, sleep),
+        // cpal::SampleFormat::U24 => play_sequence::<_, U24>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::U32 => play_sequence::<_, u32>(&device, &stream_config, sequence, sleep),
+        // cpal::SampleFormat::U48 => play_sequence::<_, U48>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::U64 => play_sequence::<_, u64>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::F32 => play_sequence::<_, f32>(&device, &stream_config, sequence, sleep),
+        cpal::SampleFormat::F64 => play_sequence::<_, f64>(&device, &stream_config, sequence, sleep),
+        sample_format => panic!("Unsupported sample format '{sample_format}'"),
+    }?;
+    println!("Output timing: {timing}");
 
     Ok(())
 }
 
-fn write_data<T>(output: &mut [T], player1: &mut TonePlayer, player2: &mut TonePlayer, player3: &mut TonePlayer)
-where
-    T: Sample + FromSample<f32> + std::ops::AddAssign
-{
-    player1.fill_buffer(output);
-    player2.fill_buffer(output);
-    player3.fill_buffer(output);
+// The default output configuration, moved to `sample_rate` if one is given.
+// Ranges with the default channel count and sample format are tried first.
+fn output_config(device: &cpal::Device, sample_rate: Option<u32>) -> anyhow::Result<cpal::SupportedStreamConfig> {
+    let default = device.default_output_config().context("Failed to get default output config")?;
+    let Some(rate) = sample_rate.filter(|&rate| rate != default.sample_rate().0) else {
+        return Ok(default);
+    };
+
+    let mut ranges: Vec<_> = device.supported_output_configs()?.collect();
+    ranges.sort_by_key(|range| {
+        (range.channels() != default.channels(), range.sample_format() != default.sample_format())
+    });
+    ranges
+        .into_iter()
+        .find_map(|range| range.try_with_sample_rate(cpal::SampleRate(rate)))
+        .with_context(|| format!("The device does not support {rate} Hz"))
 }
@@ -0,0 +1,124 @@
+use cpal::traits::DeviceTrait;
+use std::time::Duration;
+use anyhow::Context;
+use clap::Parser;
+use cpal_toy::cli::parse_duration;
+use cpal_toy::device::{find_device, Direction};
+use cpal_toy::distortion::{analyze_distortion, DistortionConfigBuilder};
+use cpal_toy::loopback::{play_tone, Capture, SETTLE};
+
+// The tone played in loopback mode when no frequency is given; not a divisor
+// of common sample rates, so that its harmonics do not land on the same
+// sample values every period and hide the converter's errors
+const DEFAULT_FREQUENCY: f32 = 997.0;
+
+/// Measures THD, THD+N, SNR and SINAD of a test tone arriving at an input
+/// device, window after window.
+///
+/// With --loopback the tone is played on an output device as well, so that
+/// an interface can be characterised with a cable from its output to its
+/// input. Otherwise the tone has to come from elsewhere.
+#[derive(Parser)]
+struct Args {
+    /// Play the test tone on the output device
+    #[arg(short, long)]
+    loopback: bool,
+    /// Input device name; defaults to the default input device
+    #[arg(short, long)]
+    input: Option<String>,
+    /// Output device name; defaults to the default output device
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Input channel to analyse, counting from 0
+    #[arg(short, long, default_value_t = 0)]
+    channel: usize,
+    /// Frequency of the test tone in Hz; without --loopback, the strongest
+    /// component is analysed unless this is given
+    #[arg(short, long)]
+    frequency: Option<f32>,
+    /// Linear level of the played test tone
+    #[arg(long, default_value_t = 0.5)]
+    level: f32,
+    /// Length of every analysed window; longer ones resolve lower noise
+    #[arg(short, long, value_parser = parse_duration, default_value = "1s")]
+    window: Duration,
+    /// Highest harmonic to measure, counting the fundamental as the first
+    #[arg(short = 'n', long, default_value_t = 10)]
+    harmonics: usize,
+    /// Lower edge of the measurement band in Hz
+    #[arg(long, default_value_t = 20.0)]
+    low: f64,
+    /// Upper edge of the measurement band in Hz
+    #[arg(long, default_value_t = 20000.0)]
+    high: f64,
+    /// Number of windows to analyse; runs until interrupted otherwise
+    #[arg(long)]
+    count: Option<usize>,
+    /// Also print the level of every harmonic
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let host = cpal::default_host();
+
+    let input_device = find_device(&host, Direction::Input, args.input.as_deref())?;
+    let input_config = input_device.default_input_config().context("Failed to get default input config")?;
+    let sample_rate = input_config.sample_rate().0;
+    println!("Input device: {} ({input_config:?})", input_device.name()?);
+
+    let frames = (args.window.as_secs_f64() * sample_rate as f64) as usize;
+    anyhow::ensure!(frames >= 64, "A window of {:?} is too short to analyse", args.window);
+
+    let (frequency, output_stream) = if args.loopback {
+        let frequency = args.frequency.unwrap_or(DEFAULT_FREQUENCY);
+        let output_device = find_device(&host, Direction::Output, args.output.as_deref())?;
+        let output_config = output_device.default_output_config().context("Failed to get default output config")?;
+        println!("Output device: {} ({output_config:?})", output_device.name()?);
+
+        (Some(frequency), Some(play_tone(&output_device, &output_config.into(), frequency, args.level)?))
+    } else {
+        (args.frequency, None)
+    };
+
+    let mut capture = Capture::start(&input_device, &input_config.into(), args.channel, frames * 2)?;
+
+    let config = DistortionConfigBuilder::default()
+        .sample_rate(sample_rate)
+        .harmonics(args.harmonics)
+        .fundamental(frequency.map(f64::from))
+        .low(args.low)
+        .high(args.high.min(sample_rate as f64 / 2.0))
+        .build()?;
+
+    std::thread::sleep(SETTLE);
+    capture.discard();
+
+    let mut window = vec![0.0; frames];
+    let mut analysed = 0;
+    while args.count.is_none_or(|count| analysed < count) {
+        capture.record(&mut window, std::thread::sleep)?;
+        analysed += 1;
+
+        match analyze_distortion(&window, &config) {
+            Some(report) => {
+                println!("{report}");
+                if args.verbose {
+                    for harmonic in &report.harmonics {
+                        println!(
+                            "  H{}: {:.1} Hz at {:.1} dB",
+                            harmonic.order,
+                            harmonic.frequency,
+                            harmonic.relative_db(&report.fundamental)
+                        );
+                    }
+                }
+            }
+            None => println!("No tone found in the window"),
+        }
+    }
+
+    drop(output_stream);
+    Ok(())
+}
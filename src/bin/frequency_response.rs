@@ -0,0 +1,255 @@
+use ratatui::prelude::*;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::{
+    layout::{Constraint, Layout},
+    widgets::{Axis, Chart, Dataset, GraphType},
+    symbols::Marker,
+};
+use std::time::Duration;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use cpal_toy::cli::parse_duration;
+use cpal_toy::impulse::Sweep;
+use cpal_toy::loopback::{common_rate, Loopback, LoopbackDevices, MAX_LATENCY, SETTLE};
+use cpal_toy::response::{log_frequencies, Excitation, FrequencyResponse, SteppedSine, IMPULSE_RESPONSE_LENGTH};
+
+// Frequencies a swept response is smoothed onto for plotting
+const PLOT_POINTS: usize = 400;
+// Range of the magnitude chart below its top
+const MAGNITUDE_RANGE_DB: f64 = 60.0;
+
+/// Measures the magnitude and phase response of whatever lies between an
+/// output and an input device, such as a loopback cable, an interface or a
+/// loudspeaker and microphone, and plots it on a logarithmic frequency axis.
+///
+/// 0 dB is unity gain from the output to the input.
+#[derive(Parser)]
+struct Args {
+    /// Input device name; defaults to the default input device
+    #[arg(short, long)]
+    input: Option<String>,
+    /// Output device name; defaults to the default output device
+    #[arg(short, long)]
+    output: Option<String>,
+    /// How to excite the system
+    #[arg(short, long, value_enum, default_value_t = Method::Sweep)]
+    method: Method,
+    /// Lowest frequency to measure in Hz
+    #[arg(long, default_value_t = 20.0)]
+    start: f64,
+    /// Highest frequency to measure in Hz
+    #[arg(long, default_value_t = 20000.0)]
+    end: f64,
+    /// Length of the sweep
+    #[arg(short, long, value_parser = parse_duration, default_value = "3s")]
+    duration: Duration,
+    /// Number of sine steps per octave
+    #[arg(long, default_value_t = 3)]
+    steps_per_octave: usize,
+    /// Length of every sine step
+    #[arg(long, value_parser = parse_duration, default_value = "200ms")]
+    step: Duration,
+    /// Linear playback level
+    #[arg(short, long, default_value_t = 0.5)]
+    level: f32,
+    /// Smoothing bandwidth as a fraction of an octave, e.g. 6 for 1/6 octave;
+    /// 0 shows the response unsmoothed
+    #[arg(short, long, default_value_t = 6.0)]
+    smoothing: f64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Method {
+    /// Exponential sine sweep, deconvolved into an impulse response
+    Sweep,
+    /// One sine per frequency in turn; slower, but robust against noise
+    Steps,
+}
+
+// What the charts show
+struct Plot {
+    title: String,
+    magnitude: Vec<(f64, f64)>,
+    phase: Vec<(f64, f64)>,
+    message: String,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let host = cpal::default_host();
+
+    let devices = LoopbackDevices::find(&host, args.output.as_deref(), args.input.as_deref())?;
+    let output_config: cpal::StreamConfig = devices.output_config.into();
+    let input_config: cpal::StreamConfig = devices.input_config.into();
+    // Both methods compare the recording with what was played, sample for sample
+    let sample_rate = common_rate(&output_config, &input_config)?;
+    anyhow::ensure!(
+        0.0 < args.start && args.start < args.end && args.end < sample_rate as f64 / 2.0,
+        "The frequencies must rise from above 0 Hz to below half the sample rate ({} Hz)",
+        sample_rate / 2
+    );
+
+    let excitation = match args.method {
+        Method::Sweep => Excitation::Sweep(Sweep::new(args.start, args.end, args.duration, sample_rate)),
+        Method::Steps => {
+            // The demodulation needs whole periods to average over, even at
+            // the lowest frequency
+            anyhow::ensure!(
+                args.step.as_secs_f64() * args.start >= 2.0,
+                "A step of {:?} is shorter than two periods of {} Hz",
+                args.step,
+                args.start
+            );
+            let steps = ((args.end / args.start).log2() * args.steps_per_octave as f64).round() as usize + 1;
+            Excitation::Steps(SteppedSine::new(log_frequencies(args.start, args.end, steps), args.step, sample_rate))
+        }
+    };
+    let frames = excitation.samples().len()
+        + ((IMPULSE_RESPONSE_LENGTH + MAX_LATENCY).as_secs_f64() * sample_rate as f64) as usize;
+
+    let mut loopback = Loopback::start(
+        &devices.output,
+        &output_config,
+        &devices.input,
+        &input_config,
+        excitation.samples().to_vec(),
+        args.level,
+        frames,
+    )?;
+    std::thread::sleep(SETTLE);
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut loopback, frames, &excitation, &args);
+    ratatui::restore();
+    result.context("Failed to run the measurement")
+}
+
+fn run(
+    terminal: &mut ratatui::DefaultTerminal,
+    loopback: &mut Loopback<cpal::Stream>,
+    frames: usize,
+    excitation: &Excitation,
+    args: &Args,
+) -> anyhow::Result<()> {
+    let method = match args.method {
+        Method::Sweep => "sweep",
+        Method::Steps => "stepped sines",
+    };
+    let smoothing = if args.smoothing > 0.0 { format!("1/{} octave smoothing", args.smoothing) } else { "unsmoothed".to_string() };
+    let mut plot = Plot {
+        title: format!("Frequency response ({method}, {smoothing})"),
+        magnitude: Vec::new(),
+        phase: Vec::new(),
+        message: String::new(),
+    };
+    let mut measurements = 0;
+    loop {
+        plot.message = "Measuring...".to_string();
+        terminal.draw(|f| draw(f, &plot, args))?;
+
+        let recording = loopback.measure(frames, std::thread::sleep)?;
+        match excitation.analyze(&recording, loopback.sample_rate()) {
+            Some(response) => {
+                measurements += 1;
+                let response = smooth(&response, excitation, args);
+                let points = response.points().iter().filter(|point| (args.start..=args.end).contains(&point.frequency));
+                (plot.magnitude, plot.phase) = points
+                    .map(|point| ((point.frequency.log10(), point.magnitude_db()), (point.frequency.log10(), point.phase_degrees())))
+                    .unzip();
+                plot.message = format!("Measurement {measurements}");
+            }
+            None => plot.message = "Nothing was recorded, is the output looped back?".to_string(),
+        }
+
+        loop {
+            terminal.draw(|f| draw(f, &plot, args))?;
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                match key.code {
+                    KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Char('r') => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn smooth(response: &FrequencyResponse, excitation: &Excitation, args: &Args) -> FrequencyResponse {
+    if args.smoothing <= 0.0 {
+        return response.clone();
+    }
+    // Steps are smoothed onto themselves, a sweep's many bins onto the plot
+    let frequencies: Vec<f64> = match excitation {
+        Excitation::Sweep(_) => log_frequencies(args.start, args.end, PLOT_POINTS),
+        Excitation::Steps(_) => response.points().iter().map(|point| point.frequency).collect(),
+    };
+    response.smoothed(args.smoothing, &frequencies)
+}
+
+fn draw(frame: &mut ratatui::Frame, plot: &Plot, args: &Args) {
+    let layout = Layout::vertical([Constraint::Length(1), Constraint::Percentage(60), Constraint::Fill(1), Constraint::Length(1)]).spacing(1);
+    let [top, magnitude_area, phase_area, status_area] = layout.areas(frame.area());
+
+    let title = Line::from_iter([
+        Span::from(plot.title.as_str()).bold(),
+        Span::from(" (Press 'r' to measure again, 'q' to quit)"),
+    ]);
+    frame.render_widget(title.centered(), top);
+    frame.render_widget(Line::from(plot.message.as_str()).dim(), status_area);
+
+    let x_axis = || {
+        Axis::default()
+            .title("Frequency (Hz)".blue())
+            .bounds([args.start.log10(), args.end.log10()])
+            .labels(log_frequencies(args.start, args.end, 5).into_iter().map(format_frequency))
+    };
+
+    // The top follows the loudest point so that gains and losses both fit
+    let loudest = plot.magnitude.iter().map(|&(_, db)| db).fold(f64::NEG_INFINITY, f64::max);
+    let ceiling = if loudest.is_finite() { (loudest / 6.0).ceil() * 6.0 + 6.0 } else { 6.0 };
+    let floor = ceiling - MAGNITUDE_RANGE_DB;
+    let magnitude = Chart::new(vec![
+        Dataset::default()
+            .name("Magnitude")
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Color::Yellow)
+            .data(&plot.magnitude),
+    ])
+    .x_axis(x_axis())
+    .y_axis(
+        Axis::default()
+            .title("dB".blue())
+            .bounds([floor, ceiling])
+            .labels([floor, (floor + ceiling) / 2.0, ceiling].map(|db| format!("{db:.0}"))),
+    );
+    frame.render_widget(magnitude, magnitude_area);
+
+    // Wrapped, so drawn as points rather than lines that would jump across
+    let phase = Chart::new(vec![
+        Dataset::default()
+            .name("Phase")
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Scatter)
+            .style(Color::Cyan)
+            .data(&plot.phase),
+    ])
+    .x_axis(x_axis())
+    .y_axis(
+        Axis::default()
+            .title("Degrees".blue())
+            .bounds([-180.0, 180.0])
+            .labels(["-180", "0", "180"]),
+    );
+    frame.render_widget(phase, phase_area);
+}
+
+fn format_frequency(frequency: f64) -> String {
+    if frequency >= 1000.0 {
+        format!("{:.1}k", frequency / 1000.0).replace(".0k", "k")
+    } else {
+        format!("{frequency:.0}")
+    }
+}
@@ -1,61 +1,111 @@
-use cpal::traits::{DeviceTrait, HostTrait};
-
-fn main() {
-    let hosts = cpal::available_hosts();
-    println!("Available hosts: {:?}", hosts);
-    for host_id in hosts {
-        let host = cpal::host_from_id(host_id).expect("Failed to get host from id");
-        let devices = host.devices().expect("Failed to get devices");
-
-        for device in devices {
-            println!("Device: {}", device.name().expect("Failed to get device name"));
-            if let Ok(config) = device.default_input_config() {
-                println!(
-                    "        Default input config: {:?}",
-                    config
-                );
-                for supported_config in device.supported_input_configs().expect("Failed to get supported input config") {
-                    if supported_config.min_sample_rate() == supported_config.max_sample_rate() {
-                        let supported_config = supported_config.with_sample_rate(supported_config.min_sample_rate());
-                        if config == supported_config {
-                            continue;
-                        }
-                        println!(
-                            "        - Supported input config: {:?}",
-                            supported_config
-                        );
-                    } else {
-                        println!(
-                            "        - Supported input config: {:?}",
-                            supported_config
-                        );
-                    }
-                }
-            }
-            if let Ok(config) = device.default_output_config() {
-                println!(
-                    "        Default output config: {:?}",
-                    config
-                );
-                for supported_config in device.supported_output_configs().expect("Failed to get supported output config") {
-                    if supported_config.min_sample_rate() == supported_config.max_sample_rate() {
-                        let supported_config = supported_config.with_sample_rate(supported_config.min_sample_rate());
-                        if config == supported_config {
-                            continue;
-                        }
-                        println!(
-                            "        - Supported output config: {:?}",
-                            supported_config
-                        );
-                    } else {
-                        println!(
-                            "        - Supported output config: {:?}",
-                            supported_config
-                        );
-                    }
-                }
-            }
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use clap::{Parser, ValueEnum};
+use cpal_toy::cli::parse_duration;
+use cpal_toy::inventory::Inventory;
+use cpal_toy::probe::ProbeReport;
+use cpal_toy::watch::DeviceWatcher;
+
+/// Lists audio hosts, devices and the stream configurations they support
+#[derive(Parser)]
+struct Args {
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Open and run every advertised configuration to check that it works
+    #[arg(short, long)]
+    probe: bool,
+    /// Only probe devices whose name contains this
+    #[arg(short, long, requires = "probe")]
+    device: Option<String>,
+    /// Ask for fixed buffers of this many frames when probing, and report
+    /// configurations that deliver other sizes
+    #[arg(short, long, requires = "probe")]
+    buffer_size: Option<u32>,
+    /// Milliseconds to run each probed configuration for
+    #[arg(long, default_value_t = 500, requires = "probe")]
+    probe_duration: u64,
+    /// Keep running and print devices as they are added and removed
+    #[arg(short, long, conflicts_with = "probe")]
+    watch: bool,
+    /// Time between device scans when watching, e.g. 1s or 250ms
+    #[arg(long, value_parser = parse_duration, default_value = "1s", requires = "watch")]
+    interval: Duration,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Aligned table with one configuration per row
+    Text,
+    Json,
+    Toml,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    if args.watch {
+        anyhow::ensure!(!args.interval.is_zero(), "The scan interval must be longer than zero");
+        let watcher = DeviceWatcher::spawn(args.interval);
+        let now = timestamp(SystemTime::now());
+        for (host, device) in watcher.initial().devices() {
+            println!("[{now}] {host}: present {device}");
+        }
+        while let Some((at, change)) = watcher.next_change() {
+            println!("[{}] {change}", timestamp(at));
         }
+        return Ok(());
+    }
 
+    if args.probe {
+        let report = ProbeReport::collect(
+            args.device.as_deref(),
+            args.buffer_size,
+            Duration::from_millis(args.probe_duration),
+        );
+        match args.format {
+            Format::Text => print!("{}", report.to_table()),
+            Format::Json => println!("{}", report.to_json()?),
+            Format::Toml => print!("{}", report.to_toml()?),
+        }
+        let failures = report.devices.iter().flat_map(|d| &d.results).filter(|r| !r.works()).count();
+        if failures > 0 {
+            eprintln!("{failures} advertised configurations did not work as advertised");
+        }
+        return Ok(());
     }
+
+    let inventory = Inventory::collect();
+
+    match args.format {
+        Format::Text => print!("{}", inventory.to_table()),
+        Format::Json => println!("{}", inventory.to_json()?),
+        Format::Toml => print!("{}", inventory.to_toml()?),
+    }
+
+    for host in &inventory.hosts {
+        for error in &host.errors {
+            eprintln!("{}: {error}", host.name);
+        }
+        for device in &host.devices {
+            for error in &device.errors {
+                eprintln!("{}: {}: {error}", host.name, device.name);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// UTC time of day with milliseconds, which is enough to line events up with
+// system logs
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
 }
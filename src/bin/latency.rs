@@ -0,0 +1,138 @@
+use cpal::traits::DeviceTrait;
+use std::time::{Duration, Instant};
+use clap::{Parser, ValueEnum};
+use cpal_toy::generator::{Chirp, Click, Mls, Signal};
+use cpal_toy::latency::{LatencyConfigBuilder, LatencyMeter};
+use cpal_toy::loopback::{common_rate, LoopbackDevices};
+
+// Detections whose correlation peak is weaker than this are discarded
+const MIN_CLARITY: f32 = 8.0;
+
+/// Measures round-trip latency by playing a known signal on an output device
+/// and finding it again in what an input device captures.
+///
+/// The latency is counted from the output callback that starts the signal to
+/// the input callback that delivers it, so it includes both devices' buffering.
+#[derive(Parser)]
+struct Args {
+    /// Input device name; defaults to the default input device
+    #[arg(short, long)]
+    input: Option<String>,
+    /// Output device name; defaults to the default output device
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Excitation signal
+    #[arg(short, long, value_enum, default_value_t = Excitation::Mls)]
+    signal: Excitation,
+    /// Number of measurements
+    #[arg(short = 'n', long, default_value_t = 5)]
+    repetitions: usize,
+    /// Linear playback level of the excitation
+    #[arg(short, long, default_value_t = 0.5)]
+    level: f32,
+    /// Longest latency to look for, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    max_latency: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Excitation {
+    Mls,
+    Chirp,
+    Click,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let host = cpal::default_host();
+
+    let devices = LoopbackDevices::find(&host, args.output.as_deref(), args.input.as_deref())?;
+    let (output_device, output_config) = (devices.output, devices.output_config);
+    let (input_device, input_config) = (devices.input, devices.input_config);
+    let sample_rate = common_rate(&output_config.clone().into(), &input_config.clone().into())?;
+
+    println!("Output device: {} ({output_config:?})", output_device.name()?);
+    println!("Input device: {} ({input_config:?})", input_device.name()?);
+
+    let reference = match args.signal {
+        Excitation::Mls => Mls::new(14).take_values(Mls::new(14).len()),
+        Excitation::Chirp => {
+            let mut chirp = Chirp::logarithmic(100.0, 10000.0, Duration::from_millis(300), sample_rate);
+            chirp.take_values(chirp.len())
+        }
+        Excitation::Click => Click::single().take_values(1),
+    };
+
+    let mut meter = LatencyMeter::start(
+        &output_device,
+        &output_config.into(),
+        &input_device,
+        &input_config.into(),
+        LatencyConfigBuilder::default()
+            .reference(reference)
+            .level(args.level)
+            .max_latency(Duration::from_millis(args.max_latency))
+            .build()?,
+        {
+            let start = Instant::now();
+            move || start.elapsed()
+        },
+    )?;
+
+    let frame_ns = 1e9 / sample_rate as f64;
+    let mut latencies = Vec::new();
+    let mut reported = Vec::new();
+    for repetition in 1..=args.repetitions {
+        let measurement = meter.measure(std::thread::sleep)?;
+        if measurement.clarity < MIN_CLARITY {
+            println!(
+                "#{repetition}: no clear detection (clarity {:.1}), is the output looped back?",
+                measurement.clarity
+            );
+            continue;
+        }
+
+        let latency = measurement.latency;
+        println!(
+            "#{repetition}: {latency:.1} samples, {:.3} ms (clarity {:.1})",
+            latency * frame_ns / 1e6,
+            measurement.clarity
+        );
+        latencies.push(latency);
+        reported.push(measurement.reported);
+    }
+
+    anyhow::ensure!(!latencies.is_empty(), "No measurement succeeded");
+
+    let (min, mean, max, jitter) = statistics(&latencies);
+    let to_ms = |samples: f64| samples * frame_ns / 1e6;
+    println!(
+        "Round trip over {} measurements: min {min:.1}, mean {mean:.1}, max {max:.1} samples \
+         ({:.3} / {:.3} / {:.3} ms), jitter {jitter:.2} samples ({:.3} ms)",
+        latencies.len(),
+        to_ms(min),
+        to_ms(mean),
+        to_ms(max),
+        to_ms(jitter)
+    );
+
+    let (_, reported_mean, _, _) = statistics(&reported);
+    println!(
+        "Reported by cpal timestamps: {reported_mean:.1} samples ({:.3} ms); \
+         measured exceeds it by {:.1} samples ({:.3} ms)",
+        to_ms(reported_mean),
+        mean - reported_mean,
+        to_ms(mean - reported_mean)
+    );
+
+    Ok(())
+}
+
+// Returns min, mean, max and standard deviation
+fn statistics(values: &[f64]) -> (f64, f64, f64, f64) {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64;
+    (min, mean, max, variance.sqrt())
+}
@@ -0,0 +1,143 @@
+use cpal::traits::DeviceTrait;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Context;
+use clap::Parser;
+use cpal_toy::cli::parse_duration;
+use cpal_toy::impulse::{decay_times, Sweep};
+use cpal_toy::loopback::{common_rate, Loopback, LoopbackDevices, MAX_LATENCY, SETTLE};
+use cpal_toy::render::{write_wav, WavData, WavFormat};
+
+/// Measures the impulse response of whatever lies between an output and an
+/// input device, such as a room, a loudspeaker or a loopback cable.
+///
+/// An exponential sine sweep is played and recorded, then deconvolved into
+/// the linear impulse response and those of the harmonic distortion products.
+/// The impulse response is written as a 32-bit float WAV file, and its
+/// reverberation times and the level of every harmonic are printed.
+#[derive(Parser)]
+struct Args {
+    /// Where to write the impulse response
+    #[arg(default_value = "impulse_response.wav")]
+    path: PathBuf,
+    /// Input device name; defaults to the default input device
+    #[arg(short, long)]
+    input: Option<String>,
+    /// Output device name; defaults to the default output device
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Length of the sweep; longer sweeps raise the signal-to-noise ratio
+    #[arg(short, long, value_parser = parse_duration, default_value = "5s")]
+    duration: Duration,
+    /// Start frequency of the sweep in Hz
+    #[arg(long, default_value_t = 20.0)]
+    start: f64,
+    /// End frequency of the sweep in Hz
+    #[arg(long, default_value_t = 20000.0)]
+    end: f64,
+    /// Linear playback level of the sweep
+    #[arg(short, long, default_value_t = 0.5)]
+    level: f32,
+    /// Length of the impulse response to keep
+    #[arg(short = 'L', long, value_parser = parse_duration, default_value = "2s")]
+    length: Duration,
+    /// Number of harmonic distortion products to separate
+    #[arg(long, default_value_t = 4)]
+    harmonics: usize,
+    /// Also write every harmonic's response, next to the impulse response
+    #[arg(long)]
+    save_harmonics: bool,
+    /// Scale the written responses so that the impulse response peaks at 0 dBFS
+    #[arg(long)]
+    normalize: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let host = cpal::default_host();
+
+    let devices = LoopbackDevices::find(&host, args.output.as_deref(), args.input.as_deref())?;
+    let output_config: cpal::StreamConfig = devices.output_config.clone().into();
+    let input_config: cpal::StreamConfig = devices.input_config.clone().into();
+    // The inverse filter only matches a recording made at the sweep's rate
+    let sample_rate = common_rate(&output_config, &input_config)?;
+    anyhow::ensure!(
+        0.0 < args.start && args.start < args.end && args.end < sample_rate as f64 / 2.0,
+        "The sweep must run upwards between 0 Hz and half the sample rate ({} Hz)",
+        sample_rate / 2
+    );
+
+    println!("Output device: {} ({:?})", devices.output.name()?, devices.output_config);
+    println!("Input device: {} ({:?})", devices.input.name()?, devices.input_config);
+
+    let sweep = Sweep::new(args.start, args.end, args.duration, sample_rate);
+    let length = (args.length.as_secs_f64() * sample_rate as f64) as usize;
+    let wanted = sweep.len() + length + (MAX_LATENCY.as_secs_f64() * sample_rate as f64) as usize;
+
+    let mut loopback = Loopback::start(
+        &devices.output,
+        &output_config,
+        &devices.input,
+        &input_config,
+        sweep.samples().to_vec(),
+        args.level,
+        wanted,
+    )?;
+    std::thread::sleep(SETTLE);
+    println!("Sweeping from {} Hz to {} Hz for {:.1} s...", args.start, args.end, args.duration.as_secs_f64());
+    let recording = loopback.measure(wanted, std::thread::sleep)?;
+    drop(loopback);
+
+    let mut responses = sweep.analyze(&recording, length, args.harmonics).context("Nothing was captured")?;
+
+    let peak = responses.linear[responses.pre_roll];
+    let energy = |response: &[f32]| response.iter().map(|&x| x as f64 * x as f64).sum::<f64>();
+    let linear_energy = energy(&responses.linear);
+    println!(
+        "Response peaks at {:.1} dB, {:.2} ms into the recording",
+        20.0 * peak.abs().log10(),
+        responses.delay as f64 * 1000.0 / sample_rate as f64
+    );
+    for (index, harmonic) in responses.harmonics.iter().enumerate() {
+        println!(
+            "Harmonic {}: {:.1} dB relative to the linear response",
+            index + 2,
+            10.0 * (energy(harmonic) / linear_energy).log10()
+        );
+    }
+
+    let times = decay_times(&responses.linear[responses.pre_roll..], sample_rate);
+    let show = |time: Option<f64>| time.map_or("n/a (decay too short or too noisy)".to_string(), |t| format!("{:.3} s", t));
+    println!("EDT: {}", show(times.edt));
+    println!("T20: {}", show(times.t20));
+    println!("T30: {}", show(times.t30));
+    println!("RT60: {}", show(times.rt60()));
+
+    if args.normalize && peak != 0.0 {
+        let gain = 1.0 / peak.abs();
+        for response in std::iter::once(&mut responses.linear).chain(&mut responses.harmonics) {
+            response.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+    save(&args.path, responses.linear, sample_rate)?;
+    println!("Impulse response written to {}", args.path.display());
+    if args.save_harmonics {
+        for (index, harmonic) in responses.harmonics.into_iter().enumerate() {
+            let path = harmonic_path(&args.path, index + 2);
+            save(&path, harmonic, sample_rate)?;
+            println!("Harmonic {} written to {}", index + 2, path.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn save(path: &Path, samples: Vec<f32>, sample_rate: u32) -> anyhow::Result<()> {
+    write_wav(path, &WavData { samples, sample_rate, channels: 1 }, WavFormat::F32)
+}
+
+// `room.wav` becomes `room_h2.wav` for the second harmonic
+fn harmonic_path(path: &Path, order: usize) -> PathBuf {
+    let stem = path.file_stem().map_or("impulse_response".into(), |stem| stem.to_string_lossy());
+    path.with_file_name(format!("{stem}_h{order}.wav"))
+}
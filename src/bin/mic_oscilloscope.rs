@@ -2,56 +2,182 @@ use ratatui::prelude::*;
 use crossterm::event::{self, Event, KeyCode, KeyEventKind};
 use ratatui::{
     layout::{Constraint, Layout},
-    widgets::{Block, Dataset, GraphType, Axis, Chart, Gauge, Borders},
+    widgets::{Block, Dataset, GraphType, Axis, Chart, Gauge, Borders, Bar, BarChart, BarGroup},
     symbols::Marker,
 };
-use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
+use cpal::traits::{HostTrait, DeviceTrait};
 use anyhow::Context;
-use lockfree::queue::Queue;
-use std::sync::Arc;
-use cpal_toy::window::Window;
+use clap::Parser;
+use std::sync::{Arc, Mutex};
+use cpal_toy::biquad::{Cascade, Pass};
+use cpal_toy::device::Direction;
+use cpal_toy::octave::{Bandwidth, FilterBank, FilterBankConfigBuilder, Weighting};
+use cpal_toy::processor::Chain;
+use cpal_toy::scope::{self, Scope};
+use cpal_toy::supervisor::{SupervisedStream, SupervisorConfigBuilder, SupervisorEvent};
+use cpal_toy::watch::{DeviceChange, DeviceWatcher};
+
+/// Shows the default input device as an oscilloscope with its level and an
+/// octave or third-octave real-time analyser
+#[derive(Parser)]
+struct Args {
+    /// Remove everything below this frequency in Hz, e.g. rumble or DC
+    #[arg(long)]
+    high_pass: Option<f64>,
+    /// Remove everything above this frequency in Hz
+    #[arg(long)]
+    low_pass: Option<f64>,
+    /// Order of the Butterworth high and low passes
+    #[arg(long, default_value_t = 4)]
+    filter_order: usize,
+    /// Bands of the real-time analyser: octave or third
+    #[arg(long, default_value = "third")]
+    bands: Bandwidth,
+    /// Frequency weighting of the analyser: A, C or Z
+    #[arg(long, default_value = "z")]
+    weighting: Weighting,
+}
+
+// Lowest level the analyser shows, in dBFS
+const RTA_FLOOR_DB: f64 = -90.0;
+
+// What the bottom of the screen shows
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum View {
+    Rta,
+    Spectrum,
+}
+
+// What the status line shows besides the callback timing: the input device
+// disappearing and the stream reconnecting
+struct Status {
+    device: String,
+    watcher: DeviceWatcher,
+    stream: SupervisedStream,
+    message: Option<String>,
+}
+
+impl Status {
+    fn update(&mut self) {
+        for (_, change) in self.watcher.try_changes() {
+            match change {
+                DeviceChange::Removed { device, .. } if device == self.device => {
+                    self.message = Some(format!("Input device {device} was unplugged"));
+                }
+                DeviceChange::Added { device, .. } if device == self.device => {
+                    self.message = Some(format!("Input device {device} is back"));
+                }
+                _ => {}
+            }
+        }
+        for event in self.stream.try_events() {
+            self.message = match event {
+                // Nothing to report about the first connection
+                SupervisorEvent::Connected { fallback: false, .. } if self.message.is_none() => None,
+                event => Some(format!("Input {event}")),
+            };
+        }
+    }
+}
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
     let host = cpal::default_host();
     let device = host.default_input_device().context("Failed to get default input device")?;
     let config = device.default_input_config().context("Failed to get default input config")?;
+    let device_name = device.name()?;
     let sample_rate = config.sample_rate().0;
-    let left_queue = Arc::new(Queue::new());
-    let left_queue_input = left_queue.clone();
-    let stream = device.build_input_stream(
-        &config.into(),
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            left_queue_input.push(data.to_vec());
-        },
-        move |err| {
-            eprintln!("An error occurred on the input stream: {}", err);
+    let channels = config.channels() as usize;
+
+    // Applied to the captured audio before it is drawn and measured
+    let mut filters = Chain::new();
+    if let Some(frequency) = args.high_pass {
+        filters.push(Cascade::butterworth(Pass::High, args.filter_order, frequency, sample_rate, channels)?);
+    }
+    if let Some(frequency) = args.low_pass {
+        filters.push(Cascade::butterworth(Pass::Low, args.filter_order, frequency, sample_rate, channels)?);
+    }
+
+    let analyser = FilterBank::with_config(
+        FilterBankConfigBuilder::default()
+            .bandwidth(args.bands)
+            .sample_rate(sample_rate)
+            .channels(channels)
+            .weighting(args.weighting)
+            .build()?,
+    );
+
+    // The last two seconds are drawn
+    let (scope, input) = Scope::new(sample_rate, channels, sample_rate as usize * 2, filters, analyser);
+    let input = Arc::new(Mutex::new(input));
+
+    // Rebuilt with the same configuration whenever the device goes away,
+    // on the default device if this one does not come back. The scope and its
+    // filters are set up for this configuration, so a fallback device that
+    // runs another one is not opened.
+    let stream_config: cpal::StreamConfig = config.into();
+    let stream = SupervisedStream::spawn(
+        SupervisorConfigBuilder::default()
+            .direction(Direction::Input)
+            .device(Some(device_name.clone()))
+            .build()?,
+        host.id(),
+        move |device, on_error| {
+            let config = device.default_input_config().context("Failed to get default input config")?;
+            anyhow::ensure!(
+                config.sample_rate() == stream_config.sample_rate && config.channels() == stream_config.channels,
+                "{} runs at {} Hz with {} channels, not at {} Hz with {}",
+                device.name()?,
+                config.sample_rate().0,
+                config.channels(),
+                stream_config.sample_rate.0,
+                stream_config.channels
+            );
+            scope::input_stream(device, &stream_config, input.clone(), on_error)
         },
-        None,
-    )?;
-    stream.play().context("Failed to start the input stream")?;
+    );
+    let mut status = Status {
+        device: device_name,
+        watcher: DeviceWatcher::spawn(std::time::Duration::from_secs(1)),
+        stream,
+        message: None,
+    };
+    if let Some(SupervisorEvent::Retrying { error, .. }) = status.stream.next_event() {
+        anyhow::bail!("Failed to start the input stream: {error}");
+    }
+
     let mut terminal = ratatui::init();
-    let window = Window::with_duration(std::time::Duration::from_millis(100), sample_rate);
-    let result = run(&mut terminal, left_queue, sample_rate as usize * 2, window);
+    let result = run(&mut terminal, scope, &mut status);
     ratatui::restore();
     result.context("Failed to run the oscilloscope")
 }
 
-fn run(terminal: &mut ratatui::DefaultTerminal, queue: Arc<Queue<Vec<f32>>>, total_samples: usize, mut window: Window) -> std::io::Result<()> {
-    let mut samples: Vec<f32> = vec![0.0; total_samples];
+fn run(terminal: &mut ratatui::DefaultTerminal, mut scope: Scope, status: &mut Status) -> std::io::Result<()> {
     let mut last_timeout = std::time::Instant::now();
+    let mut view = View::Rta;
     loop {
-        while let Some(data) = queue.pop() {
-            window.add_samples(&data);
-            samples.extend(data);
-            if samples.len() > total_samples {
-                samples.drain(0..samples.len() - total_samples); // Keep the last 1000 samples
-            }
-        }
-        terminal.draw(|f| draw(f, &samples, total_samples, &window))?;
+        scope.update();
+        status.update();
+        terminal.draw(|f| draw(f, &scope, view, status))?;
         let next_tick = last_timeout + std::time::Duration::from_secs_f32(1.0 / 30.0);
         if let Ok(true) = event::poll(next_tick.duration_since(std::time::Instant::now())) {
-            if handle_events()? {
-                break;
+            match handle_events()? {
+                Some(KeyCode::Char('q')) => break,
+                Some(KeyCode::Char('v')) => {
+                    view = match view {
+                        View::Rta => View::Spectrum,
+                        View::Spectrum => View::Rta,
+                    };
+                }
+                Some(KeyCode::Char('w')) => {
+                    let analyser = scope.analyser_mut();
+                    analyser.set_weighting(match analyser.weighting() {
+                        Weighting::A => Weighting::C,
+                        Weighting::C => Weighting::Z,
+                        Weighting::Z => Weighting::A,
+                    });
+                }
+                _ => {}
             }
         } else {
             last_timeout = std::time::Instant::now();
@@ -60,16 +186,26 @@ fn run(terminal: &mut ratatui::DefaultTerminal, queue: Arc<Queue<Vec<f32>>>, tot
     Ok(())
 }
 
-fn draw(frame: &mut ratatui::Frame, samples: &Vec<f32>, total_samples: usize, window: &Window) {
-    let layout = Layout::vertical([Constraint::Length(1), Constraint::Length(3), Constraint::Percentage(30), Constraint::Fill(1)]).spacing(1);
-    let [top, dbfs_area, oscilloscope_area, frequencies_area] = layout.areas(frame.area());
+fn draw(frame: &mut ratatui::Frame, scope: &Scope, view: View, status: &Status) {
+    let window = scope.window();
+    let total_samples = scope.history_len();
+    let layout = Layout::vertical([Constraint::Length(1), Constraint::Length(3), Constraint::Percentage(30), Constraint::Fill(1), Constraint::Length(1)]).spacing(1);
+    let [top, dbfs_area, oscilloscope_area, frequencies_area, status_area] = layout.areas(frame.area());
 
     let title = Line::from_iter([
         Span::from("Oscilloscope").bold(),
-        Span::from(" (Press 'q' to quit)"),
+        Span::from(" (Press 'v' to switch view, 'w' to change weighting, 'q' to quit)"),
     ]);
     frame.render_widget(title.centered(), top);
 
+    let mut status_spans = Vec::new();
+    if let Some(message) = &status.message {
+        status_spans.push(Span::from(format!("{message} | ")).red());
+    }
+    status_spans.push(Span::from(format!("Input: {}", scope.timing().snapshot())).dim());
+    let status = Line::from(status_spans);
+    frame.render_widget(status, status_area);
+
     let dbfs_percent = {
         if let Some(dbfs) = window.calculate_dbfs() {
             let low_limit = -40.0; // dBFS low limit
@@ -85,7 +221,7 @@ fn draw(frame: &mut ratatui::Frame, samples: &Vec<f32>, total_samples: usize, wi
         .ratio(dbfs_percent as f64);
     frame.render_widget(dbfs_gauge, dbfs_area);
 
-    let data = samples.iter().enumerate().map(|(i, &sample)| {
+    let data = scope.history().iter().enumerate().map(|(i, &sample)| {
         let x = (i as f64 / total_samples as f64) * 2000.0; // Scale x to 2000ms
         let y = sample as f64; // Use sample value directly for y
         (x, y)
@@ -111,6 +247,10 @@ fn draw(frame: &mut ratatui::Frame, samples: &Vec<f32>, total_samples: usize, wi
     let chart = Chart::new(vec![dataset]).x_axis(x_axis).y_axis(y_axis);
     frame.render_widget(chart, oscilloscope_area);
 
+    if view == View::Rta {
+        draw_rta(frame, scope.analyser(), frequencies_area);
+        return;
+    }
     frame.render_widget(
         Chart::new(
             vec![
@@ -144,15 +284,46 @@ fn draw(frame: &mut ratatui::Frame, samples: &Vec<f32>, total_samples: usize, wi
     );
 }
 
-fn handle_events() -> std::io::Result<bool> {
-    match event::read()? {
-        Event::Key(key) if key.kind == KeyEventKind::Press => match key.code {
-            KeyCode::Char('q') => return Ok(true),
-            // handle other key events
-            _ => {}
+fn draw_rta(frame: &mut ratatui::Frame, analyser: &FilterBank, area: Rect) {
+    let bands = analyser.bands();
+    let levels = analyser.levels_db();
+    // Tenths of a dB above the floor, as bars only take whole numbers
+    let bars: Vec<Bar> = bands
+        .iter()
+        .zip(&levels)
+        .map(|(band, &level)| {
+            Bar::default()
+                .value(((level - RTA_FLOOR_DB).clamp(0.0, -RTA_FLOOR_DB) * 10.0) as u64)
+                .text_value(format!("{level:.0}"))
+                .label(Line::from(band.label()))
+        })
+        .collect();
+    let bar_width = ((area.width.saturating_sub(2) / bands.len().max(1) as u16).saturating_sub(1)).max(1);
+    let title = format!(
+        "RTA, {} bands, {:?} weighting: {:.1} dBFS",
+        match analyser.bandwidth() {
+            Bandwidth::Octave => "octave",
+            Bandwidth::ThirdOctave => "third-octave",
         },
-        // handle other events
-        _ => {}
+        analyser.weighting(),
+        analyser.total_db()
+    );
+    let chart = BarChart::default()
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .data(BarGroup::default().bars(&bars))
+        .bar_width(bar_width)
+        .bar_gap(1)
+        .bar_style(Color::Yellow)
+        .value_style(Style::default().fg(Color::Black).bg(Color::Yellow))
+        .max((-RTA_FLOOR_DB * 10.0) as u64);
+    frame.render_widget(chart, area);
+}
+
+fn handle_events() -> std::io::Result<Option<KeyCode>> {
+    if let Event::Key(key) = event::read()?
+        && key.kind == KeyEventKind::Press
+    {
+        return Ok(Some(key.code));
     }
-    Ok(false)
+    Ok(None)
 }
@@ -1,8 +1,113 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use anyhow::Context;
-use lockfree::queue::Queue;
-use std::sync::Arc;
+use clap::Parser;
+use serde::Deserialize;
+use cpal_toy::aggregator::Aggregator;
+use cpal_toy::bridge::BridgeMonitor;
+use cpal_toy::cli::{EffectKind, EffectSpec};
+use cpal_toy::convolution::Convolver;
+use cpal_toy::delay::{DelayConfigBuilder, FeedbackDelay, ModulatedDelay};
+use cpal_toy::dynamics::{Dynamics, DynamicsConfigBuilder, DynamicsMode, Limiter, LimiterConfigBuilder};
+use cpal_toy::equalizer::{live_equalizer, Band, Equalizer, GraphicLayout};
+use cpal_toy::monitoring::{input_bridge, input_stream, output_stream};
+use cpal_toy::processor::Chain;
+use cpal_toy::reverb::{Reverb, ReverbConfigBuilder};
+use cpal_toy::routing::{Route, RoutingMatrix};
+use cpal_toy::timing::{TimingHandle, TimingMonitor};
+
+const DEFAULT_OUTPUT_CHANNELS: u16 = 2;
+const DEFAULT_DURATION_SECS: u64 = 60;
+const DEFAULT_CEILING_DB: f32 = -1.0;
+const DEFAULT_TEMPO: f64 = 120.0;
+// Frames of latency that an impulse response adds
+const IR_BLOCK_SIZE: usize = 256;
+
+/// Plays any number of input devices through one output device.
+///
+/// The channels of all inputs are laid side by side as one virtual input,
+/// in the order the devices are given, and mixed to the output channels.
+/// Without routes the first channel of every input goes to the outputs in turn.
+#[derive(Parser)]
+struct Args {
+    /// Input device name; repeat for every device to open
+    #[arg(short, long = "input")]
+    inputs: Vec<String>,
+    /// Output device name
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Number of output channels
+    #[arg(long)]
+    output_channels: Option<u16>,
+    /// Route as IN:OUT[=GAIN] with a linear or dB gain, e.g. 2:0=-6dB; repeat for every route
+    #[arg(short, long = "route")]
+    routes: Vec<Route>,
+    /// Equalizer band on the output as TYPE:FREQ[:GAIN[:Q]], e.g. peak:3000:-4dB:2; repeat for every band
+    #[arg(short, long = "eq")]
+    eq: Vec<Band>,
+    /// Graphic equalizer gains in dB on the output, 10 or 31 of them separated by commas
+    #[arg(long, value_delimiter = ',', allow_negative_numbers = true)]
+    graphic_eq: Vec<f64>,
+    /// WAV file with an impulse response, e.g. of a cabinet or room, to convolve the output with
+    #[arg(long)]
+    ir: Option<PathBuf>,
+    /// Effect on the output as NAME[:TIME] with delay, pingpong, chorus, flanger or reverb, e.g. delay:1/8d; repeat for every effect
+    #[arg(long = "effect")]
+    effects: Vec<EffectSpec>,
+    /// Tempo in beats per minute that note value times like 1/8d are synced to
+    #[arg(long)]
+    tempo: Option<f64>,
+    /// Level in dBFS that the limiter on the output keeps every sample under
+    #[arg(long, allow_negative_numbers = true)]
+    ceiling: Option<f32>,
+    /// Gate the inputs below this level in dBFS, to cut room noise between words
+    #[arg(long, allow_negative_numbers = true)]
+    gate: Option<f32>,
+    /// TOML file with the same settings; command line options take precedence.
+    /// Equalizer changes in the file are applied while running.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Seconds to run for
+    #[arg(short, long)]
+    duration: Option<u64>,
+}
+
+// Example:
+//
+// inputs = ["USB Mic A", "USB Mic B"]
+// output = "Speakers"
+// routes = [
+//     { input = 0, output = 0 },
+//     { input = 1, output = 1, gain = 0.5 },
+// ]
+// ir = "cabinet.wav"
+// effects = ["chorus", "delay:1/8d", "reverb:20ms"]
+// tempo = 96.0
+// gate = -50.0
+// ceiling = -1.0
+// graphic_eq = [0, 0, 0, 0, 0, 0, -2, -4, -2, 0]
+// eq = [
+//     { type = "highpass", frequency = 80 },
+//     { type = "peaking", frequency = 3000, gain = -4, q = 2 },
+// ]
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    inputs: Vec<String>,
+    output: Option<String>,
+    output_channels: Option<u16>,
+    routes: Vec<Route>,
+    duration: Option<u64>,
+    eq: Vec<Band>,
+    graphic_eq: Vec<f64>,
+    ceiling: Option<f32>,
+    gate: Option<f32>,
+    effects: Vec<String>,
+    tempo: Option<f64>,
+    ir: Option<PathBuf>,
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct HashableStreamConfig {
@@ -12,132 +117,358 @@ struct HashableStreamConfig {
 }
 
 fn main() -> anyhow::Result<()> {
-    let host = cpal::default_host();
-    let devices = host.devices()?;
-
-    let mut input_devices = Vec::new();
-    let mut output_devices = Vec::new();
-
-    for device in devices {
-        if device.supports_input() {
-            input_devices.push(device.clone());
+    let mut args = Args::parse();
+    // The equalizer in the file is only followed if the command line has none
+    let mut eq_from_file = false;
+    if let Some(path) = &args.config {
+        let file = read_config(path)?;
+        if args.eq.is_empty() && args.graphic_eq.is_empty() {
+            args.eq = file.eq;
+            args.graphic_eq = file.graphic_eq;
+            eq_from_file = true;
         }
-        if device.supports_output() {
-            output_devices.push(device.clone());
+        if args.inputs.is_empty() {
+            args.inputs = file.inputs;
         }
-    }
-
-    let mut config_to_devices: HashMap<HashableStreamConfig, (cpal::SupportedStreamConfig, Vec<cpal::Device>)> = Default::default();
-
-    for device in input_devices {
-        let configs = all_input_configs(&device)?;
-        for config in configs {
-            let hashable_config = HashableStreamConfig {
-                channels: config.channels(),
-                sample_rate: config.sample_rate().0,
-                sample_format: config.sample_format(),
-            };
-            config_to_devices.entry(hashable_config)
-                .and_modify(|e| e.1.push(device.clone()))
-                .or_insert((config, vec![device.clone()]));
+        if args.routes.is_empty() {
+            args.routes = file.routes;
+        }
+        args.output = args.output.or(file.output);
+        args.output_channels = args.output_channels.or(file.output_channels);
+        args.duration = args.duration.or(file.duration);
+        args.ceiling = args.ceiling.or(file.ceiling);
+        args.gate = args.gate.or(file.gate);
+        args.tempo = args.tempo.or(file.tempo);
+        args.ir = args.ir.or(file.ir);
+        if args.effects.is_empty() {
+            args.effects = file
+                .effects
+                .iter()
+                .map(|effect| effect.parse())
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Bad effect in {}", path.display()))?;
         }
     }
 
-    let mut config_with_two_devices = config_to_devices
-        .into_iter()
-        .find(|(_, (_, devices))| devices.len() >= 2)
-        .map(|(_, v)| v)
-        .context("No input devices with identical configurations found")?;
+    let host = cpal::default_host();
 
-    let config = config_with_two_devices
-        .0;
+    let inputs = if args.inputs.is_empty() {
+        find_identical_inputs(&host)?
+    } else {
+        args.inputs
+            .iter()
+            .map(|name| {
+                let device = find_device(host.input_devices()?, name)?;
+                let config = device.default_input_config()?;
+                Ok((device, config))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
 
-    let left_device = config_with_two_devices.1.pop().context("Expected at least two devices")?;
-    let right_device = config_with_two_devices.1.pop().context("Expected at least two devices")?;
+    for (index, (device, config)) in inputs.iter().enumerate() {
+        println!(
+            "Input {index} is {} ({} channels at {} Hz)",
+            device.name()?,
+            config.channels(),
+            config.sample_rate().0
+        );
+    }
 
-    println!("Left device is {} and right device is {}", left_device.name()?, right_device.name()?);
+    let output_channels = args.output_channels.unwrap_or(DEFAULT_OUTPUT_CHANNELS);
+    let preferred_rate = inputs[0].1.sample_rate();
+    let output_devices: Vec<cpal::Device> = match &args.output {
+        Some(name) => vec![find_device(host.output_devices()?, name)?],
+        None => host.output_devices()?.collect(),
+    };
 
     let mut output: Option<(cpal::Device, cpal::SupportedStreamConfig)> = None;
 
     for device in output_devices {
-        let configs = all_output_configs(&device)?;
+        let Ok(configs) = all_output_configs(&device) else {
+            continue;
+        };
         for config_out in configs {
-            if config_out.sample_rate() == config.sample_rate() && config_out.channels() == 2 {
-                output = Some((device, config_out));
-                break;
+            if config_out.channels() != output_channels {
+                continue;
+            }
+            // Inputs are resampled to the output rate, but avoid it when possible
+            let matches_rate = config_out.sample_rate() == preferred_rate;
+            let found_match = output.as_ref().is_some_and(|(_, c)| c.sample_rate() == preferred_rate);
+            if output.is_none() || (matches_rate && !found_match) {
+                output = Some((device.clone(), config_out));
             }
         }
     }
 
     let (output_device, output_config) = output
-        .context("No suitable output device found with 2 channels and matching sample rate")?;
+        .with_context(|| format!("No suitable output device found with {output_channels} channels"))?;
+
+    println!(
+        "Output device is {} ({} channels at {} Hz)",
+        output_device.name()?,
+        output_channels,
+        output_config.sample_rate().0
+    );
+
+    let mut bridges = Vec::new();
+    let mut streams = Vec::new();
+    let mut input_timings = Vec::new();
+    let mut default_routes = Vec::new();
+    let mut virtual_channels = 0;
+    for (index, (device, config)) in inputs.into_iter().enumerate() {
+        let channels = config.channels() as usize;
+        let (bridge, producer) = input_bridge(config.sample_rate().0, output_config.sample_rate().0, channels)?;
+        bridges.push(bridge);
+        default_routes.push(Route {
+            input: virtual_channels,
+            output: index % output_channels as usize,
+            gain: 1.0,
+        });
+        virtual_channels += channels;
+
+        let monitor = TimingMonitor::new(config.sample_rate().0, channels);
+        input_timings.push(monitor.handle());
+
+        let gate = args
+            .gate
+            .map(|threshold_db| {
+                DynamicsConfigBuilder::default()
+                    .mode(DynamicsMode::Gate)
+                    .sample_rate(config.sample_rate().0)
+                    .channels(channels)
+                    .threshold_db(threshold_db)
+                    .attack(std::time::Duration::from_millis(1))
+                    .hold(std::time::Duration::from_millis(50))
+                    .release(std::time::Duration::from_millis(150))
+                    .build()
+                    .map(Dynamics::with_config)
+            })
+            .transpose()?;
 
-    println!("Output device is {}", output_device.name()?);
+        let mut input_config: cpal::StreamConfig = config.into();
+        input_config.buffer_size = cpal::BufferSize::Fixed(15);
+        streams.push(input_stream(&device, &input_config, producer, gate, monitor, index)?);
+    }
+
+    let routes = if args.routes.is_empty() { default_routes } else { args.routes };
+    let matrix = RoutingMatrix::with_routes(virtual_channels, output_channels as usize, &routes)?;
+    let aggregator = Aggregator::new(bridges, matrix)?;
+    let monitors = aggregator.monitors();
+
+    let output_monitor = TimingMonitor::new(output_config.sample_rate().0, output_channels as usize);
+    let output_timing = output_monitor.handle();
 
-    let mut input_config: cpal::StreamConfig = config.into();
     let mut output_config: cpal::StreamConfig = output_config.into();
-    input_config.buffer_size = cpal::BufferSize::Fixed(15);
     output_config.buffer_size = cpal::BufferSize::Fixed(15);
 
-    let left_queue = Arc::new(Queue::new());
-    let left_queue_input = left_queue.clone();
-    let left_stream = left_device.build_input_stream(
-        &input_config,
-        move |data: &[f32], _| {
-            while let Some(_) = left_queue_input.pop() {
-                // Clear the queue if it has any data
-            }
-            left_queue_input.push(data.to_vec());
-        },
-        move |err| {
-            eprintln!("Error on left input stream: {}", err);
-        },
-        None,
+    let bands = eq_bands(&args.eq, &args.graphic_eq)?;
+    let equalizer = Equalizer::new(&bands, output_config.sample_rate.0, output_channels as usize)?;
+    print_eq(&equalizer);
+    // Equalizer changes are built here and handed to the output callback
+    let (equalizer, mut eq_control) = live_equalizer(equalizer);
+    let convolver = args
+        .ir
+        .as_ref()
+        .map(|path| Convolver::from_wav(path, output_config.sample_rate.0, output_channels as usize, IR_BLOCK_SIZE))
+        .transpose()?;
+    let effects = effect_chain(
+        &args.effects,
+        args.tempo.unwrap_or(DEFAULT_TEMPO),
+        output_config.sample_rate.0,
+        output_channels as usize,
     )?;
+    // Brickwall, so that no routing, EQ boost or feedback can clip the output
+    let limiter = Limiter::with_config(
+        LimiterConfigBuilder::default()
+            .sample_rate(output_config.sample_rate.0)
+            .channels(output_channels as usize)
+            .ceiling_db(args.ceiling.unwrap_or(DEFAULT_CEILING_DB))
+            .build()?,
+    );
+
 
-    let right_queue = Arc::new(Queue::new());
-    let right_queue_input = right_queue.clone();
-    let right_stream = right_device.build_input_stream(
-        &input_config,
-        move |data: &[f32], _| {
-            while let Some(_) = right_queue_input.pop() {
-                // Clear the queue if it has any data
+    let mut processors = Chain::new();
+    processors.push(equalizer);
+    if let Some(convolver) = convolver {
+        processors.push(convolver);
+    }
+    processors.push(effects);
+    processors.push(limiter);
+    let output_stream = output_stream(&output_device, &output_config, aggregator, processors, output_monitor)?;
+
+    for stream in &streams {
+        stream.play()?;
+    }
+    output_stream.play()?;
+
+    // Keep the streams alive, reporting clock drift every second and picking
+    // up equalizer changes in the config file
+    let watched = args.config.as_deref().filter(|_| eq_from_file);
+    let mut modified = watched.and_then(modified_time);
+    for _ in 0..args.duration.unwrap_or(DEFAULT_DURATION_SECS) {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        if let Some(path) = watched {
+            let now = modified_time(path);
+            if now != modified {
+                modified = now;
+                let update = read_config(path)
+                    .and_then(|file| eq_bands(&file.eq, &file.graphic_eq))
+                    .and_then(|bands| Equalizer::new(&bands, output_config.sample_rate.0, output_channels as usize));
+                match update {
+                    Ok(update) => {
+                        print_eq(&update);
+                        eq_control.replace(update);
+                    }
+                    Err(err) => eprintln!("Keeping the equalizer as it was: {err:#}"),
+                }
             }
-            right_queue_input.push(data.to_vec());
-        },
-        move |err| {
-            eprintln!("Error on right input stream: {}", err);
-        },
-        None,
-    )?;
+        }
+        // An update the output hasn't taken yet waits for the next second
+        eq_control.flush();
+        let status: Vec<String> = monitors
+            .iter()
+            .enumerate()
+            .map(|(index, monitor)| {
+                format!(
+                    "input {index}: {:+.1} ppm, {} buffered, {} xruns",
+                    monitor.drift_ppm(),
+                    monitor.buffered_samples(),
+                    monitor.xruns()
+                )
+            })
+            .collect();
+        println!("{}", status.join("; "));
+    }
+
+    for (index, (monitor, timing)) in monitors.iter().zip(&input_timings).enumerate() {
+        print_summary(index, monitor, timing);
+    }
+    println!("Output: {}", output_timing.snapshot());
+
+    Ok(())
+}
+
+fn read_config(path: &Path) -> anyhow::Result<FileConfig> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))
+}
 
-    let output_stream = output_device.build_output_stream(
-        &output_config,
-        move |data: &mut [f32], _| {
-            let left_chunk = left_queue.pop();
-            let right_chunk = right_queue.pop();
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
 
-            for ((left, right), out) in left_chunk.iter().flatten().zip(right_chunk.iter().flatten()).zip(data.chunks_exact_mut(2)) {
-                out[0] = *left;
-                out[1] = *right;
+// The effects in the order given, each with its default settings apart
+// from the time
+fn effect_chain(specs: &[EffectSpec], bpm: f64, sample_rate: u32, channels: usize) -> anyhow::Result<Chain> {
+    anyhow::ensure!(bpm > 0.0, "The tempo must be positive, got {bpm}");
+    let mut chain = Chain::new();
+    for spec in specs {
+        let time = spec.time.map(|time| time.duration(bpm));
+        match spec.kind {
+            EffectKind::Delay | EffectKind::PingPong => {
+                let mut config = DelayConfigBuilder::default();
+                config.sample_rate(sample_rate).channels(channels);
+                if let Some(time) = time {
+                    config.time(time);
+                }
+                let config = config.build()?;
+                if spec.kind == EffectKind::Delay {
+                    chain.push(FeedbackDelay::with_config(config));
+                } else {
+                    chain.push(FeedbackDelay::ping_pong(config));
+                }
+            }
+            EffectKind::Chorus | EffectKind::Flanger => {
+                let mut effect = if spec.kind == EffectKind::Chorus {
+                    ModulatedDelay::chorus(sample_rate, channels)
+                } else {
+                    ModulatedDelay::flanger(sample_rate, channels)
+                };
+                if let Some(time) = time {
+                    effect.set_rate(1.0 / time.as_secs_f64());
+                }
+                chain.push(effect);
+            }
+            EffectKind::Reverb => {
+                let mut config = ReverbConfigBuilder::default();
+                config.sample_rate(sample_rate).channels(channels);
+                if let Some(time) = time {
+                    config.pre_delay(time);
+                }
+                chain.push(Reverb::with_config(config.build()?));
             }
+        }
+    }
+    Ok(chain)
+}
 
-        },
-        move |err| {
-            eprintln!("Error on output stream: {}", err);
-        },
-        None,
-    )?;
+// Graphic bands first, then the parametric ones
+fn eq_bands(parametric: &[Band], graphic_gains: &[f64]) -> anyhow::Result<Vec<Band>> {
+    let mut bands = Vec::new();
+    if !graphic_gains.is_empty() {
+        bands = GraphicLayout::for_band_count(graphic_gains.len())?.bands(graphic_gains)?;
+    }
+    bands.extend_from_slice(parametric);
+    Ok(bands)
+}
 
-    left_stream.play()?;
-    right_stream.play()?;
-    output_stream.play()?;
+// The response at the octave centres, as a quick picture of the curve
+fn print_eq(equalizer: &Equalizer) {
+    if equalizer.bands().is_empty() {
+        return;
+    }
+    let curve: Vec<String> = GraphicLayout::Octave
+        .frequencies()
+        .into_iter()
+        .map(|frequency| format!("{frequency:.0} Hz {:+.1}", equalizer.response_db(frequency)))
+        .collect();
+    println!("Equalizer ({} bands): {}", equalizer.bands().len(), curve.join(", "));
+}
 
-    std::thread::sleep(std::time::Duration::from_secs(60)); // Keep the streams alive for 60
-                                                            // seconds
+fn find_device(mut devices: impl Iterator<Item = cpal::Device>, name: &str) -> anyhow::Result<cpal::Device> {
+    devices
+        .find(|device| device.name().is_ok_and(|n| n == name))
+        .with_context(|| format!("No device named '{name}'"))
+}
 
+// Picks two input devices that share a configuration, for when no inputs are
+// named on the command line
+fn find_identical_inputs(host: &cpal::Host) -> anyhow::Result<Vec<(cpal::Device, cpal::SupportedStreamConfig)>> {
+    let mut config_to_devices: HashMap<HashableStreamConfig, (cpal::SupportedStreamConfig, Vec<cpal::Device>)> = Default::default();
 
-    Ok(())
+    for device in host.input_devices()? {
+        let configs = all_input_configs(&device)?;
+        for config in configs {
+            let hashable_config = HashableStreamConfig {
+                channels: config.channels(),
+                sample_rate: config.sample_rate().0,
+                sample_format: config.sample_format(),
+            };
+            config_to_devices.entry(hashable_config)
+                .and_modify(|e| e.1.push(device.clone()))
+                .or_insert((config, vec![device.clone()]));
+        }
+    }
+
+    let (config, mut devices) = config_to_devices
+        .into_iter()
+        .find(|(_, (_, devices))| devices.len() >= 2)
+        .map(|(_, v)| v)
+        .context("No input devices with identical configurations found")?;
+
+    let left_device = devices.pop().context("Expected at least two devices")?;
+    let right_device = devices.pop().context("Expected at least two devices")?;
+    Ok(vec![(left_device, config.clone()), (right_device, config)])
+}
+
+fn print_summary(index: usize, monitor: &BridgeMonitor, timing: &TimingHandle) {
+    println!(
+        "Input {index}: {} underruns, {} overruns, {} frames concealed; {}",
+        monitor.underruns(),
+        monitor.overruns(),
+        monitor.concealed_frames(),
+        timing.snapshot(),
+    );
 }
 
 fn all_input_configs(device: &cpal::Device) -> anyhow::Result<Vec<cpal::SupportedStreamConfig>> {
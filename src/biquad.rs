@@ -0,0 +1,479 @@
+use derive_builder::Builder;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use crate::fft::Complex;
+use crate::processor::Processor;
+
+// Responses from Robert Bristow-Johnson's Audio EQ Cookbook, plus first
+// order low and high passes for odd order Butterworth cascades. Gains are
+// in dB and only matter to the peaking and shelving filters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    // Constant 0 dB peak gain
+    BandPass,
+    Notch,
+    AllPass,
+    Peaking { gain_db: f64 },
+    LowShelf { gain_db: f64 },
+    HighShelf { gain_db: f64 },
+    FirstOrderLowPass,
+    FirstOrderHighPass,
+}
+
+// Normalised so that a0 is 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl Coefficients {
+    pub fn new(filter_type: FilterType, frequency: f64, q: f64, sample_rate: u32) -> Self {
+        // Kept just below Nyquist, where the cookbook formulas break down
+        let frequency = frequency.clamp(1e-3, 0.499 * sample_rate as f64);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+
+        let [b0, b1, b2, a0, a1, a2] = match filter_type {
+            FilterType::LowPass => [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            FilterType::HighPass => [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            FilterType::BandPass => [alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            FilterType::Notch => [1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            FilterType::AllPass => [1.0 - alpha, -2.0 * cos, 1.0 + alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            FilterType::Peaking { gain_db } => {
+                let a = 10f64.powf(gain_db / 40.0);
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a]
+            }
+            FilterType::LowShelf { gain_db } => {
+                let a = 10f64.powf(gain_db / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + k),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - k),
+                    (a + 1.0) + (a - 1.0) * cos + k,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - k,
+                ]
+            }
+            FilterType::HighShelf { gain_db } => {
+                let a = 10f64.powf(gain_db / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + k),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - k),
+                    (a + 1.0) - (a - 1.0) * cos + k,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - k,
+                ]
+            }
+            // Bilinear transforms of 1 / (s + 1) and s / (s + 1), prewarped
+            FilterType::FirstOrderLowPass => {
+                let k = (w0 / 2.0).tan();
+                [k, k, 0.0, k + 1.0, k - 1.0, 0.0]
+            }
+            FilterType::FirstOrderHighPass => {
+                let k = (w0 / 2.0).tan();
+                [1.0, -1.0, 0.0, k + 1.0, k - 1.0, 0.0]
+            }
+        };
+        Self { b0: b0 / a0, b1: b1 / a0, b2: b2 / a0, a1: a1 / a0, a2: a2 / a0 }
+    }
+
+    // Complex gain at `frequency`, i.e. H(z) on the unit circle
+    pub fn frequency_response(&self, frequency: f64, sample_rate: u32) -> Complex {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        let z1 = Complex::from_polar(1.0, -w);
+        let z2 = Complex::from_polar(1.0, -2.0 * w);
+        let numerator = Complex::new(self.b0, 0.0) + z1.scale(self.b1) + z2.scale(self.b2);
+        let denominator = Complex::new(1.0, 0.0) + z1.scale(self.a1) + z2.scale(self.a2);
+        numerator / denominator
+    }
+
+    pub fn magnitude(&self, frequency: f64, sample_rate: u32) -> f64 {
+        self.frequency_response(frequency, sample_rate).norm()
+    }
+
+    fn approach(&mut self, target: &Self, decay: f64) -> bool {
+        let mut settled = true;
+        for (current, &target) in [
+            (&mut self.b0, &target.b0),
+            (&mut self.b1, &target.b1),
+            (&mut self.b2, &target.b2),
+            (&mut self.a1, &target.a1),
+            (&mut self.a2, &target.a2),
+        ] {
+            *current = target + (*current - target) * decay;
+            if (*current - target).abs() > 1e-9 {
+                settled = false;
+            }
+        }
+        if settled {
+            *self = *target;
+        }
+        settled
+    }
+}
+
+#[derive(Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct BiquadConfig {
+    filter_type: FilterType,
+    frequency: f64,
+    // Butterworth by default, i.e. no resonance
+    #[builder(default = "std::f64::consts::FRAC_1_SQRT_2")]
+    q: f64,
+    sample_rate: u32,
+    #[builder(default = "1")]
+    channels: usize,
+    // Time constant with which the coefficients follow a change in settings,
+    // so that sweeping a filter does not produce zipper noise
+    #[builder(default = "Duration::from_millis(10)")]
+    smoothing: Duration,
+}
+
+impl BiquadConfigBuilder {
+    // NaN fails every comparison, so it is rejected along with the rest
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(frequency), Some(sample_rate)) = (self.frequency, self.sample_rate)
+            && !(frequency > 0.0 && frequency < sample_rate as f64 / 2.0)
+        {
+            return Err(format!("The frequency must lie between 0 Hz and {} Hz, got {frequency}", sample_rate / 2));
+        }
+        if let Some(q) = self.q
+            && !(q > 0.0 && q.is_finite())
+        {
+            return Err(format!("The Q must be positive, got {q}"));
+        }
+        if self.channels == Some(0) {
+            return Err("A biquad needs at least one channel".to_string());
+        }
+        Ok(())
+    }
+}
+
+// A second order section on interleaved audio, with its own transposed
+// direct form II state per channel
+pub struct Biquad {
+    config: BiquadConfig,
+    coefficients: Coefficients,
+    target: Coefficients,
+    decay: f64,
+    settled: bool,
+    state: Vec<[f64; 2]>,
+}
+
+impl Biquad {
+    pub fn with_config(config: BiquadConfig) -> Self {
+        let coefficients = Coefficients::new(config.filter_type, config.frequency, config.q, config.sample_rate);
+        let smoothing_frames = config.smoothing.as_secs_f64() * config.sample_rate as f64;
+        let decay = if smoothing_frames > 0.0 { (-1.0 / smoothing_frames).exp() } else { 0.0 };
+        Self {
+            state: vec![[0.0; 2]; config.channels.max(1)],
+            config,
+            coefficients,
+            target: coefficients,
+            decay,
+            settled: true,
+        }
+    }
+
+    pub fn filter_type(&self) -> FilterType {
+        self.config.filter_type
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.config.frequency
+    }
+
+    pub fn q(&self) -> f64 {
+        self.config.q
+    }
+
+    pub fn set_filter_type(&mut self, filter_type: FilterType) {
+        self.config.filter_type = filter_type;
+        self.update_target();
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.config.frequency = frequency;
+        self.update_target();
+    }
+
+    pub fn set_q(&mut self, q: f64) {
+        self.config.q = q;
+        self.update_target();
+    }
+
+    // The coefficients in use right now, which may still be moving towards
+    // the current settings
+    pub fn coefficients(&self) -> Coefficients {
+        self.coefficients
+    }
+
+    // Response of the current settings, once smoothing has caught up
+    pub fn frequency_response(&self, frequency: f64) -> Complex {
+        self.target.frequency_response(frequency, self.config.sample_rate)
+    }
+
+    fn update_target(&mut self) {
+        self.target = Coefficients::new(self.config.filter_type, self.config.frequency, self.config.q, self.config.sample_rate);
+        self.settled = self.coefficients == self.target;
+    }
+}
+
+impl Processor for Biquad {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let channels = self.state.len();
+        for frame in buffer.chunks_mut(channels) {
+            if !self.settled {
+                self.settled = self.coefficients.approach(&self.target, self.decay);
+            }
+            let c = self.coefficients;
+            for (sample, [s1, s2]) in frame.iter_mut().zip(&mut self.state) {
+                let x = *sample as f64;
+                let y = c.b0 * x + *s1;
+                *s1 = c.b1 * x - c.a1 * y + *s2;
+                *s2 = c.b2 * x - c.a2 * y;
+                *sample = y as f32;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.fill([0.0; 2]);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    Low,
+    High,
+}
+
+// Biquads in series for slopes steeper than 12 dB per octave
+pub struct Cascade {
+    sections: Vec<Biquad>,
+}
+
+impl Cascade {
+    // Maximally flat, -3 dB at `frequency`, 6 dB per octave per order
+    pub fn butterworth(pass: Pass, order: usize, frequency: f64, sample_rate: u32, channels: usize) -> anyhow::Result<Self> {
+        if order == 0 {
+            anyhow::bail!("A Butterworth filter needs an order of at least 1");
+        }
+        let section = |filter_type, q| {
+            BiquadConfigBuilder::default()
+                .filter_type(filter_type)
+                .frequency(frequency)
+                .q(q)
+                .sample_rate(sample_rate)
+                .channels(channels)
+                .build()
+                .map(Biquad::with_config)
+        };
+
+        let mut sections = Vec::new();
+        // Each pole pair of the analog prototype becomes one section
+        for k in 0..order / 2 {
+            let q = 1.0 / (2.0 * ((2 * k + 1) as f64 * PI / (2 * order) as f64).sin());
+            let filter_type = match pass {
+                Pass::Low => FilterType::LowPass,
+                Pass::High => FilterType::HighPass,
+            };
+            sections.push(section(filter_type, q)?);
+        }
+        if order % 2 == 1 {
+            let filter_type = match pass {
+                Pass::Low => FilterType::FirstOrderLowPass,
+                Pass::High => FilterType::FirstOrderHighPass,
+            };
+            sections.push(section(filter_type, std::f64::consts::FRAC_1_SQRT_2)?);
+        }
+        Ok(Self { sections })
+    }
+
+    // Two Butterworth filters of half the order in series, -6 dB at
+    // `frequency`. Low and high passes of the same order sum to a flat
+    // magnitude, which makes them the usual crossover filters.
+    pub fn linkwitz_riley(pass: Pass, order: usize, frequency: f64, sample_rate: u32, channels: usize) -> anyhow::Result<Self> {
+        if order == 0 || order % 2 == 1 {
+            anyhow::bail!("A Linkwitz-Riley filter needs an even order, got {order}");
+        }
+        let mut sections = Self::butterworth(pass, order / 2, frequency, sample_rate, channels)?.sections;
+        sections.extend(Self::butterworth(pass, order / 2, frequency, sample_rate, channels)?.sections);
+        Ok(Self { sections })
+    }
+
+    pub fn sections(&self) -> &[Biquad] {
+        &self.sections
+    }
+
+    pub fn set_frequency(&mut self, frequency: f64) {
+        for section in &mut self.sections {
+            section.set_frequency(frequency);
+        }
+    }
+
+    pub fn frequency_response(&self, frequency: f64) -> Complex {
+        self.sections
+            .iter()
+            .fold(Complex::new(1.0, 0.0), |response, section| response * section.frequency_response(frequency))
+    }
+}
+
+impl Processor for Cascade {
+    fn process(&mut self, buffer: &mut [f32]) {
+        for section in &mut self.sections {
+            section.process(buffer);
+        }
+    }
+
+    fn reset(&mut self) {
+        for section in &mut self.sections {
+            section.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::Chain;
+    use crate::window::Window;
+    use crate::{TonePlayer, TonePlayerConfigBuilder};
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn to_db(gain: f64) -> f64 {
+        20.0 * gain.log10()
+    }
+
+    // Level in dB of a full scale stereo tone after `processor`, measured on
+    // the left channel with the spectrum code once the filter has settled
+    fn measure(processor: &mut impl Processor, frequency: f32) -> f64 {
+        let mut player = TonePlayer::with_config(
+            TonePlayerConfigBuilder::default()
+                .frequency(frequency)
+                .sample_rate(SAMPLE_RATE)
+                .channels(2)
+                .build()
+                .unwrap(),
+        );
+        processor.reset();
+        let mut buffer = vec![0.0f32; SAMPLE_RATE as usize];
+        let mut window = Window::with_duration(Duration::from_millis(100), SAMPLE_RATE);
+        for block in buffer.chunks_mut(512) {
+            player.fill_buffer(block);
+            processor.process(block);
+        }
+        let left: Vec<f32> = buffer.iter().step_by(2).copied().collect();
+        window.add_samples(&left);
+        to_db(window.calculate_amplitude(frequency as f64).unwrap())
+    }
+
+    #[test]
+    fn test_filter_responses() {
+        let biquad = |filter_type, frequency, q| {
+            Biquad::with_config(
+                BiquadConfigBuilder::default()
+                    .filter_type(filter_type)
+                    .frequency(frequency)
+                    .q(q)
+                    .sample_rate(SAMPLE_RATE)
+                    .channels(2)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        // The cookbook filters hit their defining gains, measured and computed
+        let cases = [
+            (biquad(FilterType::LowPass, 1000.0, std::f64::consts::FRAC_1_SQRT_2), 1000.0, -3.01),
+            (biquad(FilterType::HighPass, 1000.0, std::f64::consts::FRAC_1_SQRT_2), 1000.0, -3.01),
+            (biquad(FilterType::BandPass, 2000.0, 2.0), 2000.0, 0.0),
+            (biquad(FilterType::Notch, 1000.0, 2.0), 1000.0, -80.0),
+            (biquad(FilterType::AllPass, 1000.0, 1.0), 300.0, 0.0),
+            (biquad(FilterType::Peaking { gain_db: 6.0 }, 1000.0, 1.0), 1000.0, 6.0),
+            (biquad(FilterType::LowShelf { gain_db: -12.0 }, 500.0, 0.7), 50.0, -12.0),
+            (biquad(FilterType::HighShelf { gain_db: 9.0 }, 2000.0, 0.7), 15000.0, 9.0),
+        ];
+        for (mut filter, frequency, expected) in cases {
+            let computed = to_db(filter.frequency_response(frequency).norm());
+            let measured = measure(&mut filter, frequency as f32);
+            if expected < -40.0 {
+                // A notch only has to be deep
+                assert!(computed < expected && measured < expected, "{:?}: {computed} dB, {measured} dB", filter.filter_type());
+            } else {
+                assert!((computed - expected).abs() < 0.15, "{:?}: {computed} dB", filter.filter_type());
+                assert!((measured - expected).abs() < 0.3, "{:?}: {measured} dB", filter.filter_type());
+            }
+        }
+
+        // 4th order Butterworth: -3 dB at the corner and at least 80 dB down
+        // a decade above it, with the stop band really that quiet
+        let mut butterworth = Cascade::butterworth(Pass::Low, 4, 1000.0, SAMPLE_RATE, 2).unwrap();
+        assert_eq!(butterworth.sections().len(), 2);
+        assert!((measure(&mut butterworth, 1000.0) + 3.01).abs() < 0.3);
+        // The bilinear transform only adds attenuation near Nyquist
+        assert!(to_db(butterworth.frequency_response(10000.0).norm()) < -80.0);
+        assert!(measure(&mut butterworth, 10000.0) < -75.0);
+        let third = Cascade::butterworth(Pass::High, 3, 1000.0, SAMPLE_RATE, 1).unwrap();
+        assert!((to_db(third.frequency_response(1000.0).norm()) + 3.01).abs() < 0.05);
+        assert!((to_db(third.frequency_response(100.0).norm()) + 60.0).abs() < 1.0);
+
+        // Linkwitz-Riley low and high passes are -6 dB at the crossover and
+        // sum back to a flat response
+        let low = Cascade::linkwitz_riley(Pass::Low, 4, 2000.0, SAMPLE_RATE, 1).unwrap();
+        let high = Cascade::linkwitz_riley(Pass::High, 4, 2000.0, SAMPLE_RATE, 1).unwrap();
+        assert!((to_db(low.frequency_response(2000.0).norm()) + 6.02).abs() < 0.05);
+        for frequency in [50.0, 500.0, 2000.0, 5000.0, 15000.0] {
+            let sum = low.frequency_response(frequency) + high.frequency_response(frequency);
+            assert!((sum.norm() - 1.0).abs() < 1e-6, "{frequency} Hz: {}", sum.norm());
+        }
+        assert!(Cascade::linkwitz_riley(Pass::Low, 3, 2000.0, SAMPLE_RATE, 1).is_err());
+        assert!(Cascade::butterworth(Pass::Low, 2, 24000.0, SAMPLE_RATE, 1).is_err());
+
+        // Settings the coefficients would turn into NaN are refused
+        let config = |frequency, q| {
+            BiquadConfigBuilder::default()
+                .filter_type(FilterType::LowPass)
+                .frequency(frequency)
+                .q(q)
+                .sample_rate(SAMPLE_RATE)
+                .build()
+        };
+        assert!(config(1000.0, 0.0).is_err());
+        assert!(config(1000.0, f64::NAN).is_err());
+        assert!(config(0.0, 1.0).is_err());
+        assert!(config(f64::NAN, 1.0).is_err());
+
+        // A chain is the product of its parts
+        let mut chain = Chain::new();
+        chain.push(biquad(FilterType::Peaking { gain_db: 6.0 }, 1000.0, 1.0));
+        chain.push(biquad(FilterType::Peaking { gain_db: 6.0 }, 1000.0, 1.0));
+        assert!((measure(&mut chain, 1000.0) - 12.0).abs() < 0.3);
+
+        // Changes glide over the smoothing time instead of jumping
+        let mut sweep = biquad(FilterType::LowPass, 1000.0, 1.0);
+        let start = sweep.coefficients();
+        sweep.set_frequency(4000.0);
+        let target = Coefficients::new(FilterType::LowPass, 4000.0, 1.0, SAMPLE_RATE);
+        let mut block = vec![0.0f32; 2 * 48];
+        sweep.process(&mut block);
+        let b0 = sweep.coefficients().b0;
+        assert!(start.b0 < b0 && b0 < target.b0);
+        for _ in 0..200 {
+            sweep.process(&mut block);
+        }
+        assert_eq!(sweep.coefficients(), target);
+    }
+}
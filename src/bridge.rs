@@ -0,0 +1,316 @@
+use derive_builder::Builder;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::drift::{DriftCompensator, DriftCompensatorConfigBuilder};
+use crate::resample::{Quality, Resampler, ResamplerConfigBuilder};
+use crate::ring_buffer::{ring_buffer, Consumer, Producer, RingBufferMonitor};
+
+// Largest number of input samples moved from the ring buffer to the
+// resampler at once
+const PENDING_SAMPLES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Concealment {
+    // Output zeros for the missing frames
+    Silence,
+    // Ramp the last good frame down to zero over the given number of frames
+    FadeOut(usize),
+}
+
+#[derive(Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct BridgeConfig {
+    input_rate: u32,
+    output_rate: u32,
+    channels: usize,
+    // Input frames to accumulate before producing output, both at start and
+    // after an underrun
+    target_fill: usize,
+    // Capacity of the ring buffer between the callbacks, in input frames
+    #[builder(default = "self.default_capacity()")]
+    capacity: usize,
+    #[builder(default = "Concealment::FadeOut(64)")]
+    concealment: Concealment,
+    // Steer the resampling ratio to hold `target_fill`, for inputs whose
+    // clock is not locked to the output
+    #[builder(default = "true")]
+    drift_compensation: bool,
+    #[builder(default = "Quality::Medium")]
+    quality: Quality,
+}
+
+impl BridgeConfigBuilder {
+    fn default_capacity(&self) -> usize {
+        self.target_fill.unwrap_or_default() * 8
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.channels == Some(0) {
+            return Err("A bridge needs at least one channel".to_string());
+        }
+        if self.target_fill == Some(0) {
+            return Err("The target fill must be at least one frame".to_string());
+        }
+        if self.capacity == Some(0) {
+            return Err("The capacity must be at least one frame".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Connects an input callback to an output callback running on another clock.
+//
+// The input side pushes whatever it captures through the `Producer`; the
+// output side calls `read` and always gets exactly the number of frames it
+// asked for, resampled to the output rate. Missing input is concealed and
+// counted, and the bridge refills to its target level before resuming.
+pub struct Bridge {
+    channels: usize,
+    target_fill: usize,
+    concealment: Concealment,
+    consumer: Consumer,
+    pending: Vec<f32>,
+    pending_len: usize,
+    resampler: Resampler,
+    compensator: Option<DriftCompensator>,
+    priming: bool,
+    last_frame: Vec<f32>,
+    fade_position: usize,
+    stats: Arc<BridgeStats>,
+}
+
+struct BridgeStats {
+    underruns: AtomicUsize,
+    concealed_frames: AtomicUsize,
+    drift_ppm: AtomicU64,
+}
+
+impl Bridge {
+    pub fn with_config(config: BridgeConfig) -> anyhow::Result<(Self, Producer)> {
+        let resampler = Resampler::with_config(
+            ResamplerConfigBuilder::default()
+                .input_rate(config.input_rate)
+                .output_rate(config.output_rate)
+                .channels(config.channels)
+                .quality(config.quality)
+                .build()?,
+        );
+        let compensator = if config.drift_compensation {
+            Some(DriftCompensator::with_config(
+                DriftCompensatorConfigBuilder::default()
+                    .sample_rate(config.output_rate)
+                    .target_fill(config.target_fill)
+                    .build()?,
+            ))
+        } else {
+            None
+        };
+        let (producer, consumer) = ring_buffer(config.capacity * config.channels);
+
+        let bridge = Self {
+            channels: config.channels,
+            target_fill: config.target_fill,
+            concealment: config.concealment,
+            consumer,
+            pending: vec![0.0; PENDING_SAMPLES / config.channels * config.channels],
+            pending_len: 0,
+            resampler,
+            compensator,
+            priming: true,
+            last_frame: vec![0.0; config.channels],
+            fade_position: 0,
+            stats: Arc::new(BridgeStats {
+                underruns: AtomicUsize::new(0),
+                concealed_frames: AtomicUsize::new(0),
+                drift_ppm: AtomicU64::new(0.0f64.to_bits()),
+            }),
+        };
+        Ok((bridge, producer))
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    // Input frames waiting anywhere between the producer and the output
+    pub fn fill(&self) -> usize {
+        (self.consumer.len() + self.pending_len) / self.channels + self.resampler.buffered_frames() as usize
+    }
+
+    // Fills all of `output`, interleaved with the configured channel count
+    pub fn read(&mut self, output: &mut [f32]) {
+        let channels = self.channels;
+        let frames = output.len() / channels;
+
+        if self.priming {
+            if self.fill() < self.target_fill {
+                self.conceal(output);
+                return;
+            }
+            self.priming = false;
+        }
+
+        let fill = self.fill();
+        if let Some(compensator) = self.compensator.as_mut() {
+            let factor = compensator.update(fill, frames);
+            self.resampler.set_ratio_adjustment(factor);
+            self.stats.drift_ppm.store(compensator.drift_ppm().to_bits(), Ordering::Relaxed);
+        }
+
+        let mut produced = 0;
+        while produced < frames {
+            let count = self.consumer.len().min(self.pending.len() - self.pending_len) / channels * channels;
+            self.pending_len += self.consumer.pop_slice(&mut self.pending[self.pending_len..self.pending_len + count]);
+            let (consumed, written) = self.resampler.process(
+                &self.pending[..self.pending_len],
+                &mut output[produced * channels..frames * channels],
+            );
+            self.pending.copy_within(consumed * channels..self.pending_len, 0);
+            self.pending_len -= consumed * channels;
+            produced += written;
+            if consumed == 0 && written == 0 {
+                break;
+            }
+        }
+
+        if produced > 0 {
+            self.last_frame.copy_from_slice(&output[(produced - 1) * channels..produced * channels]);
+            self.fade_position = 0;
+        }
+        if produced < frames {
+            self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+            self.priming = true;
+            self.conceal(&mut output[produced * channels..]);
+        }
+    }
+
+    fn conceal(&mut self, output: &mut [f32]) {
+        let frames = output.len() / self.channels;
+        self.stats.concealed_frames.fetch_add(frames, Ordering::Relaxed);
+        match self.concealment {
+            Concealment::Silence => output.fill(0.0),
+            Concealment::FadeOut(length) => {
+                for frame in output.chunks_exact_mut(self.channels) {
+                    let gain = 1.0 - (self.fade_position as f32 / length as f32).min(1.0);
+                    for (sample, &last) in frame.iter_mut().zip(&self.last_frame) {
+                        *sample = last * gain;
+                    }
+                    self.fade_position += 1;
+                }
+            }
+        }
+    }
+
+    pub fn monitor(&self) -> BridgeMonitor {
+        BridgeMonitor {
+            ring: self.consumer.monitor(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+// Counters of a bridge that can be read from any thread
+#[derive(Clone)]
+pub struct BridgeMonitor {
+    ring: RingBufferMonitor,
+    stats: Arc<BridgeStats>,
+}
+
+impl BridgeMonitor {
+    // Output callbacks that ran out of input
+    pub fn underruns(&self) -> usize {
+        self.stats.underruns.load(Ordering::Relaxed)
+    }
+
+    // Input callbacks that did not fit into the ring buffer
+    pub fn overruns(&self) -> usize {
+        self.ring.overruns()
+    }
+
+    pub fn xruns(&self) -> usize {
+        self.underruns() + self.overruns()
+    }
+
+    // Output frames filled by concealment, including while priming
+    pub fn concealed_frames(&self) -> usize {
+        self.stats.concealed_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn drift_ppm(&self) -> f64 {
+        f64::from_bits(self.stats.drift_ppm.load(Ordering::Relaxed))
+    }
+
+    pub fn buffered_samples(&self) -> usize {
+        self.ring.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fills_exact_output_and_counts_underruns() {
+        let (mut bridge, mut producer) = Bridge::with_config(
+            BridgeConfigBuilder::default()
+                .input_rate(48000)
+                .output_rate(48000)
+                .channels(2)
+                .target_fill(64)
+                .concealment(Concealment::Silence)
+                .drift_compensation(false)
+                .build()
+                .expect("Failed to build BridgeConfig"),
+        )
+        .unwrap();
+        let monitor = bridge.monitor();
+
+        // Nothing comes out until the target fill is reached
+        let mut output = vec![1.0; 2 * 50];
+        bridge.read(&mut output);
+        assert!(output.iter().all(|&s| s == 0.0));
+        assert_eq!(monitor.underruns(), 0);
+
+        // Input arrives in blocks of 100 frames every other output callback
+        // of 50 frames
+        producer.push_slice(&[0.5; 2 * 64]);
+        for i in 0..20 {
+            if i % 2 == 0 {
+                producer.push_slice(&[0.5; 2 * 100]);
+            }
+            output.fill(f32::NAN);
+            bridge.read(&mut output);
+            assert!(output.iter().all(|s| s.is_finite()));
+        }
+        assert_eq!(monitor.underruns(), 0);
+
+        // The producer stops; the next reads conceal with silence
+        for _ in 0..10 {
+            output.fill(f32::NAN);
+            bridge.read(&mut output);
+            assert!(output.iter().all(|s| s.is_finite()));
+        }
+        assert_eq!(*output.last().unwrap(), 0.0);
+        assert_eq!(monitor.underruns(), 1);
+        assert_eq!(monitor.overruns(), 0);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = |channels, target_fill, capacity| {
+            BridgeConfigBuilder::default()
+                .input_rate(48000)
+                .output_rate(48000)
+                .channels(channels)
+                .target_fill(target_fill)
+                .capacity(capacity)
+                .build()
+        };
+        assert!(config(2, 64, 4096).is_ok());
+        assert!(config(0, 64, 4096).is_err());
+        // A zero target fill would leave no room in the ring buffer
+        assert!(config(2, 0, 4096).is_err());
+        assert!(config(2, 64, 0).is_err());
+    }
+}
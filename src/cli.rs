@@ -0,0 +1,153 @@
+use anyhow::Context;
+use std::time::Duration;
+
+use crate::delay::TimeSpec;
+use crate::generator::Waveform;
+
+// Parses seconds with an optional `s` or `ms` suffix, e.g. 5s, 250ms or 2.5,
+// for use as a clap value parser
+pub fn parse_duration(text: &str) -> anyhow::Result<Duration> {
+    let (number, scale) = if let Some(ms) = text.strip_suffix("ms") {
+        (ms, 1e-3)
+    } else {
+        (text.strip_suffix('s').unwrap_or(text), 1.0)
+    };
+    let value: f64 = number.trim().parse().with_context(|| format!("Invalid duration '{text}'"))?;
+    Duration::try_from_secs_f64(value * scale).with_context(|| format!("Invalid duration '{text}'"))
+}
+
+// One tone of a step, written FREQ[/WAVEFORM][@AMPLITUDE], e.g. 440/square@0.5
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToneSpec {
+    pub frequency: f64,
+    pub waveform: Waveform,
+    pub amplitude: f32,
+}
+
+impl std::str::FromStr for ToneSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (rest, amplitude) = match text.split_once('@') {
+            Some((rest, amplitude)) => {
+                let amplitude = amplitude.parse().with_context(|| format!("Invalid amplitude '{amplitude}'"))?;
+                (rest, amplitude)
+            }
+            None => (text, 1.0),
+        };
+        let (frequency, waveform) = match rest.split_once('/') {
+            Some((frequency, waveform)) => (frequency, waveform.parse()?),
+            None => (rest, Waveform::Sine),
+        };
+        let frequency: f64 = frequency.parse().with_context(|| format!("Invalid frequency '{frequency}'"))?;
+        if frequency <= 0.0 {
+            anyhow::bail!("Frequency must be positive, got {frequency}");
+        }
+        Ok(Self { frequency, waveform, amplitude })
+    }
+}
+
+// Tones played together, written TONE[+TONE...][:DURATION],
+// e.g. 440+660@0.5:250ms
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepSpec {
+    pub tones: Vec<ToneSpec>,
+    pub duration: Option<Duration>,
+}
+
+impl std::str::FromStr for StepSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (tones, duration) = match text.split_once(':') {
+            Some((tones, duration)) => (tones, Some(parse_duration(duration)?)),
+            None => (text, None),
+        };
+        let tones = tones.split('+').map(str::parse).collect::<Result<_, _>>()?;
+        Ok(Self { tones, duration })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EffectKind {
+    Delay,
+    PingPong,
+    Chorus,
+    Flanger,
+    Reverb,
+}
+
+// An effect with its default settings, written NAME[:TIME], e.g. delay:1/8d or
+// flanger:4s. The time is the delay of the echoes, one sweep of a chorus or
+// flanger, or the pre-delay of a reverb.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EffectSpec {
+    pub kind: EffectKind,
+    pub time: Option<TimeSpec>,
+}
+
+impl std::str::FromStr for EffectSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, time) = match text.split_once(':') {
+            Some((name, time)) => (name, Some(time.parse()?)),
+            None => (text, None),
+        };
+        let kind = match name.to_ascii_lowercase().as_str() {
+            "delay" | "echo" => EffectKind::Delay,
+            "pingpong" | "ping-pong" => EffectKind::PingPong,
+            "chorus" => EffectKind::Chorus,
+            "flanger" => EffectKind::Flanger,
+            "reverb" => EffectKind::Reverb,
+            _ => anyhow::bail!("Unknown effect '{name}', expected delay, pingpong, chorus, flanger or reverb"),
+        };
+        // Only a reverb works without a delay; an echo or sweep of no time
+        // has no rate
+        if kind != EffectKind::Reverb && time == Some(TimeSpec::Duration(Duration::ZERO)) {
+            anyhow::bail!("The time of effect '{text}' must be positive");
+        }
+        Ok(Self { kind, time })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_steps() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert!(parse_duration("-1s").is_err());
+
+        let step: StepSpec = "440+880/square@0.25:2s".parse().unwrap();
+        assert_eq!(
+            step,
+            StepSpec {
+                tones: vec![
+                    ToneSpec { frequency: 440.0, waveform: Waveform::Sine, amplitude: 1.0 },
+                    ToneSpec { frequency: 880.0, waveform: Waveform::Square, amplitude: 0.25 },
+                ],
+                duration: Some(Duration::from_secs(2)),
+            }
+        );
+        assert_eq!("1000/saw".parse::<StepSpec>().unwrap().duration, None);
+        assert!("440/noise".parse::<StepSpec>().is_err());
+        assert!("440+:1s".parse::<StepSpec>().is_err());
+    }
+
+    #[test]
+    fn test_parse_effects() {
+        let effect: EffectSpec = "delay:1/8d".parse().unwrap();
+        assert_eq!(effect.kind, EffectKind::Delay);
+        assert_eq!(effect.time.unwrap().duration(120.0), Duration::from_millis(375));
+        let effect: EffectSpec = "Flanger:4s".parse().unwrap();
+        assert_eq!((effect.kind, effect.time), (EffectKind::Flanger, Some(TimeSpec::Duration(Duration::from_secs(4)))));
+        assert_eq!("chorus".parse::<EffectSpec>().unwrap().time, None);
+        assert!("phaser".parse::<EffectSpec>().is_err());
+        assert!("delay:1/x".parse::<EffectSpec>().is_err());
+        assert!("flanger:0s".parse::<EffectSpec>().is_err());
+        assert!("reverb:0ms".parse::<EffectSpec>().is_ok());
+    }
+}
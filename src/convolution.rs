@@ -0,0 +1,272 @@
+use anyhow::Context;
+use std::path::Path;
+
+use crate::fft::{fft, Complex};
+use crate::processor::Processor;
+use crate::render::read_wav;
+use crate::resample::{Resampler, ResamplerConfigBuilder};
+
+// Input of one channel and its spectra so far
+struct ChannelState {
+    // The previous block followed by the one being filled
+    input: Vec<f32>,
+    // Spectra of the latest input blocks, one per partition, used as a ring
+    history: Vec<Vec<Complex>>,
+    // The block being played out
+    output: Vec<f32>,
+}
+
+// Uniformly partitioned overlap-save convolution. The impulse response is
+// cut into blocks whose spectra are multiplied with the spectra of as many
+// past input blocks, so a long response costs one FFT pair per block plus
+// a multiply-add per partition. Output lags the input by one block; nothing
+// is allocated after construction.
+pub struct Convolver {
+    block_size: usize,
+    channels: usize,
+    // [impulse response channel][partition][bin]
+    partitions: Vec<Vec<Vec<Complex>>>,
+    states: Vec<ChannelState>,
+    // Ring position of the newest input spectrum
+    head: usize,
+    // Frames of the current block filled so far
+    position: usize,
+    spectrum: Vec<Complex>,
+    sum: Vec<Complex>,
+    mix: f32,
+}
+
+impl Convolver {
+    // `impulse_response` is interleaved with either one channel, applied to
+    // every channel, or one per channel. The block size must be a power of two.
+    pub fn new(impulse_response: &[f32], ir_channels: usize, channels: usize, block_size: usize) -> anyhow::Result<Self> {
+        if !block_size.is_power_of_two() {
+            anyhow::bail!("The block size must be a power of two, got {block_size}");
+        }
+        if ir_channels != 1 && ir_channels != channels {
+            anyhow::bail!("An impulse response with {ir_channels} channels cannot be used on {channels} channels");
+        }
+        let size = 2 * block_size;
+        let frames = (impulse_response.len() / ir_channels).max(1);
+        let count = frames.div_ceil(block_size);
+
+        let partitions = (0..ir_channels)
+            .map(|channel| {
+                (0..count)
+                    .map(|partition| {
+                        let mut spectrum = vec![Complex::default(); size];
+                        for (index, value) in spectrum[..block_size].iter_mut().enumerate() {
+                            let frame = partition * block_size + index;
+                            if let Some(&sample) = impulse_response.get(frame * ir_channels + channel) {
+                                value.re = sample as f64;
+                            }
+                        }
+                        fft(&mut spectrum, false);
+                        spectrum
+                    })
+                    .collect()
+            })
+            .collect();
+        let states = (0..channels)
+            .map(|_| ChannelState {
+                input: vec![0.0; size],
+                history: vec![vec![Complex::default(); size]; count],
+                output: vec![0.0; block_size],
+            })
+            .collect();
+
+        Ok(Self {
+            block_size,
+            channels,
+            partitions,
+            states,
+            head: 0,
+            position: 0,
+            spectrum: vec![Complex::default(); size],
+            sum: vec![Complex::default(); size],
+            mix: 1.0,
+        })
+    }
+
+    // Loads an impulse response from a WAV file, resampling it if it was
+    // recorded at another rate than `sample_rate`
+    pub fn from_wav(path: impl AsRef<Path>, sample_rate: u32, channels: usize, block_size: usize) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let wav = read_wav(path)?;
+        let samples = if wav.sample_rate == sample_rate {
+            wav.samples
+        } else {
+            resample_impulse_response(&wav.samples, wav.channels, wav.sample_rate, sample_rate)
+                .with_context(|| format!("Failed to resample {}", path.display()))?
+        };
+        Self::new(&samples, wav.channels, channels, block_size)
+            .with_context(|| format!("Failed to use {} as an impulse response", path.display()))
+    }
+
+    pub fn latency_frames(&self) -> usize {
+        self.block_size
+    }
+
+    // Share of the output that is convolved; the dry rest is delayed to match
+    pub fn set_mix(&mut self, mix: f32) {
+        self.mix = mix;
+    }
+
+    fn process_block(&mut self) {
+        let block_size = self.block_size;
+        let count = self.partitions[0].len();
+        for (channel, state) in self.states.iter_mut().enumerate() {
+            for (value, &sample) in self.spectrum.iter_mut().zip(&state.input) {
+                *value = Complex::new(sample as f64, 0.0);
+            }
+            fft(&mut self.spectrum, false);
+            state.history[self.head].copy_from_slice(&self.spectrum);
+
+            let partitions = &self.partitions[channel.min(self.partitions.len() - 1)];
+            self.sum.fill(Complex::default());
+            for (age, filter) in partitions.iter().enumerate() {
+                let input = &state.history[(self.head + count - age) % count];
+                for ((sum, &x), &h) in self.sum.iter_mut().zip(input).zip(filter) {
+                    *sum = *sum + x * h;
+                }
+            }
+            fft(&mut self.sum, true);
+            // The first half wraps around; the second is the linear convolution
+            for (output, value) in state.output.iter_mut().zip(&self.sum[block_size..]) {
+                *output = value.re as f32;
+            }
+            state.input.copy_within(block_size.., 0);
+        }
+        self.head = (self.head + 1) % count;
+    }
+}
+
+impl Processor for Convolver {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let block_size = self.block_size;
+        for frame in buffer.chunks_mut(self.channels) {
+            for (sample, state) in frame.iter_mut().zip(&mut self.states) {
+                let dry = state.input[self.position];
+                state.input[block_size + self.position] = *sample;
+                *sample = dry * (1.0 - self.mix) + state.output[self.position] * self.mix;
+            }
+            self.position += 1;
+            if self.position == block_size {
+                self.process_block();
+                self.position = 0;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for state in &mut self.states {
+            state.input.fill(0.0);
+            state.output.fill(0.0);
+            state.history.iter_mut().for_each(|spectrum| spectrum.fill(Complex::default()));
+        }
+        self.position = 0;
+    }
+}
+
+// Converts an interleaved impulse response to another rate, scaled so that
+// its frequency response keeps the same level
+fn resample_impulse_response(samples: &[f32], channels: usize, from: u32, to: u32) -> anyhow::Result<Vec<f32>> {
+    let mut resampler = Resampler::with_config(
+        ResamplerConfigBuilder::default()
+            .input_rate(from)
+            .output_rate(to)
+            .channels(channels)
+            .build()?,
+    );
+    let frames = samples.len() / channels;
+    let expected = (frames as f64 * to as f64 / from as f64).ceil() as usize;
+    // Enough silence after the response to flush the filter
+    let mut input = samples.to_vec();
+    input.resize(samples.len() + 128 * channels, 0.0);
+
+    let mut output = vec![0.0; expected * channels];
+    let mut consumed = 0;
+    let mut produced = 0;
+    while produced < expected && consumed < input.len() / channels {
+        let (taken, made) = resampler.process(&input[consumed * channels..], &mut output[produced * channels..]);
+        consumed += taken;
+        produced += made;
+    }
+    let gain = from as f32 / to as f32;
+    output.iter_mut().for_each(|sample| *sample *= gain);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{Mls, Signal};
+
+    fn direct_convolution(input: &[f32], impulse_response: &[f32]) -> Vec<f32> {
+        (0..input.len())
+            .map(|n| {
+                impulse_response
+                    .iter()
+                    .enumerate()
+                    .take(n + 1)
+                    .map(|(k, &h)| h * input[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_direct_convolution() {
+        // A decaying noise burst as a room-like response, and noise as input
+        let impulse_response: Vec<f32> = Mls::new(12)
+            .take_values(1000)
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| x * 0.3 * (-(i as f32) / 200.0).exp())
+            .collect();
+        let input: Vec<f32> = Mls::new(10).take_values(5000).into_iter().map(|x| x * 0.5).collect();
+
+        // Stereo, with the right channel 6 dB down, in callback sizes
+        // that do not line up with the blocks
+        let stereo: Vec<f32> = input.iter().zip(input.iter().map(|x| x * 0.5)).flat_map(|(l, r)| [*l, r]).collect();
+        let mut convolver = Convolver::new(&impulse_response, 1, 2, 64).unwrap();
+        assert_eq!(convolver.latency_frames(), 64);
+        let mut output = stereo.clone();
+        let mut start = 0;
+        for size in [1, 37, 64, 200, 3].iter().cycle() {
+            let end = (start + size * 2).min(output.len());
+            convolver.process(&mut output[start..end]);
+            start = end;
+            if start == output.len() {
+                break;
+            }
+        }
+
+        let expected = direct_convolution(&input, &impulse_response);
+        for (n, &value) in expected.iter().enumerate().take(input.len() - 64) {
+            let left = output[(n + 64) * 2];
+            let right = output[(n + 64) * 2 + 1];
+            assert!((left - value).abs() < 1e-4, "frame {n}: {left} != {value}");
+            assert!((right - 0.5 * value).abs() < 1e-4, "frame {n}: {right} != {}", 0.5 * value);
+        }
+        assert!(output[..128].iter().all(|&x| x == 0.0));
+
+        // Half dry, half wet, with the dry part delayed alongside
+        convolver.reset();
+        convolver.set_mix(0.5);
+        let mut output = stereo.clone();
+        convolver.process(&mut output);
+        let n = 3000;
+        assert!((output[(n + 64) * 2] - 0.5 * (input[n] + expected[n])).abs() < 1e-4);
+
+        assert!(Convolver::new(&impulse_response, 1, 2, 100).is_err());
+        assert!(Convolver::new(&[0.0; 30], 3, 2, 64).is_err());
+
+        // A resampled impulse keeps its gain at low frequencies
+        let mut impulse = vec![0.0; 100];
+        impulse[50] = 1.0;
+        let resampled = resample_impulse_response(&impulse, 1, 44100, 48000).unwrap();
+        assert_eq!(resampled.len(), 109);
+        assert!((resampled.iter().sum::<f32>() - 1.0).abs() < 0.02);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::fft::{fft, real_fft};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delay {
+    // Offset of `reference` within `signal`, in samples
+    pub lag: usize,
+    // Correlation value at the peak
+    pub peak: f32,
+    // Ratio of the peak to the RMS of the whole correlation; a clean
+    // detection is well above 10
+    pub clarity: f32,
+}
+
+// Cross-correlation of `signal` with `reference` for lags 0..signal.len(),
+// computed through the FFT
+pub fn cross_correlate(signal: &[f32], reference: &[f32]) -> Vec<f32> {
+    let size = (signal.len() + reference.len()).next_power_of_two();
+    let signal_spectrum = real_fft(signal, size);
+    let reference_spectrum = real_fft(reference, size);
+
+    let mut product: Vec<_> = signal_spectrum
+        .iter()
+        .zip(&reference_spectrum)
+        .map(|(&s, &r)| s * r.conj())
+        .collect();
+    fft(&mut product, true);
+
+    product[..signal.len()].iter().map(|value| value.re as f32).collect()
+}
+
+// Finds where `reference` occurs in `signal`
+pub fn find_delay(signal: &[f32], reference: &[f32]) -> Option<Delay> {
+    if signal.is_empty() || reference.is_empty() {
+        return None;
+    }
+
+    let correlation = cross_correlate(signal, reference);
+    let (lag, &peak) = correlation
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))?;
+    let rms = (correlation.iter().map(|&x| x * x).sum::<f32>() / correlation.len() as f32).sqrt();
+
+    Some(Delay {
+        lag,
+        peak,
+        clarity: peak.abs() / (rms + f32::EPSILON),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{Mls, Signal};
+
+    #[test]
+    fn test_finds_delay_in_noise() {
+        let reference = Mls::new(10).take_values(1023);
+        let mut signal = vec![0.0; 3000];
+        for (i, sample) in signal.iter_mut().enumerate() {
+            // Deterministic pseudo-noise well above the reference level
+            *sample = ((i * 7919) % 113) as f32 / 113.0 - 0.5;
+        }
+        for (i, &value) in reference.iter().enumerate() {
+            signal[1234 + i] += value * 0.3;
+        }
+
+        let delay = find_delay(&signal, &reference).unwrap();
+        assert_eq!(delay.lag, 1234);
+        assert!(delay.clarity > 10.0, "{}", delay.clarity);
+    }
+}
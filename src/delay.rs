@@ -0,0 +1,403 @@
+use derive_builder::Builder;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::cli::parse_duration;
+use crate::processor::Processor;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    // 4-point Hermite, smoother for delays that are modulated
+    Cubic,
+}
+
+// Mono circular buffer read at fractional delays
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    // Where the next sample goes
+    position: usize,
+    interpolation: Interpolation,
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize, interpolation: Interpolation) -> Self {
+        Self {
+            // Room for the interpolation points on either side
+            buffer: vec![0.0; max_delay + 3],
+            position: 0,
+            interpolation,
+        }
+    }
+
+    pub fn max_delay(&self) -> usize {
+        self.buffer.len() - 3
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        self.buffer[self.position] = sample;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+
+    // The signal `delay` frames before the next sample to be pushed, so
+    // that reading, then pushing, delays by exactly `delay`. Delays are
+    // clamped to between one frame and `max_delay`.
+    pub fn read(&self, delay: f64) -> f32 {
+        let delay = delay.clamp(1.0, self.max_delay() as f64);
+        let whole = delay.floor() as usize;
+        let fraction = (delay - whole as f64) as f32;
+        match self.interpolation {
+            Interpolation::Linear => {
+                let a = self.at(whole);
+                a + (self.at(whole + 1) - a) * fraction
+            }
+            Interpolation::Cubic => {
+                let y0 = self.at(whole.saturating_sub(1).max(1));
+                let y1 = self.at(whole);
+                let y2 = self.at(whole + 1);
+                let y3 = self.at(whole + 2);
+                let c1 = 0.5 * (y2 - y0);
+                let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+                ((c3 * fraction + c2) * fraction + c1) * fraction + y1
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+
+    fn at(&self, delay: usize) -> f32 {
+        let length = self.buffer.len();
+        self.buffer[(self.position + length - delay % length) % length]
+    }
+}
+
+// A note length relative to a tempo, written like 1/4, 1/8d (dotted) or
+// 1/16t (triplet); a quarter note is one beat
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteValue {
+    beats: f64,
+}
+
+impl NoteValue {
+    pub fn duration(self, bpm: f64) -> Duration {
+        Duration::from_secs_f64(self.beats * 60.0 / bpm)
+    }
+
+    // For syncing a modulation rate: one cycle per note
+    pub fn frequency(self, bpm: f64) -> f64 {
+        bpm / (60.0 * self.beats)
+    }
+}
+
+impl FromStr for NoteValue {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (value, factor) = if let Some(value) = s.strip_suffix('d') {
+            (value, 1.5)
+        } else if let Some(value) = s.strip_suffix('t') {
+            (value, 2.0 / 3.0)
+        } else {
+            (s, 1.0)
+        };
+        let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+        let numerator: f64 = numerator.trim().parse().map_err(|_| anyhow::anyhow!("Bad note value '{s}'"))?;
+        let denominator: f64 = denominator.trim().parse().map_err(|_| anyhow::anyhow!("Bad note value '{s}'"))?;
+        if numerator <= 0.0 || denominator <= 0.0 {
+            anyhow::bail!("Note value '{s}' must be positive");
+        }
+        Ok(Self { beats: 4.0 * numerator / denominator * factor })
+    }
+}
+
+// A time given either outright or as a note value at the tempo, e.g. 350ms or 1/8d
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeSpec {
+    Duration(Duration),
+    Note(NoteValue),
+}
+
+impl TimeSpec {
+    pub fn duration(&self, bpm: f64) -> Duration {
+        match self {
+            TimeSpec::Duration(duration) => *duration,
+            TimeSpec::Note(note) => note.duration(bpm),
+        }
+    }
+}
+
+impl FromStr for TimeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('/') {
+            s.parse().map(TimeSpec::Note)
+        } else {
+            parse_duration(s).map(TimeSpec::Duration)
+        }
+    }
+}
+
+#[derive(Builder)]
+pub struct DelayConfig {
+    sample_rate: u32,
+    channels: usize,
+    #[builder(default = "Duration::from_millis(350)")]
+    time: Duration,
+    // Share of each echo fed back into the next one
+    #[builder(default = "0.4")]
+    feedback: f32,
+    // Share of the output that is delayed; the rest is the dry input
+    #[builder(default = "0.3")]
+    mix: f32,
+    // 0 keeps echoes bright, towards 1 each one is duller than the last
+    #[builder(default = "0.0")]
+    damping: f32,
+}
+
+// Echoes with feedback. Every channel has its own line, unless the delay
+// ping-pongs, in which case the input goes into the first channel's line
+// and every line feeds the next one, so echoes move across the channels.
+pub struct FeedbackDelay {
+    config: DelayConfig,
+    lines: Vec<DelayLine>,
+    // What each line puts out this frame
+    wet: Vec<f32>,
+    // One-pole low pass state per line for the damping
+    damped: Vec<f32>,
+    ping_pong: bool,
+}
+
+impl FeedbackDelay {
+    pub fn with_config(config: DelayConfig) -> Self {
+        Self::new(config, false)
+    }
+
+    pub fn ping_pong(config: DelayConfig) -> Self {
+        Self::new(config, true)
+    }
+
+    fn new(config: DelayConfig, ping_pong: bool) -> Self {
+        let channels = config.channels.max(1);
+        let frames = (config.time.as_secs_f64() * config.sample_rate as f64).round().max(1.0) as usize;
+        Self {
+            lines: (0..channels).map(|_| DelayLine::new(frames, Interpolation::Linear)).collect(),
+            wet: vec![0.0; channels],
+            damped: vec![0.0; channels],
+            config,
+            ping_pong,
+        }
+    }
+
+    pub fn delay_frames(&self) -> usize {
+        self.lines[0].max_delay()
+    }
+}
+
+impl Processor for FeedbackDelay {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let channels = self.lines.len();
+        let delay = self.delay_frames() as f64;
+        let DelayConfig { feedback, mix, damping, .. } = self.config;
+        for frame in buffer.chunks_mut(channels) {
+            for ((line, wet), damped) in self.lines.iter().zip(&mut self.wet).zip(&mut self.damped) {
+                *wet = line.read(delay);
+                *damped = *wet + (*damped - *wet) * damping;
+            }
+            let mono = frame.iter().sum::<f32>() / frame.len() as f32;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let input = if !self.ping_pong {
+                    *sample + feedback * self.damped[channel]
+                } else {
+                    let previous = self.damped[(channel + channels - 1) % channels];
+                    if channel == 0 { mono + feedback * previous } else { feedback * previous }
+                };
+                self.lines[channel].push(input);
+                *sample = *sample * (1.0 - mix) + self.wet[channel] * mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.damped.fill(0.0);
+    }
+}
+
+#[derive(Builder)]
+pub struct ModulationConfig {
+    sample_rate: u32,
+    channels: usize,
+    // Delay at the centre of the sweep
+    delay: Duration,
+    // How far the delay swings either side of the centre
+    depth: Duration,
+    // Sweeps per second
+    rate: f64,
+    #[builder(default = "0.0")]
+    feedback: f32,
+    #[builder(default = "0.5")]
+    mix: f32,
+    // Phase offset of the sweep between neighbouring channels in cycles,
+    // which widens the stereo image
+    #[builder(default = "0.25")]
+    spread: f64,
+}
+
+// A delay swept by a sine; a chorus with a long delay and no feedback, a
+// flanger with a short one and lots of feedback
+pub struct ModulatedDelay {
+    config: ModulationConfig,
+    lines: Vec<DelayLine>,
+    // In cycles
+    phase: f64,
+}
+
+impl ModulatedDelay {
+    pub fn with_config(config: ModulationConfig) -> Self {
+        let max = (config.delay + config.depth).as_secs_f64() * config.sample_rate as f64;
+        Self {
+            lines: (0..config.channels.max(1)).map(|_| DelayLine::new(max.ceil() as usize + 1, Interpolation::Cubic)).collect(),
+            config,
+            phase: 0.0,
+        }
+    }
+
+    // 20 ms swept by 5 ms at 0.8 Hz, mixed half and half
+    pub fn chorus(sample_rate: u32, channels: usize) -> Self {
+        Self::with_config(
+            ModulationConfigBuilder::default()
+                .sample_rate(sample_rate)
+                .channels(channels)
+                .delay(Duration::from_millis(20))
+                .depth(Duration::from_millis(5))
+                .rate(0.8)
+                .build()
+                .expect("All required fields are set"),
+        )
+    }
+
+    // 3 ms swept by 2 ms at 0.25 Hz with strong feedback
+    pub fn flanger(sample_rate: u32, channels: usize) -> Self {
+        Self::with_config(
+            ModulationConfigBuilder::default()
+                .sample_rate(sample_rate)
+                .channels(channels)
+                .delay(Duration::from_millis(3))
+                .depth(Duration::from_millis(2))
+                .rate(0.25)
+                .feedback(0.7)
+                .spread(0.0)
+                .build()
+                .expect("All required fields are set"),
+        )
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.config.rate = rate;
+    }
+}
+
+impl Processor for ModulatedDelay {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let sample_rate = self.config.sample_rate as f64;
+        let centre = self.config.delay.as_secs_f64() * sample_rate;
+        let depth = self.config.depth.as_secs_f64() * sample_rate;
+        let ModulationConfig { feedback, mix, spread, rate, .. } = self.config;
+        for frame in buffer.chunks_mut(self.lines.len()) {
+            for (channel, (sample, line)) in frame.iter_mut().zip(&mut self.lines).enumerate() {
+                let phase = self.phase + spread * channel as f64;
+                let delay = centre + depth * (2.0 * std::f64::consts::PI * phase).sin();
+                let wet = line.read(delay);
+                line.push(*sample + feedback * wet);
+                *sample = *sample * (1.0 - mix) + wet * mix;
+            }
+            self.phase = (self.phase + rate / sample_rate).fract();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lines.iter_mut().for_each(DelayLine::clear);
+        self.phase = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_effects() {
+        // Both interpolations are exact on a ramp
+        for interpolation in [Interpolation::Linear, Interpolation::Cubic] {
+            let mut line = DelayLine::new(16, interpolation);
+            for value in 0..20 {
+                line.push(value as f32);
+            }
+            assert_eq!(line.read(1.0), 19.0);
+            assert!((line.read(3.25) - 16.75).abs() < 1e-5, "{interpolation:?}");
+            assert_eq!(line.read(100.0), 4.0);
+        }
+
+        let note = |s: &str| s.parse::<NoteValue>().unwrap();
+        assert_eq!(note("1/4").duration(120.0), Duration::from_millis(500));
+        assert_eq!(note("1/8d").duration(120.0), Duration::from_millis(375));
+        assert!((note("1/4t").duration(120.0).as_secs_f64() - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(note("1").frequency(120.0), 0.5);
+        assert!("1/0".parse::<NoteValue>().is_err());
+
+        // An impulse comes back every 10 frames at feedback to the power of
+        // the echo, scaled by the mix
+        let config = || {
+            DelayConfigBuilder::default()
+                .sample_rate(1000)
+                .channels(2)
+                .time(Duration::from_millis(10))
+                .feedback(0.5)
+                .mix(0.5)
+                .build()
+                .unwrap()
+        };
+        let mut delay = FeedbackDelay::with_config(config());
+        let mut buffer = vec![0.0f32; 80];
+        buffer[0] = 1.0;
+        delay.process(&mut buffer);
+        let left: Vec<f32> = buffer.iter().step_by(2).copied().collect();
+        assert_eq!(left[0], 0.5);
+        assert_eq!([left[10], left[20], left[30]], [0.5, 0.25, 0.125]);
+        assert_eq!(left.iter().filter(|&&x| x != 0.0).count(), 4);
+        assert!(buffer.iter().skip(1).step_by(2).all(|&x| x == 0.0));
+
+        // Ping-pong echoes alternate between the channels
+        let mut ping_pong = FeedbackDelay::ping_pong(config());
+        let mut buffer = vec![0.0f32; 80];
+        buffer[0] = 1.0;
+        ping_pong.process(&mut buffer);
+        let echoes: Vec<(usize, f32)> = buffer.iter().copied().enumerate().filter(|&(i, x)| i > 1 && x != 0.0).collect();
+        assert_eq!(echoes, [(20, 0.25), (41, 0.125), (60, 0.0625)]);
+
+        // A chorus moves the copy around its centre delay, so a steady
+        // impulse train comes out with changing echo positions
+        let mut chorus = ModulatedDelay::chorus(48000, 1);
+        let mut buffer = vec![0.0f32; 48000];
+        for i in (0..buffer.len()).step_by(4800) {
+            buffer[i] = 1.0;
+        }
+        chorus.process(&mut buffer);
+        let echo_delay = |start: usize| {
+            (start + 1..start + 4800).max_by(|&a, &b| buffer[a].abs().total_cmp(&buffer[b].abs())).unwrap() - start
+        };
+        let delays: Vec<usize> = (0..10).map(|i| echo_delay(i * 4800)).collect();
+        assert!(delays.iter().all(|&d| (720..=1200).contains(&d)), "{delays:?}");
+        assert!(delays.iter().max().unwrap() - delays.iter().min().unwrap() > 300, "{delays:?}");
+
+        // The flanger's feedback stays stable on a loud input
+        let mut flanger = ModulatedDelay::flanger(48000, 2);
+        let mut buffer: Vec<f32> = (0..96000).map(|i| if (i / 2) % 100 < 50 { 1.0 } else { -1.0 }).collect();
+        flanger.process(&mut buffer);
+        assert!(buffer.iter().all(|x| x.abs() < 4.0));
+    }
+}
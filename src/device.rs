@@ -0,0 +1,44 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+        }
+    }
+}
+
+pub fn default_device(host: &cpal::Host, direction: Direction) -> Option<cpal::Device> {
+    match direction {
+        Direction::Input => host.default_input_device(),
+        Direction::Output => host.default_output_device(),
+    }
+}
+
+// The device of the host with exactly this name, if it is there right now
+pub fn named_device(host: &cpal::Host, direction: Direction, name: &str) -> anyhow::Result<Option<cpal::Device>> {
+    let mut devices = match direction {
+        Direction::Input => host.input_devices()?,
+        Direction::Output => host.output_devices()?,
+    };
+    Ok(devices.find(|device| device.name().is_ok_and(|n| n == name)))
+}
+
+// The device named `name`, or the host's default device without a name
+pub fn find_device(host: &cpal::Host, direction: Direction, name: Option<&str>) -> anyhow::Result<cpal::Device> {
+    match name {
+        Some(name) => named_device(host, direction, name)?
+            .ok_or_else(|| anyhow::anyhow!("No {direction} device named '{name}'")),
+        None => default_device(host, direction).ok_or_else(|| anyhow::anyhow!("No default {direction} device")),
+    }
+}
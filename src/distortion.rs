@@ -0,0 +1,229 @@
+use derive_builder::Builder;
+use std::f64::consts::PI;
+use std::fmt;
+
+use crate::fft::real_fft;
+
+// Bins on either side of a component's peak that belong to it, enough for
+// the main lobe of the Blackman-Harris window when nothing is zero-padded
+const LOBE_BINS: f64 = 5.0;
+// Four-term Blackman-Harris, whose side lobes are 92 dB down
+const WINDOW: [f64; 4] = [0.35875, 0.48829, 0.14128, 0.01168];
+
+#[derive(Builder)]
+pub struct DistortionConfig {
+    sample_rate: u32,
+    // Highest harmonic measured, counting the fundamental as the first
+    #[builder(default = "10")]
+    harmonics: usize,
+    // Where to look for the fundamental; the strongest component otherwise
+    #[builder(default)]
+    fundamental: Option<f64>,
+    // Band in Hz that harmonics and noise are measured in
+    #[builder(default = "20.0")]
+    low: f64,
+    #[builder(default = "20000.0")]
+    high: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Harmonic {
+    // 1 for the fundamental
+    pub order: usize,
+    pub frequency: f64,
+    pub rms: f64,
+}
+
+impl Harmonic {
+    // Level relative to another component, usually the fundamental
+    pub fn relative_db(&self, other: &Harmonic) -> f64 {
+        ratio_db(self.rms / other.rms)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DistortionReport {
+    pub fundamental: Harmonic,
+    // From the second up, as far as they fall within the band
+    pub harmonics: Vec<Harmonic>,
+    // Everything in the band besides the fundamental and its harmonics
+    pub noise_rms: f64,
+}
+
+impl DistortionReport {
+    fn harmonic_power(&self) -> f64 {
+        self.harmonics.iter().map(|harmonic| harmonic.rms * harmonic.rms).sum()
+    }
+
+    // Total harmonic distortion as a ratio to the fundamental
+    pub fn thd(&self) -> f64 {
+        self.harmonic_power().sqrt() / self.fundamental.rms
+    }
+
+    // Harmonics and noise together as a ratio to the fundamental
+    pub fn thd_n(&self) -> f64 {
+        (self.harmonic_power() + self.noise_rms * self.noise_rms).sqrt() / self.fundamental.rms
+    }
+
+    pub fn snr_db(&self) -> f64 {
+        ratio_db(self.fundamental.rms / self.noise_rms)
+    }
+
+    // Signal to noise and distortion: everything over everything but the fundamental
+    pub fn sinad_db(&self) -> f64 {
+        let thd_n = self.thd_n();
+        ratio_db((1.0 + thd_n * thd_n).sqrt() / thd_n)
+    }
+}
+
+impl fmt::Display for DistortionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} Hz at {:.2} dBFS: THD {:.4}% ({:.1} dB), THD+N {:.4}% ({:.1} dB), SNR {:.1} dB, SINAD {:.1} dB",
+            self.fundamental.frequency,
+            ratio_db(self.fundamental.rms),
+            self.thd() * 100.0,
+            ratio_db(self.thd()),
+            self.thd_n() * 100.0,
+            ratio_db(self.thd_n()),
+            self.snr_db(),
+            self.sinad_db()
+        )
+    }
+}
+
+pub fn ratio_db(ratio: f64) -> f64 {
+    20.0 * ratio.max(1e-12).log10()
+}
+
+// Measures the fundamental of a test tone, its harmonics and the noise
+// around them. The samples are Blackman-Harris windowed; every component is
+// the power of the bins under its main lobe, and the noise the power of the
+// remaining bins in the band, scaled up for the bins the components took.
+// Harmonics above the band or the Nyquist frequency are not measured.
+pub fn analyze_distortion(samples: &[f32], config: &DistortionConfig) -> Option<DistortionReport> {
+    let n = samples.len();
+    if n < 64 {
+        return None;
+    }
+    let mut energy = 0.0;
+    let windowed: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &sample)| {
+            let phase = 2.0 * PI * i as f64 / n as f64;
+            let w = WINDOW[0] - WINDOW[1] * phase.cos() + WINDOW[2] * (2.0 * phase).cos() - WINDOW[3] * (3.0 * phase).cos();
+            energy += w * w;
+            (w * sample as f64) as f32
+        })
+        .collect();
+    let size = n.next_power_of_two();
+    let bin_width = config.sample_rate as f64 / size as f64;
+    let nyquist = size / 2;
+    // One-sided bin powers scaled so that a component's bins sum to its mean square
+    let scale = 2.0 / (size as f64 * energy);
+    let power: Vec<f64> = real_fft(&windowed, size)[..=nyquist].iter().map(|value| value.norm_sqr() * scale).collect();
+
+    let lobe = (LOBE_BINS * size as f64 / n as f64).ceil() as usize;
+    let low = ((config.low / bin_width).ceil() as usize).max(lobe + 1);
+    let high = ((config.high / bin_width).floor() as usize).min(nyquist);
+    if low >= high {
+        return None;
+    }
+
+    let (search_from, search_to) = match config.fundamental {
+        Some(frequency) => {
+            let bin = (frequency / bin_width).round() as usize;
+            (bin.saturating_sub(lobe).max(low), (bin + lobe).min(high))
+        }
+        None => (low, high),
+    };
+    let peak = (search_from..=search_to).max_by(|&a, &b| power[a].total_cmp(&power[b]))?;
+    if power[peak] <= 0.0 {
+        return None;
+    }
+    // Parabolic interpolation of the log power around the peak
+    let offset = if peak > 0 && peak < nyquist {
+        let (left, centre, right) = (power[peak - 1].ln(), power[peak].ln(), power[peak + 1].ln());
+        let denominator = left - 2.0 * centre + right;
+        if denominator.abs() > f64::EPSILON { 0.5 * (left - right) / denominator } else { 0.0 }
+    } else {
+        0.0
+    };
+    let fundamental_frequency = (peak as f64 + offset.clamp(-0.5, 0.5)) * bin_width;
+
+    let mut taken = vec![false; nyquist + 1];
+    let mut component = |order: usize, frequency: f64| {
+        let centre = (frequency / bin_width).round() as usize;
+        let bins = centre.saturating_sub(lobe)..=(centre + lobe).min(nyquist);
+        let mean_square = bins.clone().map(|bin| power[bin]).sum::<f64>();
+        bins.for_each(|bin| taken[bin] = true);
+        Harmonic { order, frequency, rms: mean_square.sqrt() }
+    };
+    let fundamental = component(1, fundamental_frequency);
+    let harmonics = (2..=config.harmonics)
+        .map(|order| (order, order as f64 * fundamental_frequency))
+        .take_while(|&(_, frequency)| frequency / bin_width + lobe as f64 <= high as f64)
+        .map(|(order, frequency)| component(order, frequency))
+        .collect();
+
+    let (noise, counted) = (low..=high)
+        .filter(|&bin| !taken[bin])
+        .fold((0.0, 0), |(sum, count), bin| (sum + power[bin], count + 1));
+    let noise = if counted > 0 { noise * (high - low + 1) as f64 / counted as f64 } else { 0.0 };
+
+    Some(DistortionReport { fundamental, harmonics, noise_rms: noise.sqrt() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{Mls, Signal};
+
+    #[test]
+    fn test_distortion_measures() {
+        // 997 Hz with a 1% second and a 0.5% third harmonic over white noise
+        let sample_rate = 48000;
+        let noise = Mls::new(16).take_values(48000);
+        let tone = |frequency: f64, amplitude: f64, n: usize| {
+            amplitude * (2.0 * PI * frequency * n as f64 / sample_rate as f64).sin()
+        };
+        let samples: Vec<f32> = (0..48000)
+            .map(|n| (tone(997.0, 0.5, n) + tone(1994.0, 0.005, n) + tone(2991.0, 0.0025, n) + 1e-4 * noise[n] as f64) as f32)
+            .collect();
+        let config = DistortionConfigBuilder::default().sample_rate(sample_rate).build().unwrap();
+        let report = analyze_distortion(&samples, &config).unwrap();
+
+        assert!((report.fundamental.frequency - 997.0).abs() < 0.2, "{report:?}");
+        assert!((report.fundamental.rms - 0.5 / 2f64.sqrt()).abs() < 1e-3, "{report:?}");
+        // Up to the 10th, all well inside the band
+        assert_eq!(report.harmonics.len(), 9);
+        assert!((report.harmonics[0].relative_db(&report.fundamental) + 40.0).abs() < 0.1, "{report:?}");
+        assert!((report.harmonics[1].relative_db(&report.fundamental) + 46.02).abs() < 0.1, "{report:?}");
+        assert!(report.harmonics[2].relative_db(&report.fundamental) < -90.0, "{report:?}");
+
+        let thd = (0.005f64.powi(2) + 0.0025f64.powi(2)).sqrt() / 0.5;
+        assert!((report.thd() / thd - 1.0).abs() < 0.01, "{}", report.thd());
+        // Only the share of the noise inside 20 Hz to 20 kHz counts
+        let noise = 1e-4 * (19980.0f64 / 24000.0).sqrt();
+        assert!((report.noise_rms / noise - 1.0).abs() < 0.05, "{}", report.noise_rms);
+        assert!((report.snr_db() - ratio_db(0.5 / 2f64.sqrt() / noise)).abs() < 0.5, "{}", report.snr_db());
+        assert!(report.thd_n() > report.thd());
+        assert!((report.sinad_db() + ratio_db(report.thd_n())).abs() < 0.01);
+        assert!(report.to_string().starts_with("997.0 Hz at -9.03 dBFS: THD 1.11"), "{report}");
+
+        // A narrower band drops the harmonics above it, and a given
+        // fundamental is found even below a louder tone
+        let config = DistortionConfigBuilder::default()
+            .sample_rate(sample_rate)
+            .fundamental(Some(2991.0))
+            .high(8000.0)
+            .build()
+            .unwrap();
+        let report = analyze_distortion(&samples, &config).unwrap();
+        assert!((report.fundamental.frequency - 2991.0).abs() < 0.5);
+        assert_eq!(report.harmonics.len(), 1);
+        assert!(analyze_distortion(&samples[..10], &config).is_none());
+    }
+}
@@ -0,0 +1,112 @@
+use derive_builder::Builder;
+
+// Keeps a buffer between two independently clocked devices at a target fill
+// level by nudging the consumer's resampling ratio.
+//
+// The fill level is smoothed to hide callback-sized jumps and fed to a PI
+// controller; the integral term converges on the clock mismatch between the
+// devices and is reported as the estimated drift.
+#[derive(Builder)]
+pub struct DriftCompensatorConfig {
+    // Rate of the consumer clock, used to convert elapsed frames to seconds
+    sample_rate: u32,
+    // Buffer fill level to hold, in frames
+    target_fill: usize,
+    // ppm of correction per frame of fill error
+    #[builder(default = "2.0")]
+    proportional_gain: f64,
+    // ppm per second of correction per frame of fill error
+    #[builder(default = "0.05")]
+    integral_gain: f64,
+    // Time constant of the fill level smoothing, in seconds
+    #[builder(default = "0.5")]
+    smoothing: f64,
+    // Largest correction ever applied, in ppm
+    #[builder(default = "1000.0")]
+    max_ppm: f64,
+}
+
+pub struct DriftCompensator {
+    config: DriftCompensatorConfig,
+    smoothed_fill: Option<f64>,
+    integral: f64,
+    correction: f64,
+}
+
+impl DriftCompensator {
+    pub fn with_config(config: DriftCompensatorConfig) -> Self {
+        Self {
+            config,
+            smoothed_fill: None,
+            integral: 0.0,
+            correction: 0.0,
+        }
+    }
+
+    // Feeds the current fill level after `elapsed_frames` consumer frames and
+    // returns the factor to apply to the nominal resampling ratio
+    pub fn update(&mut self, fill: usize, elapsed_frames: usize) -> f64 {
+        let dt = elapsed_frames as f64 / self.config.sample_rate as f64;
+        let fill = fill as f64;
+        let smoothed = match self.smoothed_fill {
+            Some(previous) => {
+                let alpha = 1.0 - (-dt / self.config.smoothing).exp();
+                previous + alpha * (fill - previous)
+            }
+            None => fill,
+        };
+        self.smoothed_fill = Some(smoothed);
+
+        let error = smoothed - self.config.target_fill as f64;
+        let max_ppm = self.config.max_ppm;
+        self.integral = (self.integral + self.config.integral_gain * error * dt).clamp(-max_ppm, max_ppm);
+        self.correction = (self.config.proportional_gain * error + self.integral).clamp(-max_ppm, max_ppm);
+
+        self.factor()
+    }
+
+    pub fn factor(&self) -> f64 {
+        1.0 + self.correction * 1e-6
+    }
+
+    // Estimated rate of the producer relative to the consumer, in ppm
+    pub fn drift_ppm(&self) -> f64 {
+        self.integral
+    }
+
+    pub fn smoothed_fill(&self) -> Option<f64> {
+        self.smoothed_fill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_converges_on_drift() {
+        let sample_rate = 48000;
+        let block = 480;
+        let drift_ppm = 150.0;
+        let mut compensator = DriftCompensator::with_config(
+            DriftCompensatorConfigBuilder::default()
+                .sample_rate(sample_rate)
+                .target_fill(960)
+                .build()
+                .expect("Failed to build DriftCompensatorConfig"),
+        );
+
+        // The producer runs fast; the consumer pulls `block` frames scaled by
+        // the correction factor, as a resampler would
+        let mut fill = 960.0;
+        let mut factor = 1.0;
+        for _ in 0..(sample_rate as usize / block) * 600 {
+            fill += block as f64 * (1.0 + drift_ppm * 1e-6);
+            fill -= block as f64 * factor;
+            factor = compensator.update(fill.round() as usize, block);
+        }
+
+        assert!((compensator.drift_ppm() - drift_ppm).abs() < 5.0, "{}", compensator.drift_ppm());
+        assert!((fill - 960.0).abs() < 5.0, "{fill}");
+    }
+}
@@ -0,0 +1,474 @@
+use derive_builder::Builder;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::processor::Processor;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DynamicsMode {
+    // Turns levels above the threshold down by the ratio
+    Compressor,
+    // Turns levels below the threshold further down by the ratio
+    Expander,
+    // Turns everything below the threshold down to the range
+    Gate,
+}
+
+#[derive(Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct DynamicsConfig {
+    mode: DynamicsMode,
+    sample_rate: u32,
+    channels: usize,
+    #[builder(default = "-20.0")]
+    threshold_db: f32,
+    // n:1 for the compressor, 1:n for the expander, unused by the gate
+    #[builder(default = "4.0")]
+    ratio: f32,
+    // Width in dB of the soft knee around the threshold; 0 is a hard knee
+    #[builder(default = "0.0")]
+    knee_db: f32,
+    // How fast the gain reduction builds up for the compressor, and how fast
+    // the expander and gate open
+    #[builder(default = "Duration::from_millis(10)")]
+    attack: Duration,
+    #[builder(default = "Duration::from_millis(100)")]
+    release: Duration,
+    // How long the gate and expander stay open after the level falls below
+    // the threshold, so they do not chatter on decaying sounds
+    #[builder(default = "Duration::ZERO")]
+    hold: Duration,
+    // Most gain reduction the expander and gate apply, in dB
+    #[builder(default = "-80.0")]
+    range_db: f32,
+    #[builder(default = "0.0")]
+    makeup_db: f32,
+    // One gain for all channels, driven by the loudest, so the stereo image
+    // does not shift; otherwise every channel is processed on its own
+    #[builder(default = "true")]
+    linked: bool,
+    // Channels of the sidechain passed to `process_sidechain`
+    #[builder(default = "self.channels.unwrap_or(1)")]
+    sidechain_channels: usize,
+}
+
+impl DynamicsConfigBuilder {
+    // An infinite ratio is allowed, as a brickwall
+    fn validate(&self) -> Result<(), String> {
+        if let Some(ratio) = self.ratio
+            && (ratio.is_nan() || ratio <= 0.0)
+        {
+            return Err(format!("The ratio must be positive, got {ratio}"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Detector {
+    envelope: f32,
+    // Current gain change in dB, 0 or below before makeup
+    gain_db: f32,
+    hold_frames: usize,
+}
+
+// Compressor, expander or gate on interleaved blocks. The level is the peak
+// of each frame with instant attack and release-rate decay; the gain
+// computed from it is smoothed with the attack and release times.
+pub struct Dynamics {
+    config: DynamicsConfig,
+    detectors: Vec<Detector>,
+    attack: f32,
+    release: f32,
+    hold_frames: usize,
+}
+
+fn coefficient(time: Duration, sample_rate: u32) -> f32 {
+    let frames = time.as_secs_f64() * sample_rate as f64;
+    if frames > 0.0 { (-1.0 / frames).exp() as f32 } else { 0.0 }
+}
+
+fn to_db(value: f32) -> f32 {
+    20.0 * (value + 1e-10).log10()
+}
+
+fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+impl Dynamics {
+    pub fn with_config(config: DynamicsConfig) -> Self {
+        let detectors = if config.linked { 1 } else { config.channels.max(1) };
+        Self {
+            detectors: vec![Detector::default(); detectors],
+            attack: coefficient(config.attack, config.sample_rate),
+            release: coefficient(config.release, config.sample_rate),
+            hold_frames: (config.hold.as_secs_f64() * config.sample_rate as f64) as usize,
+            config,
+        }
+    }
+
+    // Gain change in dB that the static curve applies at `level_db`
+    pub fn static_gain_db(&self, level_db: f32) -> f32 {
+        let DynamicsConfig { threshold_db: threshold, ratio, knee_db: knee, .. } = self.config;
+        let over = level_db - threshold;
+        match self.config.mode {
+            DynamicsMode::Compressor => {
+                if 2.0 * over < -knee {
+                    0.0
+                } else if knee > 0.0 && 2.0 * over <= knee {
+                    (1.0 / ratio - 1.0) * (over + knee / 2.0).powi(2) / (2.0 * knee)
+                } else {
+                    over / ratio - over
+                }
+            }
+            DynamicsMode::Expander => {
+                let gain = if 2.0 * over > knee {
+                    0.0
+                } else if knee > 0.0 && 2.0 * over >= -knee {
+                    (1.0 - ratio) * (over - knee / 2.0).powi(2) / (2.0 * knee)
+                } else {
+                    over * ratio - over
+                };
+                gain.max(self.config.range_db)
+            }
+            DynamicsMode::Gate => {
+                if over >= 0.0 { 0.0 } else { self.config.range_db }
+            }
+        }
+    }
+
+    // The largest gain reduction being applied right now, in dB
+    pub fn gain_reduction_db(&self) -> f32 {
+        self.detectors.iter().map(|detector| -detector.gain_db).fold(0.0, f32::max)
+    }
+
+    // Processes `buffer` with its level taken from `sidechain`, which holds
+    // the same number of frames, e.g. to duck music under a voice
+    pub fn process_sidechain(&mut self, buffer: &mut [f32], sidechain: &[f32]) {
+        let channels = self.config.channels.max(1);
+        let sidechain_channels = self.config.sidechain_channels.max(1);
+        for (frame, key) in buffer.chunks_mut(channels).zip(sidechain.chunks(sidechain_channels)) {
+            self.detect(key);
+            self.apply(frame);
+        }
+    }
+
+    // Moves the gains on by one frame of the detection signal
+    fn detect(&mut self, key: &[f32]) {
+        let linked = self.detectors.len() == 1;
+        for index in 0..self.detectors.len() {
+            let level = if linked {
+                key.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+            } else {
+                key.get(index % key.len().max(1)).map_or(0.0, |sample| sample.abs())
+            };
+            let target = {
+                let detector = &mut self.detectors[index];
+                detector.envelope = level.max(detector.envelope * self.release);
+                detector.envelope
+            };
+            let mut target_db = self.static_gain_db(to_db(target));
+
+            let detector = &mut self.detectors[index];
+            if self.config.mode != DynamicsMode::Compressor {
+                // Open means no reduction; the hold keeps it that way
+                if target_db >= 0.0 {
+                    detector.hold_frames = self.hold_frames;
+                } else if detector.hold_frames > 0 {
+                    detector.hold_frames -= 1;
+                    target_db = 0.0;
+                }
+            }
+            let attacking = match self.config.mode {
+                DynamicsMode::Compressor => target_db < detector.gain_db,
+                DynamicsMode::Expander | DynamicsMode::Gate => target_db > detector.gain_db,
+            };
+            let coefficient = if attacking { self.attack } else { self.release };
+            detector.gain_db = target_db + (detector.gain_db - target_db) * coefficient;
+        }
+    }
+
+    fn apply(&self, frame: &mut [f32]) {
+        let makeup = self.config.makeup_db;
+        if let [detector] = self.detectors[..] {
+            let gain = from_db(detector.gain_db + makeup);
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        } else {
+            for (sample, detector) in frame.iter_mut().zip(&self.detectors) {
+                *sample *= from_db(detector.gain_db + makeup);
+            }
+        }
+    }
+}
+
+impl Processor for Dynamics {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let channels = self.config.channels.max(1);
+        for frame in buffer.chunks_mut(channels) {
+            self.detect(frame);
+            self.apply(frame);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.detectors.fill(Detector::default());
+    }
+}
+
+#[derive(Builder)]
+pub struct LimiterConfig {
+    sample_rate: u32,
+    channels: usize,
+    // No sample leaves the limiter above this level
+    #[builder(default = "-1.0")]
+    ceiling_db: f32,
+    // How far ahead peaks are seen, which is also the added latency
+    #[builder(default = "Duration::from_millis(5)")]
+    lookahead: Duration,
+    #[builder(default = "Duration::from_millis(50)")]
+    release: Duration,
+}
+
+// Brickwall limiter with look-ahead. The audio is delayed by the look-ahead
+// time while the gain needed to keep every frame under the ceiling is held
+// at its minimum over that time and averaged over it, so the gain is down
+// in time for each peak without stepping.
+pub struct Limiter {
+    ceiling: f32,
+    release: f32,
+    channels: usize,
+    lookahead: usize,
+    // Interleaved delay line of `lookahead` frames
+    delay: Vec<f32>,
+    position: usize,
+    // Released gain per frame over the look-ahead window, kept as a
+    // monotonic queue of (frame, gain) for the running minimum
+    minimum: VecDeque<(u64, f32)>,
+    // Running average of the held minimum over `lookahead` frames
+    average: Vec<f32>,
+    sum: f64,
+    released: f32,
+    frame: u64,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn with_config(config: LimiterConfig) -> Self {
+        let channels = config.channels.max(1);
+        let lookahead = ((config.lookahead.as_secs_f64() * config.sample_rate as f64) as usize).max(1);
+        Self {
+            ceiling: from_db(config.ceiling_db),
+            release: coefficient(config.release, config.sample_rate),
+            channels,
+            lookahead,
+            delay: vec![0.0; lookahead * channels],
+            position: 0,
+            minimum: VecDeque::with_capacity(lookahead + 1),
+            average: vec![1.0; lookahead],
+            sum: lookahead as f64,
+            released: 1.0,
+            frame: 0,
+            gain: 1.0,
+        }
+    }
+
+    pub fn latency_frames(&self) -> usize {
+        self.lookahead
+    }
+
+    // Gain reduction applied to the frame that last left the limiter, in dB
+    pub fn gain_reduction_db(&self) -> f32 {
+        -to_db(self.gain)
+    }
+}
+
+impl Processor for Limiter {
+    fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+            // Instant attack, exponential release back towards unity
+            self.released = required.min(1.0 - (1.0 - self.released) * self.release);
+
+            // Evicted before pushing, so that the queue never outgrows the
+            // window and reallocates
+            while self.minimum.front().is_some_and(|&(frame, _)| frame + (self.lookahead as u64) < self.frame) {
+                self.minimum.pop_front();
+            }
+            while self.minimum.back().is_some_and(|&(_, gain)| gain >= self.released) {
+                self.minimum.pop_back();
+            }
+            self.minimum.push_back((self.frame, self.released));
+            let held = self.minimum.front().map_or(1.0, |&(_, gain)| gain);
+
+            let slot = self.position / self.channels;
+            self.sum += (held - self.average[slot]) as f64;
+            self.average[slot] = held;
+            self.gain = (self.sum / self.lookahead as f64) as f32;
+
+            let delayed = &mut self.delay[self.position..self.position + self.channels];
+            for (sample, stored) in frame.iter_mut().zip(delayed.iter_mut()) {
+                let output = *stored * self.gain;
+                *stored = *sample;
+                // Rounding in the running average must not let anything through
+                *sample = output.clamp(-self.ceiling, self.ceiling);
+            }
+            self.position = (self.position + self.channels) % self.delay.len();
+            self.frame += 1;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay.fill(0.0);
+        self.minimum.clear();
+        self.average.fill(1.0);
+        self.sum = self.lookahead as f64;
+        self.released = 1.0;
+        self.gain = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TonePlayer, TonePlayerConfigBuilder};
+
+    fn tone(frequency: f32, factor: f32, frames: usize) -> Vec<f32> {
+        let mut player = TonePlayer::with_config(
+            TonePlayerConfigBuilder::default()
+                .frequency(frequency)
+                .sample_rate(48000)
+                .channels(2)
+                .factor(factor)
+                .build()
+                .unwrap(),
+        );
+        let mut buffer = vec![0.0f32; frames * 2];
+        player.fill_buffer(&mut buffer);
+        buffer
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    fn dynamics(builder: &mut DynamicsConfigBuilder) -> Dynamics {
+        Dynamics::with_config(builder.sample_rate(48000).channels(2).build().unwrap())
+    }
+
+    #[test]
+    fn test_dynamics() {
+        // Static curves: 4:1 above -20 dB, with a 10 dB soft knee halfway
+        // between at the threshold, and 1:2 below -40 dB down to the range
+        let compressor = dynamics(DynamicsConfigBuilder::default().mode(DynamicsMode::Compressor).knee_db(10.0));
+        assert_eq!(compressor.static_gain_db(-30.0), 0.0);
+        assert!((compressor.static_gain_db(0.0) + 15.0).abs() < 1e-4);
+        assert!((compressor.static_gain_db(-20.0) + 0.9375).abs() < 1e-4);
+        let expander = dynamics(
+            DynamicsConfigBuilder::default().mode(DynamicsMode::Expander).threshold_db(-40.0).ratio(2.0).range_db(-30.0),
+        );
+        assert_eq!(expander.static_gain_db(-30.0), 0.0);
+        assert!((expander.static_gain_db(-50.0) + 10.0).abs() < 1e-4);
+        assert_eq!(expander.static_gain_db(-90.0), -30.0);
+
+        // A full scale tone settles 15 dB down, the same on both channels
+        let mut compressor = dynamics(
+            DynamicsConfigBuilder::default().mode(DynamicsMode::Compressor).attack(Duration::from_millis(1)),
+        );
+        let mut buffer = tone(1000.0, 1.0, 24000);
+        for block in buffer.chunks_mut(512) {
+            compressor.process(block);
+        }
+        let settled = &buffer[40000..];
+        assert!((to_db(peak(settled)) + 15.0).abs() < 0.3, "{} dB", to_db(peak(settled)));
+        assert!(settled.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!((compressor.gain_reduction_db() - 15.0).abs() < 0.3);
+
+        // The gate shuts room noise at -50 dB but lets a -10 dB voice through
+        let mut gate = dynamics(
+            DynamicsConfigBuilder::default()
+                .mode(DynamicsMode::Gate)
+                .threshold_db(-40.0)
+                .attack(Duration::from_millis(1))
+                .hold(Duration::from_millis(20)),
+        );
+        let mut noise = tone(200.0, from_db(-50.0), 24000);
+        gate.process(&mut noise);
+        assert!(peak(&noise[47000..]) < from_db(-125.0));
+        let mut voice = tone(200.0, from_db(-10.0), 4800);
+        gate.process(&mut voice);
+        assert!((to_db(peak(&voice[4000..])) + 10.0).abs() < 0.1);
+
+        // Unlinked channels get their own gain; a sidechain ducks one signal
+        // by the level of another
+        let mut unlinked = dynamics(DynamicsConfigBuilder::default().mode(DynamicsMode::Compressor).linked(false));
+        let mut buffer: Vec<f32> = tone(1000.0, 1.0, 24000).chunks(2).flat_map(|f| [f[0], f[1] * 0.01]).collect();
+        unlinked.process(&mut buffer);
+        let right: Vec<f32> = buffer[40000..].iter().skip(1).step_by(2).copied().collect();
+        assert!((to_db(peak(&right)) + 40.0).abs() < 0.1);
+        let mut ducker = dynamics(
+            DynamicsConfigBuilder::default().mode(DynamicsMode::Compressor).ratio(f32::INFINITY).sidechain_channels(1),
+        );
+        let mut music = tone(500.0, 0.5, 24000);
+        let voice: Vec<f32> = tone(1000.0, 1.0, 24000).into_iter().step_by(2).collect();
+        ducker.process_sidechain(&mut music, &voice);
+        assert!((to_db(peak(&music[40000..])) + 26.0).abs() < 0.3);
+
+        // The limiter holds a +6 dB burst under its ceiling, with the audio
+        // delayed by the look-ahead
+        let mut limiter = Limiter::with_config(LimiterConfigBuilder::default().sample_rate(48000).channels(2).build().unwrap());
+        assert_eq!(limiter.latency_frames(), 240);
+        let mut impulse = vec![0.0f32; 1000];
+        impulse[0] = 0.5;
+        limiter.process(&mut impulse);
+        assert_eq!(impulse[480], 0.5);
+        let mut burst = tone(3000.0, 2.0, 4800);
+        burst.extend(tone(3000.0, 0.5, 24000));
+        let original = burst.clone();
+        for block in burst.chunks_mut(100) {
+            limiter.process(block);
+        }
+        assert!(peak(&burst) <= from_db(-1.0));
+        assert!((to_db(peak(&burst[4800..9600])) + 1.0).abs() < 0.1);
+        // Once released, quieter audio passes unchanged
+        assert!(burst[57000..].iter().zip(&original[56520..]).all(|(out, x)| (out - x).abs() < 1e-4));
+    }
+
+    #[test]
+    fn test_hard_knee_at_threshold() {
+        // A full scale sample sits exactly on a 0 dB threshold
+        for mode in [DynamicsMode::Compressor, DynamicsMode::Expander] {
+            let mut processor = dynamics(DynamicsConfigBuilder::default().mode(mode).threshold_db(0.0));
+            assert_eq!(processor.static_gain_db(0.0), 0.0);
+            let mut buffer = vec![1.0f32; 200];
+            processor.process(&mut buffer);
+            assert!(buffer.iter().all(|sample| sample.is_finite()), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn test_ratio_must_be_positive() {
+        for ratio in [0.0, -2.0, f32::NAN] {
+            let config = DynamicsConfigBuilder::default()
+                .mode(DynamicsMode::Compressor)
+                .sample_rate(48000)
+                .channels(2)
+                .ratio(ratio)
+                .build();
+            assert!(config.is_err(), "{ratio}");
+        }
+    }
+
+    #[test]
+    fn test_limiter_queue_fits_its_window() {
+        let mut limiter = Limiter::with_config(LimiterConfigBuilder::default().sample_rate(48000).channels(1).build().unwrap());
+        let capacity = limiter.minimum.capacity();
+        // A loud burst, then the gain rising steadily as it is released,
+        // which keeps every frame of the window in the queue
+        let mut buffer = vec![1.0f32; 100];
+        buffer.extend(std::iter::repeat_n(0.0, 48000));
+        limiter.process(&mut buffer);
+        assert_eq!(limiter.minimum.capacity(), capacity);
+    }
+}
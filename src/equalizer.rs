@@ -0,0 +1,459 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::mpsc;
+
+use crate::biquad::{Biquad, BiquadConfigBuilder, FilterType};
+use crate::fft::Complex;
+use crate::processor::Processor;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+impl FromStr for BandKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "peaking" | "peak" | "bell" => Ok(BandKind::Peaking),
+            "lowshelf" | "ls" => Ok(BandKind::LowShelf),
+            "highshelf" | "hs" => Ok(BandKind::HighShelf),
+            "lowpass" | "lp" => Ok(BandKind::LowPass),
+            "highpass" | "hp" => Ok(BandKind::HighPass),
+            "bandpass" | "bp" => Ok(BandKind::BandPass),
+            "notch" => Ok(BandKind::Notch),
+            _ => anyhow::bail!("Unknown band type '{s}'"),
+        }
+    }
+}
+
+// One band of a parametric equalizer. The gain is in dB and ignored by the
+// pass and notch types.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "BandFields")]
+pub struct Band {
+    pub kind: BandKind,
+    pub frequency: f64,
+    pub gain_db: f64,
+    pub q: f64,
+}
+
+// A band as written in a config file, checked on its way to a `Band`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BandFields {
+    #[serde(rename = "type")]
+    kind: BandKind,
+    frequency: f64,
+    #[serde(default, rename = "gain")]
+    gain_db: f64,
+    #[serde(default = "default_q")]
+    q: f64,
+}
+
+impl TryFrom<BandFields> for Band {
+    type Error = anyhow::Error;
+
+    fn try_from(fields: BandFields) -> anyhow::Result<Self> {
+        let band = Self { kind: fields.kind, frequency: fields.frequency, gain_db: fields.gain_db, q: fields.q };
+        band.check()?;
+        Ok(band)
+    }
+}
+
+fn default_q() -> f64 {
+    std::f64::consts::FRAC_1_SQRT_2
+}
+
+impl Band {
+    pub fn peaking(frequency: f64, gain_db: f64, q: f64) -> Self {
+        Self { kind: BandKind::Peaking, frequency, gain_db, q }
+    }
+
+    // Whether the settings make a filter at all; the upper frequency limit
+    // depends on the sample rate and is left to the equalizer
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.frequency > 0.0 && self.frequency.is_finite(),
+            "The frequency of a band must be positive, got {}",
+            self.frequency
+        );
+        anyhow::ensure!(self.q > 0.0 && self.q.is_finite(), "The Q of a band must be positive, got {}", self.q);
+        anyhow::ensure!(self.gain_db.is_finite(), "The gain of a band must be finite, got {}", self.gain_db);
+        Ok(())
+    }
+
+    fn filter_type(&self) -> FilterType {
+        match self.kind {
+            BandKind::Peaking => FilterType::Peaking { gain_db: self.gain_db },
+            BandKind::LowShelf => FilterType::LowShelf { gain_db: self.gain_db },
+            BandKind::HighShelf => FilterType::HighShelf { gain_db: self.gain_db },
+            BandKind::LowPass => FilterType::LowPass,
+            BandKind::HighPass => FilterType::HighPass,
+            BandKind::BandPass => FilterType::BandPass,
+            BandKind::Notch => FilterType::Notch,
+        }
+    }
+}
+
+// Parses `TYPE:FREQ[:GAIN[:Q]]` with an optional dB suffix on the gain,
+// e.g. `peak:1000:-6dB:2`, `lowshelf:120:+3` or `hp:80`
+impl FromStr for Band {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':').map(str::trim);
+        let kind = parts.next().unwrap_or_default().parse()?;
+        let frequency = parts
+            .next()
+            .with_context(|| format!("Band '{s}' should look like TYPE:FREQ[:GAIN[:Q]]"))?
+            .parse()
+            .with_context(|| format!("Bad frequency in band '{s}'"))?;
+        let gain_db = match parts.next() {
+            Some(gain) => {
+                let gain = gain.strip_suffix("dB").or_else(|| gain.strip_suffix("db")).unwrap_or(gain);
+                gain.trim().parse().with_context(|| format!("Bad gain in band '{s}'"))?
+            }
+            None => 0.0,
+        };
+        let q = match parts.next() {
+            Some(q) => q.parse().with_context(|| format!("Bad Q in band '{s}'"))?,
+            None => default_q(),
+        };
+        if parts.next().is_some() {
+            anyhow::bail!("Band '{s}' has too many fields");
+        }
+        let band = Self { kind, frequency, gain_db, q };
+        band.check().with_context(|| format!("Bad band '{s}'"))?;
+        Ok(band)
+    }
+}
+
+// Fixed centre frequencies of a graphic equalizer, on the ISO octave or
+// third-octave grid around 1 kHz
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphicLayout {
+    // 10 bands from 31 Hz to 16 kHz
+    Octave,
+    // 31 bands from 20 Hz to 20 kHz
+    ThirdOctave,
+}
+
+impl GraphicLayout {
+    pub fn for_band_count(count: usize) -> anyhow::Result<Self> {
+        match count {
+            10 => Ok(GraphicLayout::Octave),
+            31 => Ok(GraphicLayout::ThirdOctave),
+            _ => anyhow::bail!("A graphic equalizer has 10 or 31 bands, got {count}"),
+        }
+    }
+
+    pub fn frequencies(self) -> Vec<f64> {
+        match self {
+            GraphicLayout::Octave => (-5..=4).map(|k| 1000.0 * 2f64.powi(k)).collect(),
+            GraphicLayout::ThirdOctave => (-17..=13).map(|k| 1000.0 * 2f64.powf(k as f64 / 3.0)).collect(),
+        }
+    }
+
+    // Peaking bands whose bandwidth is the spacing between centres
+    pub fn bands(self, gains_db: &[f64]) -> anyhow::Result<Vec<Band>> {
+        let frequencies = self.frequencies();
+        if gains_db.len() != frequencies.len() {
+            anyhow::bail!("Expected {} graphic equalizer gains, got {}", frequencies.len(), gains_db.len());
+        }
+        let octaves: f64 = match self {
+            GraphicLayout::Octave => 1.0,
+            GraphicLayout::ThirdOctave => 1.0 / 3.0,
+        };
+        let q = 2f64.powf(octaves).sqrt() / (2f64.powf(octaves) - 1.0);
+        Ok(frequencies
+            .into_iter()
+            .zip(gains_db)
+            .map(|(frequency, &gain_db)| Band::peaking(frequency, gain_db, q))
+            .collect())
+    }
+}
+
+// Bands in series on interleaved audio. Changing a band glides to the new
+// setting through the biquad coefficient smoothing.
+pub struct Equalizer {
+    bands: Vec<Band>,
+    filters: Vec<Biquad>,
+    sample_rate: u32,
+    channels: usize,
+}
+
+impl Equalizer {
+    pub fn new(bands: &[Band], sample_rate: u32, channels: usize) -> anyhow::Result<Self> {
+        let mut equalizer = Self {
+            bands: Vec::new(),
+            filters: Vec::new(),
+            sample_rate,
+            channels,
+        };
+        equalizer.set_bands(bands)?;
+        Ok(equalizer)
+    }
+
+    pub fn graphic(layout: GraphicLayout, gains_db: &[f64], sample_rate: u32, channels: usize) -> anyhow::Result<Self> {
+        Self::new(&layout.bands(gains_db)?, sample_rate, channels)
+    }
+
+    pub fn bands(&self) -> &[Band] {
+        &self.bands
+    }
+
+    pub fn set_band(&mut self, index: usize, band: Band) {
+        let filter = &mut self.filters[index];
+        filter.set_filter_type(band.filter_type());
+        filter.set_frequency(band.frequency);
+        filter.set_q(band.q);
+        self.bands[index] = band;
+    }
+
+    pub fn set_gain(&mut self, index: usize, gain_db: f64) {
+        self.set_band(index, Band { gain_db, ..self.bands[index] });
+    }
+
+    // Bands are updated in place when the count stays the same, so that
+    // their audio state carries over; otherwise the filters are rebuilt, which
+    // allocates, so use `replace` on the audio thread
+    pub fn set_bands(&mut self, bands: &[Band]) -> anyhow::Result<()> {
+        for band in bands {
+            band.check()?;
+            anyhow::ensure!(
+                band.frequency < self.sample_rate as f64 / 2.0,
+                "A band at {} Hz lies above half the sample rate ({} Hz)",
+                band.frequency,
+                self.sample_rate / 2
+            );
+        }
+        if bands.len() == self.filters.len() {
+            for (index, &band) in bands.iter().enumerate() {
+                self.set_band(index, band);
+            }
+            return Ok(());
+        }
+        self.filters = bands
+            .iter()
+            .map(|band| {
+                let config = BiquadConfigBuilder::default()
+                    .filter_type(band.filter_type())
+                    .frequency(band.frequency)
+                    .q(band.q)
+                    .sample_rate(self.sample_rate)
+                    .channels(self.channels)
+                    .build()?;
+                Ok(Biquad::with_config(config))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        self.bands = bands.to_vec();
+        Ok(())
+    }
+
+    // Takes over the bands of `other`, an equalizer for the same rate and
+    // channels built off the audio thread. With the same number of bands they
+    // are updated in place like `set_bands` does; otherwise `other` takes this
+    // one's place. Returns whichever is left over, so that it can be dropped
+    // where freeing memory is allowed.
+    pub fn replace(&mut self, other: Equalizer) -> Equalizer {
+        if other.bands.len() != self.bands.len() {
+            return std::mem::replace(self, other);
+        }
+        for (index, &band) in other.bands.iter().enumerate() {
+            self.set_band(index, band);
+        }
+        other
+    }
+
+    // Overall gain in dB at `frequency` of the current settings
+    pub fn response_db(&self, frequency: f64) -> f64 {
+        let response = self
+            .filters
+            .iter()
+            .fold(Complex::new(1.0, 0.0), |response, filter| response * filter.frequency_response(frequency));
+        20.0 * (response.norm() + 1e-12).log10()
+    }
+}
+
+impl Processor for Equalizer {
+    fn process(&mut self, buffer: &mut [f32]) {
+        for filter in &mut self.filters {
+            filter.process(buffer);
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.reset();
+        }
+    }
+}
+
+// An equalizer on an audio thread whose updates are built elsewhere. It
+// hands back the equalizers it no longer needs, so that it never allocates
+// or frees.
+pub struct LiveEqualizer {
+    equalizer: Equalizer,
+    updates: mpsc::Receiver<Equalizer>,
+    retired: mpsc::SyncSender<Equalizer>,
+}
+
+impl Processor for LiveEqualizer {
+    fn process(&mut self, buffer: &mut [f32]) {
+        if let Ok(update) = self.updates.try_recv() {
+            let _ = self.retired.try_send(self.equalizer.replace(update));
+        }
+        self.equalizer.process(buffer);
+    }
+
+    fn reset(&mut self) {
+        self.equalizer.reset();
+    }
+}
+
+// The control end of a `LiveEqualizer`
+pub struct EqualizerControl {
+    updates: mpsc::SyncSender<Equalizer>,
+    retired: mpsc::Receiver<Equalizer>,
+    pending: Option<Equalizer>,
+}
+
+impl EqualizerControl {
+    // Hands `equalizer` over at the next flush, in place of any update still
+    // pending
+    pub fn replace(&mut self, equalizer: Equalizer) {
+        self.pending = Some(equalizer);
+    }
+
+    // Drops the equalizers the audio thread handed back and offers it the
+    // pending update. One it hasn't taken the previous update yet waits for
+    // the next flush.
+    pub fn flush(&mut self) {
+        self.retired.try_iter().for_each(drop);
+        if let Some(update) = self.pending.take()
+            && let Err(mpsc::TrySendError::Full(update)) = self.updates.try_send(update)
+        {
+            self.pending = Some(update);
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+// Splits `equalizer` into the processor for the audio thread and its control.
+// Flushes empty the retired equalizers before every update, so two slots are
+// enough for the update taken before that and this one.
+pub fn live_equalizer(equalizer: Equalizer) -> (LiveEqualizer, EqualizerControl) {
+    let (updates, receiver) = mpsc::sync_channel(1);
+    let (retired, retired_receiver) = mpsc::sync_channel(2);
+    (
+        LiveEqualizer { equalizer, updates: receiver, retired },
+        EqualizerControl { updates, retired: retired_receiver, pending: None },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::Window;
+    use crate::{TonePlayer, TonePlayerConfigBuilder};
+    use std::time::Duration;
+
+    #[test]
+    fn test_equalizer() {
+        assert_eq!("peak:1000:-6dB:2".parse::<Band>().unwrap(), Band::peaking(1000.0, -6.0, 2.0));
+        let band: Band = "hp:80".parse().unwrap();
+        assert_eq!((band.kind, band.frequency, band.gain_db), (BandKind::HighPass, 80.0, 0.0));
+        assert!("wobble:80".parse::<Band>().is_err());
+        assert!("peak:1000:3:1:2".parse::<Band>().is_err());
+        assert!("peak:1000:-6dB:0".parse::<Band>().is_err());
+        assert!("peak:-1000".parse::<Band>().is_err());
+        assert!("lp:NaN".parse::<Band>().is_err());
+        assert!(toml::from_str::<Band>("type = \"peak\"\nfrequency = 1000\nq = 0").is_err());
+        let band: Band = toml::from_str("type = \"lowshelf\"\nfrequency = 120\ngain = 3").unwrap();
+        assert_eq!((band.kind, band.gain_db), (BandKind::LowShelf, 3.0));
+
+        // Bands in series add up in dB
+        let mut equalizer = Equalizer::new(
+            &[Band::peaking(1000.0, 6.0, 1.0), "lowshelf:100:-10:0.7".parse().unwrap()],
+            48000,
+            2,
+        )
+        .unwrap();
+        assert!((equalizer.response_db(1000.0) - 6.0).abs() < 0.1);
+        assert!((equalizer.response_db(20.0) + 10.0).abs() < 0.2);
+        assert!(equalizer.response_db(10000.0).abs() < 0.2);
+
+        // The processor agrees with the calculated response
+        let mut player = TonePlayer::with_config(
+            TonePlayerConfigBuilder::default().frequency(1000.0).sample_rate(48000).channels(2).build().unwrap(),
+        );
+        let mut buffer = vec![0.0f32; 48000];
+        for block in buffer.chunks_mut(480) {
+            player.fill_buffer(block);
+            equalizer.process(block);
+        }
+        let mut window = Window::with_duration(Duration::from_millis(100), 48000);
+        window.add_samples(&buffer.iter().step_by(2).copied().collect::<Vec<_>>());
+        let measured = 20.0 * window.calculate_amplitude(1000.0).unwrap().log10();
+        assert!((measured - 6.0).abs() < 0.3, "{measured} dB");
+
+        equalizer.set_gain(0, -3.0);
+        assert!((equalizer.response_db(1000.0) + 3.0).abs() < 0.1);
+
+        // Replacing keeps the filters for the same band count and swaps
+        // the whole equalizer otherwise
+        let retired = equalizer.replace(Equalizer::new(&[Band::peaking(1000.0, 2.0, 1.0), "lowshelf:100:-10:0.7".parse().unwrap()], 48000, 2).unwrap());
+        assert_eq!(retired.bands().len(), 2);
+        assert!((equalizer.response_db(1000.0) - 2.0).abs() < 0.1);
+        let retired = equalizer.replace(Equalizer::new(&[], 48000, 2).unwrap());
+        assert_eq!(retired.bands().len(), 2);
+        assert!(equalizer.bands().is_empty());
+        // Nothing at or above half the sample rate
+        assert!(Equalizer::new(&["hp:24000".parse().unwrap()], 48000, 2).is_err());
+        assert!(equalizer.set_bands(&["hp:30000".parse().unwrap()]).is_err());
+        assert!(equalizer.bands().is_empty());
+
+        // A live equalizer takes one update per block and hands back the
+        // one it replaced
+        let (mut live, mut control) = live_equalizer(equalizer);
+        control.replace(Equalizer::new(&[Band::peaking(1000.0, 6.0, 1.0)], 48000, 2).unwrap());
+        control.flush();
+        control.replace(Equalizer::new(&[], 48000, 2).unwrap());
+        control.flush();
+        assert!(control.is_pending());
+        live.process(&mut [0.0; 2]);
+        assert!((live.equalizer.response_db(1000.0) - 6.0).abs() < 0.1);
+        control.flush();
+        assert!(!control.is_pending());
+        live.process(&mut [0.0; 2]);
+        assert!(live.equalizer.bands().is_empty());
+
+        // Graphic bands sit at their centres and barely touch their
+        // neighbours' centres
+        assert_eq!(GraphicLayout::Octave.frequencies().len(), 10);
+        let frequencies = GraphicLayout::ThirdOctave.frequencies();
+        assert_eq!(frequencies.len(), 31);
+        assert!((frequencies[0] - 19.7).abs() < 0.1 && (frequencies[30] - 20159.0).abs() < 1.0);
+        let mut gains = [0.0; 31];
+        gains[17] = 12.0;
+        let graphic = Equalizer::graphic(GraphicLayout::ThirdOctave, &gains, 48000, 1).unwrap();
+        assert!((graphic.response_db(1000.0) - 12.0).abs() < 0.01);
+        assert!(graphic.response_db(frequencies[16]) < 6.0);
+        assert!(graphic.response_db(frequencies[14]) < 1.0);
+        assert!(Equalizer::graphic(GraphicLayout::Octave, &gains, 48000, 1).is_err());
+        assert_eq!(GraphicLayout::for_band_count(31).unwrap(), GraphicLayout::ThirdOctave);
+    }
+}
@@ -0,0 +1,152 @@
+use std::ops::{Add, Div, Mul, Sub};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    pub fn from_polar(magnitude: f64, phase: f64) -> Self {
+        Self::new(magnitude * phase.cos(), magnitude * phase.sin())
+    }
+
+    pub fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    pub fn norm(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn norm_sqr(self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn scale(self, factor: f64) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        let denominator = other.norm_sqr();
+        let product = self * other.conj();
+        Self::new(product.re / denominator, product.im / denominator)
+    }
+}
+
+// In-place iterative radix-2 FFT; the length must be a power of two.
+// The inverse transform is scaled by 1/n so that a round trip is lossless.
+pub fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    assert!(n.is_power_of_two(), "FFT length {n} is not a power of two");
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let step = Complex::from_polar(1.0, sign * 2.0 * std::f64::consts::PI / length as f64);
+        for chunk in data.chunks_exact_mut(length) {
+            let (lower, upper) = chunk.split_at_mut(length / 2);
+            let mut twiddle = Complex::new(1.0, 0.0);
+            for (a, b) in lower.iter_mut().zip(upper.iter_mut()) {
+                let t = *b * twiddle;
+                *b = *a - t;
+                *a = *a + t;
+                twiddle = twiddle * step;
+            }
+        }
+        length <<= 1;
+    }
+
+    if inverse {
+        let scale = 1.0 / n as f64;
+        for value in data.iter_mut() {
+            *value = value.scale(scale);
+        }
+    }
+}
+
+// Transforms real samples, zero-padded to `size`
+pub fn real_fft(samples: &[f32], size: usize) -> Vec<Complex> {
+    let mut data = vec![Complex::default(); size];
+    for (value, &sample) in data.iter_mut().zip(samples) {
+        value.re = sample as f64;
+    }
+    fft(&mut data, false);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_dft() {
+        let samples: Vec<f32> = (0..16).map(|i| ((i * 7) % 5) as f32 - 2.0).collect();
+        let spectrum = real_fft(&samples, 16);
+        for (k, value) in spectrum.iter().enumerate() {
+            let expected = samples.iter().enumerate().fold(Complex::default(), |sum, (i, &x)| {
+                let angle = -2.0 * std::f64::consts::PI * (k * i) as f64 / 16.0;
+                sum + Complex::from_polar(x as f64, angle)
+            });
+            assert!((*value - expected).norm() < 1e-9, "bin {k}");
+        }
+
+        let mut round_trip = spectrum.clone();
+        fft(&mut round_trip, true);
+        for (value, &sample) in round_trip.iter().zip(&samples) {
+            assert!((value.re - sample as f64).abs() < 1e-9 && value.im.abs() < 1e-9);
+        }
+    }
+}
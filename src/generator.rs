@@ -0,0 +1,456 @@
+use cpal::{Sample, FromSample};
+use derive_builder::Builder;
+use std::str::FromStr;
+use std::time::Duration;
+
+// A mono test signal produced one sample at a time
+pub trait Signal {
+    fn next_value(&mut self) -> f32;
+
+    fn take_values(&mut self, count: usize) -> Vec<f32> {
+        (0..count).map(|_| self.next_value()).collect()
+    }
+}
+
+#[derive(Builder)]
+pub struct GeneratorConfig {
+    channels: usize,
+    #[builder(default = "false")]
+    mix: bool,
+    #[builder(default = "1.0")]
+    factor: f32,
+}
+
+// Plays a `Signal` into interleaved buffers the way `TonePlayer` plays its
+// sine: the same value on every channel, scaled by `factor`, either
+// overwriting or mixed into what is already there
+pub struct Generator<S> {
+    config: GeneratorConfig,
+    signal: S,
+}
+
+impl<S: Signal> Generator<S> {
+    pub fn with_config(config: GeneratorConfig, signal: S) -> Self {
+        Self { config, signal }
+    }
+
+    pub fn signal(&self) -> &S {
+        &self.signal
+    }
+
+    pub fn signal_mut(&mut self) -> &mut S {
+        &mut self.signal
+    }
+
+    pub fn fill_buffer<T>(&mut self, buffer: &mut [T])
+        where T: Sample + FromSample<f32> + std::ops::AddAssign
+    {
+        for frame in buffer.chunks_mut(self.config.channels) {
+            let sample_value: T = T::from_sample(self.signal.next_value() * self.config.factor);
+
+            for channel in frame.iter_mut() {
+                if self.config.mix {
+                    *channel += sample_value;
+                } else {
+                    *channel = sample_value;
+                }
+            }
+        }
+    }
+}
+
+// Feedback taps (1-based bit positions) giving a maximum length sequence
+// for every register length from 2 to 20
+const MLS_TAPS: [&[u32]; 19] = [
+    &[2, 1],
+    &[3, 2],
+    &[4, 3],
+    &[5, 3],
+    &[6, 5],
+    &[7, 6],
+    &[8, 6, 5, 4],
+    &[9, 5],
+    &[10, 7],
+    &[11, 9],
+    &[12, 6, 4, 1],
+    &[13, 4, 3, 1],
+    &[14, 5, 3, 1],
+    &[15, 14],
+    &[16, 15, 13, 4],
+    &[17, 14],
+    &[18, 11],
+    &[19, 6, 2, 1],
+    &[20, 17],
+];
+
+// Maximum length sequence of ±1 values with a period of 2^order - 1 samples.
+// Its circular autocorrelation is a single spike, which makes it a robust
+// excitation for finding delays in noisy captures.
+pub struct Mls {
+    order: u32,
+    mask: u32,
+    register: u32,
+}
+
+impl Mls {
+    pub fn new(order: u32) -> Self {
+        assert!((2..=20).contains(&order), "MLS order must be between 2 and 20");
+        let mask = MLS_TAPS[order as usize - 2].iter().fold(0, |mask, tap| mask | 1 << (order - tap));
+        Self {
+            order,
+            mask,
+            register: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        (1 << self.order) - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Signal for Mls {
+    fn next_value(&mut self) -> f32 {
+        let output = self.register & 1;
+        let feedback = (self.register & self.mask).count_ones() & 1;
+        self.register = (self.register >> 1) | (feedback << (self.order - 1));
+        if output == 1 { 1.0 } else { -1.0 }
+    }
+}
+
+// Sine sweep from `start` to `end` Hz, followed by silence
+pub struct Chirp {
+    start: f64,
+    end: f64,
+    length: usize,
+    sample_rate: u32,
+    logarithmic: bool,
+    position: usize,
+}
+
+impl Chirp {
+    // Exponential sweep spending equal time in every octave
+    pub fn logarithmic(start: f64, end: f64, duration: Duration, sample_rate: u32) -> Self {
+        Self::new(start, end, duration, sample_rate, true)
+    }
+
+    pub fn linear(start: f64, end: f64, duration: Duration, sample_rate: u32) -> Self {
+        Self::new(start, end, duration, sample_rate, false)
+    }
+
+    fn new(start: f64, end: f64, duration: Duration, sample_rate: u32, logarithmic: bool) -> Self {
+        Self {
+            start,
+            end,
+            length: (duration.as_secs_f64() * sample_rate as f64) as usize,
+            sample_rate,
+            logarithmic,
+            position: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn start_frequency(&self) -> f64 {
+        self.start
+    }
+
+    pub fn end_frequency(&self) -> f64 {
+        self.end
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0;
+    }
+}
+
+impl Signal for Chirp {
+    fn next_value(&mut self) -> f32 {
+        if self.position >= self.length {
+            return 0.0;
+        }
+
+        // Integrate the instantaneous frequency analytically so that the
+        // phase does not accumulate rounding errors
+        let t = self.position as f64 / self.sample_rate as f64;
+        let duration = self.length as f64 / self.sample_rate as f64;
+        let phase = if self.logarithmic {
+            let rate = (self.end / self.start).ln();
+            self.start * duration / rate * ((t / duration * rate).exp() - 1.0)
+        } else {
+            self.start * t + (self.end - self.start) * t * t / (2.0 * duration)
+        };
+        self.position += 1;
+
+        (2.0 * std::f64::consts::PI * phase).sin() as f32
+    }
+}
+
+// Unit impulses every `period` samples, or a single one when there is no period
+pub struct Click {
+    period: Option<usize>,
+    position: usize,
+}
+
+impl Click {
+    pub fn single() -> Self {
+        Self {
+            period: None,
+            position: 0,
+        }
+    }
+
+    pub fn periodic(period: usize) -> Self {
+        Self {
+            period: Some(period),
+            position: 0,
+        }
+    }
+}
+
+impl Signal for Click {
+    fn next_value(&mut self) -> f32 {
+        let value = if self.position == 0 { 1.0 } else { 0.0 };
+        self.position += 1;
+        if let Some(period) = self.period {
+            self.position %= period;
+        }
+        value
+    }
+}
+
+// Plays precomputed values once, then silence until rewound
+pub struct Sequence {
+    values: Vec<f32>,
+    position: usize,
+}
+
+impl Sequence {
+    pub fn new(values: Vec<f32>) -> Self {
+        // Start exhausted so that nothing plays until the first rewind
+        let position = values.len();
+        Self { values, position }
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.values.len()
+    }
+}
+
+impl Signal for Sequence {
+    fn next_value(&mut self) -> f32 {
+        let value = self.values.get(self.position).copied().unwrap_or(0.0);
+        self.position = (self.position + 1).min(self.values.len());
+        value
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Square,
+    Triangle,
+    Sawtooth,
+}
+
+impl FromStr for Waveform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sine" | "sin" => Ok(Waveform::Sine),
+            "square" | "sq" => Ok(Waveform::Square),
+            "triangle" | "tri" => Ok(Waveform::Triangle),
+            "sawtooth" | "saw" => Ok(Waveform::Sawtooth),
+            _ => anyhow::bail!("Unknown waveform '{s}', expected sine, square, triangle or sawtooth"),
+        }
+    }
+}
+
+// Periodic waveform at a fixed frequency and amplitude. The square and
+// sawtooth are naive, so they alias at high frequencies.
+#[derive(Clone, Debug)]
+pub struct Oscillator {
+    waveform: Waveform,
+    frequency: f64,
+    amplitude: f32,
+    sample_rate: u32,
+    // In cycles, from 0 to 1
+    phase: f64,
+}
+
+impl Oscillator {
+    pub fn new(waveform: Waveform, frequency: f64, amplitude: f32, sample_rate: u32) -> Self {
+        Self {
+            waveform,
+            frequency,
+            amplitude,
+            sample_rate,
+            phase: 0.0,
+        }
+    }
+}
+
+impl Signal for Oscillator {
+    fn next_value(&mut self) -> f32 {
+        let phase = self.phase;
+        self.phase = (self.phase + self.frequency / self.sample_rate as f64).fract();
+        let value = match self.waveform {
+            Waveform::Sine => (2.0 * std::f64::consts::PI * phase).sin(),
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * (phase + 0.5).fract() - 1.0,
+        };
+        value as f32 * self.amplitude
+    }
+}
+
+// Length of the fades at the edges of every step, against clicks
+const STEP_RAMP: Duration = Duration::from_millis(5);
+
+// Steps of simultaneous oscillators played one after another, then silence
+pub struct ToneSequence {
+    sample_rate: u32,
+    steps: Vec<(Vec<Oscillator>, usize)>,
+    step: usize,
+    position: usize,
+}
+
+impl ToneSequence {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            steps: Vec::new(),
+            step: 0,
+            position: 0,
+        }
+    }
+
+    pub fn push(&mut self, oscillators: Vec<Oscillator>, duration: Duration) {
+        let length = (duration.as_secs_f64() * self.sample_rate as f64).round() as usize;
+        self.steps.push((oscillators, length));
+    }
+
+    // Total length in samples
+    pub fn len(&self) -> usize {
+        self.steps.iter().map(|(_, length)| length).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // True once every sample of every step has been produced
+    pub fn is_finished(&self) -> bool {
+        self.steps.iter().skip(self.step).map(|(_, length)| length).sum::<usize>() <= self.position
+    }
+}
+
+impl Signal for ToneSequence {
+    fn next_value(&mut self) -> f32 {
+        while let Some((_, length)) = self.steps.get(self.step)
+            && self.position >= *length
+        {
+            self.step += 1;
+            self.position = 0;
+        }
+        let Some((oscillators, length)) = self.steps.get_mut(self.step) else {
+            return 0.0;
+        };
+
+        let ramp = (STEP_RAMP.as_secs_f64() * self.sample_rate as f64) as usize;
+        let ramp = ramp.min(*length / 2).max(1);
+        let edge = self.position.min(*length - 1 - self.position);
+        let gain = (edge as f32 / ramp as f32).min(1.0);
+        self.position += 1;
+        oscillators.iter_mut().map(|oscillator| oscillator.next_value()).sum::<f32>() * gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mls_period() {
+        for order in 2..=16 {
+            let mut mls = Mls::new(order);
+            let length = mls.len();
+            let first = mls.take_values(length);
+            let second = mls.take_values(length);
+            assert_eq!(first, second, "order {order}");
+            // One more +1 than -1 over a period, and no shorter period
+            assert_eq!(first.iter().sum::<f32>(), 1.0, "order {order}");
+        }
+    }
+
+    #[test]
+    fn test_generator_fills_channels() {
+        let mut generator = Generator::with_config(
+            GeneratorConfigBuilder::default()
+                .channels(2)
+                .factor(0.5)
+                .build()
+                .expect("Failed to build GeneratorConfig"),
+            Click::periodic(3),
+        );
+        let mut buffer = [0.0f32; 8];
+        generator.fill_buffer(&mut buffer);
+        assert_eq!(buffer, [0.5, 0.5, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5]);
+
+        let mut chirp = Chirp::logarithmic(20.0, 20000.0, Duration::from_millis(100), 48000);
+        let sweep = chirp.take_values(chirp.len() + 10);
+        assert!(sweep.iter().all(|x| x.abs() <= 1.0));
+        assert_eq!(sweep[chirp.len()..], [0.0; 10]);
+    }
+
+    #[test]
+    fn test_tone_sequence() {
+        let mut square = Oscillator::new(Waveform::Square, 1000.0, 0.5, 8000);
+        assert_eq!(square.take_values(8), [0.5, 0.5, 0.5, 0.5, -0.5, -0.5, -0.5, -0.5]);
+        let mut triangle = Oscillator::new(Waveform::Triangle, 1000.0, 1.0, 8000);
+        assert_eq!(triangle.take_values(4), [0.0, 0.5, 1.0, 0.5]);
+
+        // 100 ms of a sine, then 50 ms of two sines summed, at 8 kHz
+        let mut sequence = ToneSequence::new(8000);
+        sequence.push(vec![Oscillator::new(Waveform::Sine, 500.0, 1.0, 8000)], Duration::from_millis(100));
+        sequence.push(
+            vec![
+                Oscillator::new(Waveform::Sine, 500.0, 0.5, 8000),
+                Oscillator::new(Waveform::Sine, 500.0, 0.5, 8000),
+            ],
+            Duration::from_millis(50),
+        );
+        assert_eq!(sequence.len(), 1200);
+        let values = sequence.take_values(1199);
+        assert!(!sequence.is_finished());
+        // Both steps start silent and fade in over 5 ms
+        assert_eq!(values[0], 0.0);
+        assert_eq!(values[800], 0.0);
+        assert!(values[..40].iter().enumerate().all(|(i, v)| v.abs() <= i as f32 / 40.0 + 1e-6));
+        let peak = |values: &[f32]| values.iter().fold(0.0f32, |peak, v| peak.max(v.abs()));
+        assert!((peak(&values[100..700]) - 1.0).abs() < 1e-6);
+        assert!((peak(&values[900..1100]) - 1.0).abs() < 1e-6);
+
+        sequence.next_value();
+        assert!(sequence.is_finished());
+        assert_eq!(sequence.take_values(10), [0.0; 10]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::time::Duration;
+
+use crate::fft::{fft, real_fft};
+use crate::generator::{Chirp, Signal};
+
+// Raised-cosine fade at the end of the sweep, so that it does not stop with
+// a click that would smear over the whole response
+const FADE_OUT: Duration = Duration::from_millis(2);
+// Kept before the peak of every extracted response
+const PRE_ROLL: Duration = Duration::from_millis(1);
+
+// Exponential sine sweep and its inverse filter, after Farina. Convolving
+// what a system makes of the sweep with the inverse filter yields the
+// system's linear impulse response, preceded by one response per harmonic
+// distortion product at offsets that only depend on the sweep rate.
+pub struct Sweep {
+    samples: Vec<f32>,
+    inverse: Vec<f32>,
+    start: f64,
+    end: f64,
+    sample_rate: u32,
+}
+
+// Responses cut out of a deconvolved recording
+pub struct ImpulseResponses {
+    // Linear impulse response, starting `pre_roll` frames before its peak
+    pub linear: Vec<f32>,
+    // Responses of the 2nd, 3rd, ... harmonics, aligned like `linear`
+    pub harmonics: Vec<Vec<f32>>,
+    // Frames from the start of the recording to the arrival of the sweep
+    pub delay: usize,
+    pub pre_roll: usize,
+}
+
+impl Sweep {
+    pub fn new(start: f64, end: f64, duration: Duration, sample_rate: u32) -> Self {
+        let mut chirp = Chirp::logarithmic(start, end, duration, sample_rate);
+        let mut samples = chirp.take_values(chirp.len());
+        let length = samples.len();
+
+        let fade = ((FADE_OUT.as_secs_f64() * sample_rate as f64) as usize).min(length);
+        for (index, sample) in samples[length - fade..].iter_mut().enumerate() {
+            let phase = std::f32::consts::PI * (index + 1) as f32 / fade as f32;
+            *sample *= 0.5 + 0.5 * phase.cos();
+        }
+
+        // The time-reversed sweep, weighted by 6 dB per octave of its
+        // instantaneous frequency to take out the extra energy the sweep
+        // spends at low frequencies
+        let rate = (end / start).ln();
+        let mut inverse: Vec<f32> = (0..length)
+            .map(|n| {
+                let source = length - 1 - n;
+                samples[source] * (rate * (source as f64 / length as f64 - 1.0)).exp() as f32
+            })
+            .collect();
+
+        // Unity gain across the band, judged away from its edges
+        let size = (2 * length).next_power_of_two();
+        let sweep_spectrum = real_fft(&samples, size);
+        let inverse_spectrum = real_fft(&inverse, size);
+        let bin = |frequency: f64| (frequency * size as f64 / sample_rate as f64) as usize;
+        let band = bin(2.0 * start)..bin(end / 2.0).max(bin(2.0 * start) + 1);
+        let gain = band.clone().map(|k| (sweep_spectrum[k] * inverse_spectrum[k]).norm()).sum::<f64>() / band.len() as f64;
+        if gain > 0.0 {
+            inverse.iter_mut().for_each(|x| *x /= gain as f32);
+        }
+
+        Self { samples, inverse, start, end, sample_rate }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // How far ahead of the linear response that of the given harmonic
+    // (2 for the second) lands, in frames
+    pub fn harmonic_offset(&self, order: usize) -> f64 {
+        self.len() as f64 * (order as f64).ln() / (self.end / self.start).ln()
+    }
+
+    // Full convolution of `recording` with the inverse filter. For a system
+    // without delay the linear response peaks at `len() - 1`.
+    pub fn deconvolve(&self, recording: &[f32]) -> Vec<f32> {
+        if recording.is_empty() || self.is_empty() {
+            return Vec::new();
+        }
+        let length = recording.len() + self.inverse.len() - 1;
+        let size = length.next_power_of_two();
+        let recording_spectrum = real_fft(recording, size);
+        let inverse_spectrum = real_fft(&self.inverse, size);
+        let mut product: Vec<_> = recording_spectrum.iter().zip(&inverse_spectrum).map(|(&r, &i)| r * i).collect();
+        fft(&mut product, true);
+        product[..length].iter().map(|value| value.re as f32).collect()
+    }
+
+    // Deconvolves `recording` and cuts out the linear response and those of
+    // `harmonics` distortion products, each at most `length` frames long.
+    // Harmonic responses are shortened where they would run into the next
+    // lower one.
+    pub fn analyze(&self, recording: &[f32], length: usize, harmonics: usize) -> Option<ImpulseResponses> {
+        let response = self.deconvolve(recording);
+        let origin = self.len().checked_sub(1)?;
+        let (offset, _) = response
+            .get(origin..)?
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))?;
+        let peak = origin + offset;
+        let pre_roll = (PRE_ROLL.as_secs_f64() * self.sample_rate as f64).round() as usize;
+
+        let cut = |start: f64, length: usize| -> Vec<f32> {
+            let start = start.round() as isize;
+            (0..length as isize)
+                .map(|index| {
+                    usize::try_from(start + index)
+                        .ok()
+                        .and_then(|position| response.get(position))
+                        .copied()
+                        .unwrap_or(0.0)
+                })
+                .collect()
+        };
+        let start = peak as f64 - pre_roll as f64;
+        let linear = cut(start, length);
+        let harmonics = (2..harmonics + 2)
+            .map(|order| {
+                let room = self.harmonic_offset(order) - self.harmonic_offset(order - 1);
+                cut(start - self.harmonic_offset(order), length.min(room as usize))
+            })
+            .collect();
+
+        Some(ImpulseResponses { linear, harmonics, delay: offset, pre_roll })
+    }
+}
+
+// Reverberation times in seconds, each extrapolated to a 60 dB decay
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DecayTimes {
+    // Early decay time, fitted from 0 to -10 dB
+    pub edt: Option<f64>,
+    // Fitted from -5 to -25 dB
+    pub t20: Option<f64>,
+    // Fitted from -5 to -35 dB
+    pub t30: Option<f64>,
+}
+
+impl DecayTimes {
+    // T30 where the response decays far enough above the noise, else T20
+    pub fn rt60(&self) -> Option<f64> {
+        self.t30.or(self.t20)
+    }
+}
+
+// Schroeder backward integral of the squared response in dB, 0 at the
+// start. The mean energy of the last tenth is taken as the noise floor: it
+// is subtracted, and the curve ends where the response sinks into it.
+pub fn energy_decay_curve(impulse_response: &[f32]) -> Vec<f64> {
+    if impulse_response.is_empty() {
+        return Vec::new();
+    }
+    let energy: Vec<f64> = impulse_response.iter().map(|&x| x as f64 * x as f64).collect();
+    let tail = (energy.len() / 10).max(1);
+    let noise = energy[energy.len() - tail..].iter().sum::<f64>() / tail as f64;
+
+    // The first stretch after the peak whose mean falls within 3 dB of the noise
+    let peak = energy.iter().enumerate().max_by(|(_, a), (_, b)| a.total_cmp(b)).map_or(0, |(index, _)| index);
+    let stretch = (energy.len() / 100).max(1);
+    let end = energy[peak..]
+        .chunks(stretch)
+        .position(|chunk| chunk.iter().sum::<f64>() / (chunk.len() as f64) < 2.0 * noise)
+        .map_or(energy.len(), |index| peak + index * stretch);
+
+    let mut remaining = 0.0;
+    let mut curve: Vec<f64> = energy[..end]
+        .iter()
+        .rev()
+        .map(|&e| {
+            remaining += (e - noise).max(0.0);
+            remaining
+        })
+        .collect();
+    curve.reverse();
+    let total = curve.first().copied().unwrap_or(0.0).max(f64::MIN_POSITIVE);
+    curve.iter().map(|&e| 10.0 * (e / total).max(1e-30).log10()).collect()
+}
+
+pub fn decay_times(impulse_response: &[f32], sample_rate: u32) -> DecayTimes {
+    let curve = energy_decay_curve(impulse_response);
+    let fit = |from: f64, to: f64| decay_time(&curve, from, to, sample_rate);
+    DecayTimes {
+        edt: fit(0.0, -10.0),
+        t20: fit(-5.0, -25.0),
+        t30: fit(-5.0, -35.0),
+    }
+}
+
+// Least-squares slope of the decay curve between two levels, as the time a
+// 60 dB decay would take
+fn decay_time(curve: &[f64], from: f64, to: f64, sample_rate: u32) -> Option<f64> {
+    let first = curve.iter().position(|&level| level <= from)?;
+    let last = first + curve[first..].iter().position(|&level| level <= to)?;
+    if last <= first {
+        return None;
+    }
+
+    let count = (last - first + 1) as f64;
+    let mean_x = (first + last) as f64 / 2.0;
+    let mean_y = curve[first..=last].iter().sum::<f64>() / count;
+    let (covariance, variance) = curve[first..=last].iter().enumerate().fold((0.0, 0.0), |(c, v), (index, &level)| {
+        let dx = (first + index) as f64 - mean_x;
+        (c + dx * (level - mean_y), v + dx * dx)
+    });
+    let slope = covariance / variance;
+    (slope < 0.0).then(|| -60.0 / slope / sample_rate as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::Mls;
+
+    #[test]
+    fn test_sweep_measurement() {
+        let sweep = Sweep::new(20.0, 20000.0, Duration::from_secs(1), 48000);
+        assert_eq!(sweep.len(), 48000);
+
+        // A delayed echo behind a slightly squaring system
+        let x = |n: usize| if n < sweep.len() { sweep.samples()[n] } else { 0.0 };
+        let recording: Vec<f32> = (0..sweep.len() + 4800)
+            .map(|n| {
+                let direct = if n >= 200 { x(n - 200) } else { 0.0 };
+                let echo = if n >= 250 { x(n - 250) } else { 0.0 };
+                direct + 0.1 * direct * direct - 0.5 * echo
+            })
+            .collect();
+
+        let responses = sweep.analyze(&recording, 2400, 2).unwrap();
+        assert_eq!(responses.delay, 200);
+        assert_eq!(responses.pre_roll, 48);
+        let linear = &responses.linear;
+        assert_eq!(linear.len(), 2400);
+        let peak = linear[48];
+        assert!(peak > 0.7, "{peak}");
+        assert!((linear[98] / peak + 0.5).abs() < 0.05, "{}", linear[98] / peak);
+
+        // The squaring shows up as a second harmonic only
+        let largest = |response: &[f32]| response.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let second = largest(&responses.harmonics[0]);
+        let third = largest(&responses.harmonics[1]);
+        assert!(second > 0.01 && second < 0.1, "{second}");
+        assert!(third < second / 10.0, "{third}");
+
+        // A decaying noise tail with a 400 ms reverberation time over a
+        // noise floor 80 dB down
+        let decay = Mls::new(16)
+            .take_values(48000)
+            .into_iter()
+            .zip(Mls::new(15).take_values(48000))
+            .enumerate()
+            .map(|(n, (tail, floor))| tail * 10f32.powf(-3.0 * n as f32 / 48000.0 / 0.4) + 1e-4 * floor)
+            .collect::<Vec<_>>();
+        let times = decay_times(&decay, 48000);
+        for time in [times.edt, times.t20, times.t30, times.rt60()] {
+            let time = time.unwrap();
+            assert!((time - 0.4).abs() < 0.02, "{times:?}");
+        }
+        assert_eq!(decay_times(&decay[..100], 48000).t30, None);
+    }
+}
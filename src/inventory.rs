@@ -0,0 +1,330 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use serde::Serialize;
+
+// Snapshot of every host and device cpal can see, with the configurations
+// they advertise. Failures to query a device are recorded in `errors`
+// rather than aborting the whole inventory.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Inventory {
+    pub hosts: Vec<HostReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HostReport {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_input: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_output: Option<String>,
+    pub devices: Vec<DeviceReport>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceReport {
+    pub name: String,
+    pub input: bool,
+    pub output: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_input_config: Option<ConfigReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_output_config: Option<ConfigReport>,
+    pub input_configs: Vec<ConfigRangeReport>,
+    pub output_configs: Vec<ConfigRangeReport>,
+    pub errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigReport {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_format: String,
+    pub buffer_size: BufferSizeReport,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigRangeReport {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+    pub buffer_size: BufferSizeReport,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BufferSizeReport {
+    Range { min: u32, max: u32 },
+    Unknown,
+}
+
+impl std::fmt::Display for BufferSizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Range { min, max } => write!(f, "{min}-{max}"),
+            Self::Unknown => write!(f, "-"),
+        }
+    }
+}
+
+impl From<&cpal::SupportedBufferSize> for BufferSizeReport {
+    fn from(buffer_size: &cpal::SupportedBufferSize) -> Self {
+        match *buffer_size {
+            cpal::SupportedBufferSize::Range { min, max } => Self::Range { min, max },
+            cpal::SupportedBufferSize::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl From<&cpal::SupportedStreamConfig> for ConfigReport {
+    fn from(config: &cpal::SupportedStreamConfig) -> Self {
+        Self {
+            channels: config.channels(),
+            sample_rate: config.sample_rate().0,
+            sample_format: config.sample_format().to_string(),
+            buffer_size: config.buffer_size().into(),
+        }
+    }
+}
+
+impl From<&cpal::SupportedStreamConfigRange> for ConfigRangeReport {
+    fn from(config: &cpal::SupportedStreamConfigRange) -> Self {
+        Self {
+            channels: config.channels(),
+            min_sample_rate: config.min_sample_rate().0,
+            max_sample_rate: config.max_sample_rate().0,
+            sample_format: config.sample_format().to_string(),
+            buffer_size: config.buffer_size().into(),
+        }
+    }
+}
+
+impl Inventory {
+    pub fn collect() -> Self {
+        let hosts = cpal::available_hosts()
+            .into_iter()
+            .map(|host_id| match cpal::host_from_id(host_id) {
+                Ok(host) => HostReport::collect(&host),
+                Err(err) => HostReport {
+                    name: host_id.name().to_string(),
+                    default_input: None,
+                    default_output: None,
+                    devices: Vec::new(),
+                    errors: vec![err.to_string()],
+                },
+            })
+            .collect();
+        Self { hosts }
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    // One whitespace-aligned row per configuration (or per device without
+    // any), with a fixed set of columns and `-` for missing values
+    pub fn to_table(&self) -> String {
+        let mut rows = vec![
+            ["HOST", "DEVICE", "DIRECTION", "KIND", "CHANNELS", "MIN_RATE", "MAX_RATE", "FORMAT", "BUFFER"]
+                .map(String::from)
+                .to_vec(),
+        ];
+
+        for host in &self.hosts {
+            for device in &host.devices {
+                let mut device_rows = Vec::new();
+                let directions = [
+                    ("input", &device.default_input_config, &device.input_configs),
+                    ("output", &device.default_output_config, &device.output_configs),
+                ];
+                for (direction, default, ranges) in directions {
+                    if let Some(config) = default {
+                        device_rows.push(vec![
+                            direction.to_string(),
+                            "default".to_string(),
+                            config.channels.to_string(),
+                            config.sample_rate.to_string(),
+                            config.sample_rate.to_string(),
+                            config.sample_format.clone(),
+                            config.buffer_size.to_string(),
+                        ]);
+                    }
+                    for range in ranges {
+                        device_rows.push(vec![
+                            direction.to_string(),
+                            "supported".to_string(),
+                            range.channels.to_string(),
+                            range.min_sample_rate.to_string(),
+                            range.max_sample_rate.to_string(),
+                            range.sample_format.clone(),
+                            range.buffer_size.to_string(),
+                        ]);
+                    }
+                }
+                if device_rows.is_empty() {
+                    device_rows.push(vec!["-".to_string(); 7]);
+                }
+                for row in device_rows {
+                    let mut full = vec![host.name.clone(), device.name.clone()];
+                    full.extend(row);
+                    rows.push(full);
+                }
+            }
+        }
+
+        format_table(&rows)
+    }
+}
+
+// Left-aligns every column to its widest cell, two spaces apart, one line
+// per row; all rows have as many cells as the first
+pub fn format_table(rows: &[Vec<String>]) -> String {
+    let columns = rows.first().map_or(0, Vec::len);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+        .collect();
+    let mut table = String::new();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{cell:<width$}"))
+            .collect();
+        table.push_str(line.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+impl HostReport {
+    pub fn collect(host: &cpal::Host) -> Self {
+        let mut report = Self {
+            name: host.id().name().to_string(),
+            default_input: host.default_input_device().and_then(|d| d.name().ok()),
+            default_output: host.default_output_device().and_then(|d| d.name().ok()),
+            devices: Vec::new(),
+            errors: Vec::new(),
+        };
+        match host.devices() {
+            Ok(devices) => report.devices = devices.map(|device| DeviceReport::collect(&device)).collect(),
+            Err(err) => report.errors.push(err.to_string()),
+        }
+        report
+    }
+}
+
+impl DeviceReport {
+    pub fn collect(device: &cpal::Device) -> Self {
+        let mut errors = Vec::new();
+        let name = device.name().unwrap_or_else(|err| {
+            errors.push(err.to_string());
+            "<unknown>".to_string()
+        });
+
+        let default_input_config = device.default_input_config().ok().map(|c| ConfigReport::from(&c));
+        let default_output_config = device.default_output_config().ok().map(|c| ConfigReport::from(&c));
+        let input_configs = match device.supported_input_configs() {
+            Ok(configs) => configs.map(|c| ConfigRangeReport::from(&c)).collect(),
+            Err(err) => {
+                if default_input_config.is_some() {
+                    errors.push(format!("input configs: {err}"));
+                }
+                Vec::new()
+            }
+        };
+        let output_configs = match device.supported_output_configs() {
+            Ok(configs) => configs.map(|c| ConfigRangeReport::from(&c)).collect(),
+            Err(err) => {
+                if default_output_config.is_some() {
+                    errors.push(format!("output configs: {err}"));
+                }
+                Vec::new()
+            }
+        };
+
+        Self {
+            name,
+            input: default_input_config.is_some() || !input_configs.is_empty(),
+            output: default_output_config.is_some() || !output_configs.is_empty(),
+            default_input_config,
+            default_output_config,
+            input_configs,
+            output_configs,
+            errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inventory() -> Inventory {
+        let config = ConfigReport {
+            channels: 2,
+            sample_rate: 48000,
+            sample_format: "f32".to_string(),
+            buffer_size: BufferSizeReport::Range { min: 64, max: 4096 },
+        };
+        Inventory {
+            hosts: vec![HostReport {
+                name: "ALSA".to_string(),
+                default_input: Some("default".to_string()),
+                default_output: None,
+                devices: vec![
+                    DeviceReport {
+                        name: "default".to_string(),
+                        input: true,
+                        output: false,
+                        default_input_config: Some(config),
+                        default_output_config: None,
+                        input_configs: vec![ConfigRangeReport {
+                            channels: 1,
+                            min_sample_rate: 8000,
+                            max_sample_rate: 192000,
+                            sample_format: "i16".to_string(),
+                            buffer_size: BufferSizeReport::Unknown,
+                        }],
+                        output_configs: Vec::new(),
+                        errors: Vec::new(),
+                    },
+                    DeviceReport {
+                        name: "null".to_string(),
+                        input: false,
+                        output: false,
+                        default_input_config: None,
+                        default_output_config: None,
+                        input_configs: Vec::new(),
+                        output_configs: Vec::new(),
+                        errors: vec!["busy".to_string()],
+                    },
+                ],
+                errors: Vec::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_inventory_formats() {
+        let inventory = inventory();
+        assert_eq!(
+            inventory.to_table(),
+            "HOST  DEVICE   DIRECTION  KIND       CHANNELS  MIN_RATE  MAX_RATE  FORMAT  BUFFER\n\
+             ALSA  default  input      default    2         48000     48000     f32     64-4096\n\
+             ALSA  default  input      supported  1         8000      192000    i16     -\n\
+             ALSA  null     -          -          -         -         -         -       -\n"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&inventory.to_json().unwrap()).unwrap();
+        assert_eq!(json["hosts"][0]["devices"][0]["default_input_config"]["buffer_size"]["kind"], "range");
+        assert_eq!(json["hosts"][0]["devices"][1]["errors"][0], "busy");
+        assert!(json["hosts"][0].get("default_output").is_none());
+
+        let toml: toml::Value = toml::from_str(&inventory.to_toml().unwrap()).unwrap();
+        assert_eq!(toml["hosts"][0]["devices"][0]["input_configs"][0]["max_sample_rate"].as_integer(), Some(192000));
+    }
+}
@@ -0,0 +1,195 @@
+use cpal::traits::StreamTrait;
+use derive_builder::Builder;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use anyhow::Context;
+
+use crate::correlation::find_delay;
+use crate::generator::{Generator, GeneratorConfigBuilder, Sequence};
+use crate::loopback::{self, common_rate, MAX_LATENCY, SETTLE};
+use crate::ring_buffer::{ring_buffer, Consumer};
+use crate::virtual_audio::AudioBackend;
+
+#[derive(Builder)]
+pub struct LatencyConfig {
+    // Played on the output and looked for in what the input captures
+    reference: Vec<f32>,
+    // Linear playback level of the reference
+    #[builder(default = "0.5")]
+    level: f32,
+    // Longest latency to look for
+    #[builder(default = "MAX_LATENCY")]
+    max_latency: Duration,
+}
+
+pub struct LatencyMeasurement {
+    // From the output callback that started the reference to the input
+    // callback that delivered it, in samples
+    pub latency: f64,
+    // How far the correlation peak stands out from the rest
+    pub clarity: f32,
+    // What the cpal timestamps of both streams add up to, in samples
+    pub reported: f64,
+}
+
+// What the callbacks share with the measurements. Times are in nanoseconds
+// since the start of `now`.
+struct Shared {
+    now: Box<dyn Fn() -> Duration + Send + Sync>,
+    trigger: AtomicBool,
+    emitted_at: AtomicU64,
+    // When the input callback delivered captured frame zero, as f64 bits,
+    // extrapolated from the latest callback so the frame count and its time
+    // are always read together
+    anchor: AtomicU64,
+    // Latest latencies reported through the cpal callback timestamps
+    output_latency: AtomicU64,
+    input_latency: AtomicU64,
+}
+
+impl Shared {
+    fn now(&self) -> u64 {
+        (self.now)().as_nanos() as u64
+    }
+}
+
+// Measures round-trip latency by playing a known signal on an output and
+// finding it again in what an input captures. Both callbacks read the time
+// from `now` rather than from their timestamps, which need not share an
+// origin across streams.
+pub struct LatencyMeter<S> {
+    shared: Arc<Shared>,
+    consumer: Consumer,
+    reference: Vec<f32>,
+    sample_rate: u32,
+    recording: Vec<f32>,
+    // Frames taken from the consumer so far
+    consumed: u64,
+    _output: S,
+    _input: S,
+}
+
+impl<S: StreamTrait> LatencyMeter<S> {
+    pub fn start<B>(
+        output: &B,
+        output_config: &cpal::StreamConfig,
+        input: &B,
+        input_config: &cpal::StreamConfig,
+        config: LatencyConfig,
+        now: impl Fn() -> Duration + Send + Sync + 'static,
+    ) -> anyhow::Result<Self>
+    where
+        B: AudioBackend<Stream = S>,
+    {
+        let sample_rate = common_rate(output_config, input_config)?;
+        let frame_ns = 1e9 / sample_rate as f64;
+        let shared = Arc::new(Shared {
+            now: Box::new(now),
+            trigger: AtomicBool::new(false),
+            emitted_at: AtomicU64::new(0),
+            anchor: AtomicU64::new(0f64.to_bits()),
+            output_latency: AtomicU64::new(0),
+            input_latency: AtomicU64::new(0),
+        });
+
+        let mut generator = Generator::with_config(
+            GeneratorConfigBuilder::default()
+                .channels(output_config.channels as usize)
+                .factor(config.level)
+                .build()?,
+            Sequence::new(config.reference.clone()),
+        );
+        let output_shared = shared.clone();
+        let output_stream = output.build_output(
+            output_config,
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                let now = output_shared.now();
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                    output_shared.output_latency.store(latency.as_nanos() as u64, Ordering::Relaxed);
+                }
+                if output_shared.trigger.swap(false, Ordering::AcqRel) {
+                    generator.signal_mut().rewind();
+                    output_shared.emitted_at.store(now, Ordering::Release);
+                }
+                generator.fill_buffer(data);
+            },
+            move |err| {
+                eprintln!("An error occurred on the output stream: {}", err);
+            },
+        )?;
+
+        // Room for a recording and a few seconds besides; only the first
+        // channel is kept
+        let frames = config.reference.len() + (config.max_latency.as_secs_f64() * sample_rate as f64) as usize;
+        let input_channels = input_config.channels as usize;
+        let (mut producer, consumer) = ring_buffer(frames + sample_rate as usize * 4);
+        let input_shared = shared.clone();
+        let mut captured_frames: u64 = 0;
+        let input_stream = input.build_input(
+            input_config,
+            move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                let now = input_shared.now();
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.callback.duration_since(&timestamp.capture) {
+                    input_shared.input_latency.store(latency.as_nanos() as u64, Ordering::Relaxed);
+                }
+                // Frames the ring buffer dropped never reach the consumer, so
+                // only the pushed ones count towards its frame numbers
+                captured_frames += producer.push_iter(data.iter().step_by(input_channels).copied()) as u64;
+                if captured_frames > 0 {
+                    let anchor = now as f64 - (captured_frames - 1) as f64 * frame_ns;
+                    input_shared.anchor.store(anchor.to_bits(), Ordering::Release);
+                }
+            },
+            move |err| {
+                eprintln!("An error occurred on the input stream: {}", err);
+            },
+        )?;
+
+        input_stream.play()?;
+        output_stream.play()?;
+        Ok(Self {
+            shared,
+            consumer,
+            reference: config.reference,
+            sample_rate,
+            recording: vec![0.0; frames],
+            consumed: 0,
+            _output: output_stream,
+            _input: input_stream,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Lets the previous reference die away, plays it again and looks for it
+    // in the recording. `wait` lets time pass meanwhile.
+    pub fn measure(&mut self, mut wait: impl FnMut(Duration)) -> anyhow::Result<LatencyMeasurement> {
+        wait(SETTLE);
+        self.consumed += self.consumer.skip(self.consumer.len()) as u64;
+        let recording_start = self.consumed;
+        self.shared.trigger.store(true, Ordering::Release);
+
+        loopback::record(&mut self.consumer, &mut self.recording, self.sample_rate, wait)?;
+        self.consumed += self.recording.len() as u64;
+
+        let anchor = f64::from_bits(self.shared.anchor.load(Ordering::Acquire));
+        let emitted_at = self.shared.emitted_at.load(Ordering::Acquire) as f64;
+        let delay = find_delay(&self.recording, &self.reference).context("Nothing was captured")?;
+
+        // Host time at which the input callback delivered the detected frame
+        let frame_ns = 1e9 / self.sample_rate as f64;
+        let detected_frame = recording_start + delay.lag as u64;
+        let detected_at = anchor + detected_frame as f64 * frame_ns;
+        let reported = self.shared.output_latency.load(Ordering::Relaxed) + self.shared.input_latency.load(Ordering::Relaxed);
+        Ok(LatencyMeasurement {
+            latency: (detected_at - emitted_at) / frame_ns,
+            clarity: delay.clarity,
+            reported: reported as f64 / frame_ns,
+        })
+    }
+}
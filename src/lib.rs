@@ -1,6 +1,42 @@
 use cpal::{Sample, FromSample};
 use derive_builder::Builder;
 
+pub mod aggregator;
+pub mod analysis;
+pub mod biquad;
+pub mod bridge;
+pub mod cli;
+pub mod convolution;
+pub mod correlation;
+pub mod delay;
+pub mod device;
+pub mod distortion;
+pub mod drift;
+pub mod dynamics;
+pub mod equalizer;
+pub mod fft;
+pub mod generator;
+pub mod impulse;
+pub mod inventory;
+pub mod latency;
+pub mod loopback;
+pub mod monitoring;
+pub mod octave;
+pub mod playback;
+pub mod preview;
+pub mod probe;
+pub mod processor;
+pub mod render;
+pub mod resample;
+pub mod response;
+pub mod reverb;
+pub mod ring_buffer;
+pub mod routing;
+pub mod scope;
+pub mod supervisor;
+pub mod timing;
+pub mod virtual_audio;
+pub mod watch;
 pub mod window;
 
 
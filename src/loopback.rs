@@ -0,0 +1,258 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::device::{find_device, Direction};
+use crate::generator::{Generator, GeneratorConfigBuilder, Sequence};
+use crate::ring_buffer::{ring_buffer, Consumer};
+use crate::virtual_audio::AudioBackend;
+use crate::{TonePlayer, TonePlayerConfigBuilder};
+
+// Longest round trip a recording leaves room for
+pub const MAX_LATENCY: Duration = Duration::from_secs(1);
+// Time for the streams and whatever lies between them to settle before the
+// first measurement
+pub const SETTLE: Duration = Duration::from_millis(300);
+// How often a recording looks for newly captured audio
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How much longer than the audio itself a recording may take before the
+// input counts as stopped
+const STALL_MARGIN: Duration = Duration::from_secs(2);
+
+// An output and an input device of a host, by name or the defaults, with
+// their default configurations
+pub struct LoopbackDevices {
+    pub output: cpal::Device,
+    pub output_config: cpal::SupportedStreamConfig,
+    pub input: cpal::Device,
+    pub input_config: cpal::SupportedStreamConfig,
+}
+
+impl LoopbackDevices {
+    pub fn find(host: &cpal::Host, output: Option<&str>, input: Option<&str>) -> anyhow::Result<Self> {
+        let output = find_device(host, Direction::Output, output)?;
+        let input = find_device(host, Direction::Input, input)?;
+        Ok(Self {
+            output_config: output.default_output_config()?,
+            output,
+            input_config: input.default_input_config()?,
+            input,
+        })
+    }
+}
+
+// The rate shared by both ends. Measurements compare the recording with what
+// was played sample for sample, and resampling would add a delay of its own.
+pub fn common_rate(output: &cpal::StreamConfig, input: &cpal::StreamConfig) -> anyhow::Result<u32> {
+    anyhow::ensure!(
+        input.sample_rate == output.sample_rate,
+        "The input runs at {} Hz and the output at {} Hz; a loopback measurement needs a common rate",
+        input.sample_rate.0,
+        output.sample_rate.0
+    );
+    Ok(output.sample_rate.0)
+}
+
+// One channel of a running input stream, collected for recordings
+pub struct Capture<S> {
+    consumer: Consumer,
+    sample_rate: u32,
+    _stream: S,
+}
+
+impl<S: StreamTrait> Capture<S> {
+    // Starts capturing `channel` of the input, keeping up to `capacity`
+    // frames that no recording has taken yet
+    pub fn start<B>(device: &B, config: &cpal::StreamConfig, channel: usize, capacity: usize) -> anyhow::Result<Self>
+    where
+        B: AudioBackend<Stream = S>,
+    {
+        let channels = config.channels as usize;
+        anyhow::ensure!(channel < channels, "The input has {channels} channels, there is no channel {channel}");
+        let (mut producer, consumer) = ring_buffer(capacity);
+        let stream = device.build_input(
+            config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                producer.push_iter(data.iter().skip(channel).step_by(channels).copied());
+            },
+            move |err| {
+                eprintln!("An error occurred on the input stream: {}", err);
+            },
+        )?;
+        stream.play()?;
+        Ok(Self { consumer, sample_rate: config.sample_rate.0, _stream: stream })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Forgets everything captured so far
+    pub fn discard(&mut self) {
+        self.consumer.skip(self.consumer.len());
+    }
+
+    // Fills `recording` with what arrives from now on. `wait` lets time pass
+    // between looks at the input; the input counts as stopped when a lot more
+    // time passes than the recording is long.
+    pub fn record(&mut self, recording: &mut [f32], wait: impl FnMut(Duration)) -> anyhow::Result<()> {
+        record(&mut self.consumer, recording, self.sample_rate, wait)
+    }
+}
+
+// Fills `recording` from the consumer of a running input, as `Capture::record`
+// does, for inputs that keep more than the samples
+pub(crate) fn record(
+    consumer: &mut Consumer,
+    recording: &mut [f32],
+    sample_rate: u32,
+    mut wait: impl FnMut(Duration),
+) -> anyhow::Result<()> {
+    let limit = Duration::from_secs_f64(recording.len() as f64 / sample_rate as f64) + STALL_MARGIN;
+    let mut waited = Duration::ZERO;
+    let mut captured = 0;
+    while captured < recording.len() {
+        anyhow::ensure!(waited < limit, "The input stopped delivering audio");
+        wait(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+        let count = consumer.len().min(recording.len() - captured);
+        captured += consumer.pop_slice(&mut recording[captured..captured + count]);
+    }
+    Ok(())
+}
+
+// Plays a steady tone at `level` on every channel of the output, for
+// measuring what arrives at an input without triggering anything
+pub fn play_tone<B: AudioBackend>(
+    device: &B,
+    config: &cpal::StreamConfig,
+    frequency: f32,
+    level: f32,
+) -> anyhow::Result<B::Stream> {
+    let mut player = TonePlayer::with_config(
+        TonePlayerConfigBuilder::default()
+            .frequency(frequency)
+            .sample_rate(config.sample_rate.0)
+            .channels(config.channels as usize)
+            .factor(level)
+            .build()?,
+    );
+    let stream = device.build_output(
+        config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| player.fill_buffer(data),
+        move |err| {
+            eprintln!("An error occurred on the output stream: {}", err);
+        },
+    )?;
+    stream.play()?;
+    Ok(stream)
+}
+
+// Plays a test signal on an output each time a measurement starts and
+// records the first channel of an input, for measuring whatever lies between
+// them, be it a cable, an interface or a room
+pub struct Loopback<S> {
+    trigger: Arc<AtomicBool>,
+    level: f32,
+    capture: Capture<S>,
+    _output: S,
+}
+
+impl<S: StreamTrait> Loopback<S> {
+    // `frames` is the length of the longest recording
+    pub fn start<B>(
+        output: &B,
+        output_config: &cpal::StreamConfig,
+        input: &B,
+        input_config: &cpal::StreamConfig,
+        signal: Vec<f32>,
+        level: f32,
+        frames: usize,
+    ) -> anyhow::Result<Self>
+    where
+        B: AudioBackend<Stream = S>,
+    {
+        common_rate(output_config, input_config)?;
+
+        let trigger = Arc::new(AtomicBool::new(false));
+        let mut generator = Generator::with_config(
+            GeneratorConfigBuilder::default()
+                .channels(output_config.channels as usize)
+                .factor(level)
+                .build()?,
+            Sequence::new(signal),
+        );
+        let output_trigger = trigger.clone();
+        let output_stream = output.build_output(
+            output_config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                if output_trigger.swap(false, Ordering::AcqRel) {
+                    generator.signal_mut().rewind();
+                }
+                generator.fill_buffer(data);
+            },
+            move |err| {
+                eprintln!("An error occurred on the output stream: {}", err);
+            },
+        )?;
+
+        let capture = Capture::start(input, input_config, 0, frames)?;
+        output_stream.play()?;
+        Ok(Self { trigger, level, capture, _output: output_stream })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.capture.sample_rate()
+    }
+
+    // Plays the signal from its start and records `frames` frames from then
+    // on, relative to the signal as played rather than to full scale
+    pub fn measure(&mut self, frames: usize, wait: impl FnMut(Duration)) -> anyhow::Result<Vec<f32>> {
+        self.capture.discard();
+        self.trigger.store(true, Ordering::Release);
+        let mut recording = vec![0.0; frames];
+        self.capture.record(&mut recording, wait)?;
+        recording.iter_mut().for_each(|sample| *sample /= self.level);
+        Ok(recording)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_audio::{VirtualClock, VirtualDevice, VirtualDeviceConfigBuilder};
+
+    fn config(channels: u16, sample_rate: u32) -> cpal::StreamConfig {
+        cpal::StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let clock = VirtualClock::new();
+        let output = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(256).build().unwrap());
+        let input = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(128).build().unwrap());
+        input.set_source(output.cable(2));
+        assert!(Loopback::start(&output, &config(2, 48000), &input, &config(1, 44100), vec![1.0], 0.5, 4800).is_err());
+        assert!(Capture::start(&input, &config(1, 48000), 1, 4800).is_err());
+
+        let mut loopback =
+            Loopback::start(&output, &config(2, 48000), &input, &config(1, 48000), vec![1.0, -1.0], 0.5, 4800).unwrap();
+        clock.advance(SETTLE);
+        for _ in 0..2 {
+            let recording = loopback.measure(4800, |duration| clock.advance(duration)).unwrap();
+            // The click arrives within an output buffer and an input buffer
+            let arrival = recording.iter().position(|&sample| sample != 0.0).unwrap();
+            assert!(arrival < 256 + 128, "arrived after {arrival} frames");
+            assert_eq!(recording[arrival..arrival + 2], [1.0, -1.0]);
+            assert!(recording[arrival + 2..].iter().all(|&sample| sample == 0.0));
+        }
+
+        input.disconnect();
+        assert!(loopback.measure(4800, |duration| clock.advance(duration)).is_err());
+    }
+}
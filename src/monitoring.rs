@@ -0,0 +1,82 @@
+use crate::aggregator::Aggregator;
+use crate::bridge::{Bridge, BridgeConfigBuilder};
+use crate::dynamics::Dynamics;
+use crate::processor::{Chain, Processor};
+use crate::ring_buffer::Producer;
+use crate::timing::TimingMonitor;
+use crate::virtual_audio::AudioBackend;
+
+// Frames the noise gate works on at a time
+const GATE_BLOCK_FRAMES: usize = 1024;
+
+// A bridge for an input played live on an output, holding 10ms of input in
+// reserve to absorb callback jitter
+pub fn input_bridge(input_rate: u32, output_rate: u32, channels: usize) -> anyhow::Result<(Bridge, Producer)> {
+    Bridge::with_config(
+        BridgeConfigBuilder::default()
+            .input_rate(input_rate)
+            .output_rate(output_rate)
+            .channels(channels)
+            .target_fill(input_rate as usize / 100)
+            .build()?,
+    )
+}
+
+// An input stream into its bridge, through the gate if there is one. The
+// stream is not started, so that all inputs can start together.
+pub fn input_stream<B: AudioBackend>(
+    device: &B,
+    config: &cpal::StreamConfig,
+    mut producer: Producer,
+    mut gate: Option<Dynamics>,
+    mut monitor: TimingMonitor,
+    index: usize,
+) -> anyhow::Result<B::Stream> {
+    // Gated audio is worked on in a copy, a block at a time, sized up front
+    // so that the callback does not allocate
+    let mut scratch = vec![0.0; config.channels as usize * GATE_BLOCK_FRAMES];
+    device.build_input(
+        config,
+        move |data: &[f32], info: &cpal::InputCallbackInfo| {
+            monitor.record_input(info, data.len());
+            match &mut gate {
+                Some(gate) => {
+                    for block in data.chunks(scratch.len()) {
+                        let scratch = &mut scratch[..block.len()];
+                        scratch.copy_from_slice(block);
+                        gate.process(scratch);
+                        producer.push_slice(scratch);
+                    }
+                }
+                None => {
+                    producer.push_slice(data);
+                }
+            }
+        },
+        move |err| {
+            eprintln!("Error on input stream {index}: {}", err);
+        },
+    )
+}
+
+// An output stream playing the mixed inputs through the processors. Like the
+// inputs it is not started.
+pub fn output_stream<B: AudioBackend>(
+    device: &B,
+    config: &cpal::StreamConfig,
+    mut aggregator: Aggregator,
+    mut processors: Chain,
+    mut monitor: TimingMonitor,
+) -> anyhow::Result<B::Stream> {
+    device.build_output(
+        config,
+        move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+            monitor.record_output(info, data.len());
+            aggregator.read(data);
+            processors.process(data);
+        },
+        move |err| {
+            eprintln!("Error on output stream: {}", err);
+        },
+    )
+}
@@ -0,0 +1,436 @@
+use derive_builder::Builder;
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::biquad::Coefficients;
+
+// Octave ratio of IEC 61260-1, base ten
+const OCTAVE_RATIO: f64 = 1.9952623149688795;
+// Renard R10 series, the nominal third-octave mid-frequencies of a decade in
+// hundredths
+const NOMINAL_MANTISSAS: [u32; 10] = [100, 125, 160, 200, 250, 315, 400, 500, 630, 800];
+// Order of the Butterworth low-pass prototype of every band filter, which
+// keeps third-octave bands within class 1 of IEC 61260-1
+const PROTOTYPE_ORDER: usize = 3;
+// Pole frequencies in Hz of the A and C weightings in IEC 61672-1
+const WEIGHTING_POLES: [f64; 4] = [20.598997, 107.65265, 737.86223, 12194.217];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bandwidth {
+    Octave,
+    ThirdOctave,
+}
+
+impl FromStr for Bandwidth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "octave" | "1" | "1/1" => Ok(Bandwidth::Octave),
+            "third" | "third-octave" | "3" | "1/3" => Ok(Bandwidth::ThirdOctave),
+            _ => anyhow::bail!("Unknown bandwidth '{s}', expected octave or third"),
+        }
+    }
+}
+
+impl Bandwidth {
+    // Bands per octave
+    fn fraction(self) -> i32 {
+        match self {
+            Bandwidth::Octave => 1,
+            Bandwidth::ThirdOctave => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Weighting {
+    A,
+    C,
+    // Flat
+    Z,
+}
+
+impl FromStr for Weighting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "a" => Ok(Weighting::A),
+            "c" => Ok(Weighting::C),
+            "z" | "flat" => Ok(Weighting::Z),
+            _ => anyhow::bail!("Unknown weighting '{s}', expected A, C or Z"),
+        }
+    }
+}
+
+impl Weighting {
+    // Gain of the weighting at `frequency`, 0 dB at 1 kHz
+    pub fn gain_db(self, frequency: f64) -> f64 {
+        let [f1, f2, f3, f4] = WEIGHTING_POLES.map(|pole| pole * pole);
+        let response = |f: f64| {
+            let f = f * f;
+            match self {
+                Weighting::A => f4 * f * f / ((f + f1) * ((f + f2) * (f + f3)).sqrt() * (f + f4)),
+                Weighting::C => f4 * f / ((f + f1) * (f + f4)),
+                Weighting::Z => 1.0,
+            }
+        };
+        20.0 * (response(frequency) / response(1000.0)).log10()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OctaveBand {
+    // Exact mid-frequency and band edges
+    pub centre: f64,
+    pub lower: f64,
+    pub upper: f64,
+    // Preferred frequency the band goes by, e.g. 31.5 for 31.62 Hz
+    pub nominal: f64,
+}
+
+impl OctaveBand {
+    // The nominal frequency, in kHz from 1 kHz up, e.g. 31.5 or 1.25k
+    pub fn label(&self) -> String {
+        if self.nominal >= 1000.0 {
+            format!("{}k", self.nominal / 1000.0)
+        } else {
+            format!("{}", self.nominal)
+        }
+    }
+}
+
+// The bands whose nominal frequencies lie between `from` and `to` and whose
+// upper edges lie below the Nyquist frequency
+pub fn octave_bands(bandwidth: Bandwidth, from: f64, to: f64, sample_rate: u32) -> Vec<OctaveBand> {
+    let fraction = bandwidth.fraction();
+    let half_band = OCTAVE_RATIO.powf(0.5 / fraction as f64);
+    // Counted in third octaves from 1 kHz, so that octaves take every third
+    let step = 3 / fraction;
+    (-30..=20)
+        .filter(|index| index % step == 0)
+        .map(|index: i32| {
+            let centre = 1000.0 * 10f64.powf(index as f64 / 10.0);
+            let mantissa = NOMINAL_MANTISSAS[index.rem_euclid(10) as usize] as f64;
+            let exponent = index.div_euclid(10) + 1;
+            let nominal = if exponent >= 0 { mantissa * 10f64.powi(exponent) } else { mantissa / 10f64.powi(-exponent) };
+            OctaveBand { centre, lower: centre / half_band, upper: centre * half_band, nominal }
+        })
+        .filter(|band| from <= band.nominal && band.nominal <= to && band.upper < sample_rate as f64 / 2.0)
+        .collect()
+}
+
+#[derive(Builder)]
+pub struct FilterBankConfig {
+    bandwidth: Bandwidth,
+    sample_rate: u32,
+    // Interleaved channels, mixed to mono before filtering
+    #[builder(default = "1")]
+    channels: usize,
+    #[builder(default = "20.0")]
+    from: f64,
+    #[builder(default = "20000.0")]
+    to: f64,
+    #[builder(default = "Weighting::Z")]
+    weighting: Weighting,
+    // Exponential averaging of the band levels; 125 ms is "fast" and 1 s
+    // "slow" in sound level meter terms
+    #[builder(default = "Duration::from_millis(125)")]
+    time_constant: Duration,
+}
+
+// Second order sections in series, each with its own transposed direct
+// form II state
+struct Cascade {
+    sections: Vec<(Coefficients, [f64; 2])>,
+}
+
+impl Cascade {
+    fn new(sections: Vec<Coefficients>) -> Self {
+        Self { sections: sections.into_iter().map(|coefficients| (coefficients, [0.0; 2])).collect() }
+    }
+
+    fn process(&mut self, mut x: f64) -> f64 {
+        for (c, state) in &mut self.sections {
+            let y = c.b0 * x + state[0];
+            state[0] = c.b1 * x - c.a1 * y + state[1];
+            state[1] = c.b2 * x - c.a2 * y;
+            x = y;
+        }
+        x
+    }
+
+    fn reset(&mut self) {
+        self.sections.iter_mut().for_each(|(_, state)| *state = [0.0; 2]);
+    }
+}
+
+struct BandFilter {
+    cascade: Cascade,
+    mean_square: f64,
+}
+
+// Octave or third-octave filter bank in the manner of IEC 61260-1: every
+// band is a Butterworth band-pass between the band edges, and its level the
+// exponentially averaged power of its output. The input goes through the
+// frequency weighting filter first, so that the band levels and their total
+// are weighted levels as a sound level meter measures them.
+pub struct FilterBank {
+    config: FilterBankConfig,
+    bands: Vec<OctaveBand>,
+    weighting: Cascade,
+    filters: Vec<BandFilter>,
+    smoothing: f64,
+}
+
+impl FilterBank {
+    pub fn with_config(config: FilterBankConfig) -> Self {
+        let bands = octave_bands(config.bandwidth, config.from, config.to, config.sample_rate);
+        let filters = bands
+            .iter()
+            .map(|band| BandFilter {
+                cascade: Cascade::new(band_pass(band.lower, band.upper, config.sample_rate)),
+                mean_square: 0.0,
+            })
+            .collect();
+        let weighting = Cascade::new(weighting_filter(config.weighting, config.sample_rate));
+        let frames = config.time_constant.as_secs_f64() * config.sample_rate as f64;
+        let smoothing = if frames > 0.0 { 1.0 - (-1.0 / frames).exp() } else { 1.0 };
+        Self { config, bands, weighting, filters, smoothing }
+    }
+
+    pub fn bands(&self) -> &[OctaveBand] {
+        &self.bands
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        self.config.bandwidth
+    }
+
+    pub fn weighting(&self) -> Weighting {
+        self.config.weighting
+    }
+
+    // The band levels follow the new weighting within the time constant
+    pub fn set_weighting(&mut self, weighting: Weighting) {
+        self.config.weighting = weighting;
+        self.weighting = Cascade::new(weighting_filter(weighting, self.config.sample_rate));
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        let channels = self.config.channels.max(1);
+        for frame in samples.chunks(channels) {
+            let mono = frame.iter().map(|&sample| sample as f64).sum::<f64>() / frame.len() as f64;
+            let weighted = self.weighting.process(mono);
+            for filter in &mut self.filters {
+                let y = filter.cascade.process(weighted);
+                filter.mean_square += (y * y - filter.mean_square) * self.smoothing;
+            }
+        }
+    }
+
+    // Weighted RMS level of every band in dBFS
+    pub fn levels_db(&self) -> Vec<f64> {
+        self.filters.iter().map(|filter| 10.0 * filter.mean_square.max(1e-20).log10()).collect()
+    }
+
+    // Weighted level of all bands together in dBFS
+    pub fn total_db(&self) -> f64 {
+        let power = self.levels_db().iter().map(|level| 10f64.powf(level / 10.0)).sum::<f64>();
+        10.0 * power.max(1e-20).log10()
+    }
+
+    pub fn reset(&mut self) {
+        self.weighting.reset();
+        for filter in &mut self.filters {
+            filter.cascade.reset();
+            filter.mean_square = 0.0;
+        }
+    }
+}
+
+// Butterworth band-pass from `lower` to `upper` Hz: the low-pass prototype's
+// poles moved onto the band, each conjugate pair with a zero at DC and one
+// at infinity, then through the bilinear transform with both edges
+// prewarped. Scaled to unity gain at the geometric mid-frequency.
+fn band_pass(lower: f64, upper: f64, sample_rate: u32) -> Vec<Coefficients> {
+    let k = 2.0 * sample_rate as f64;
+    let warp = |frequency: f64| k * (PI * frequency / sample_rate as f64).tan();
+    let (low, high) = (warp(lower), warp(upper));
+    let centre_squared = low * high;
+    let bandwidth = high - low;
+
+    let mut sections: Vec<Coefficients> = (0..PROTOTYPE_ORDER)
+        .flat_map(|index| {
+            // Prototype poles in the upper half plane, plus the real one
+            let angle = PI * (2 * index + PROTOTYPE_ORDER + 1) as f64 / (2 * PROTOTYPE_ORDER) as f64;
+            let (re, im) = (angle.cos(), angle.sin());
+            if im < -1e-9 {
+                return Vec::new();
+            }
+            // Roots of s^2 - p * B * s + w0^2 for the prototype pole p
+            let (pr, pi) = (re * bandwidth / 2.0, im * bandwidth / 2.0);
+            let (dr, di) = (pr * pr - pi * pi - centre_squared, 2.0 * pr * pi);
+            let magnitude = (dr * dr + di * di).sqrt();
+            let root_re = ((magnitude + dr) / 2.0).sqrt();
+            let root_im = ((magnitude - dr) / 2.0).sqrt().copysign(di);
+            let poles = if im.abs() < 1e-9 {
+                // A real prototype pole gives one conjugate pair
+                vec![(pr, (centre_squared - pr * pr).max(0.0).sqrt())]
+            } else {
+                vec![(pr + root_re, pi + root_im), (pr - root_re, pi - root_im)]
+            };
+            poles
+                .into_iter()
+                // s / (s^2 - 2 re s + |p|^2)
+                .map(|(re, im)| bilinear([0.0, 1.0, 0.0], [-2.0 * re, re * re + im * im], k))
+                .collect()
+        })
+        .collect();
+
+    normalize(&mut sections, (lower * upper).sqrt(), sample_rate);
+    sections
+}
+
+// The A or C weighting of IEC 61672-1 from its analogue poles, each
+// prewarped so that it keeps its frequency through the bilinear transform,
+// with 0 dB at 1 kHz. Empty for Z.
+fn weighting_filter(weighting: Weighting, sample_rate: u32) -> Vec<Coefficients> {
+    let k = 2.0 * sample_rate as f64;
+    // Poles past Nyquist, at low sample rates, are kept just below it
+    let warp = |pole: f64| k * (PI * pole.min(0.49 * sample_rate as f64) / sample_rate as f64).tan();
+    let [w1, w2, w3, w4] = WEIGHTING_POLES.map(warp);
+    // s^2 / (s + w1)^2, the roll-off at the bottom
+    let low = bilinear([1.0, 0.0, 0.0], [2.0 * w1, w1 * w1], k);
+    // w4^2 / (s + w4)^2, the roll-off at the top
+    let high = bilinear([0.0, 0.0, w4 * w4], [2.0 * w4, w4 * w4], k);
+    let mut sections = match weighting {
+        // s^2 / ((s + w2)(s + w3)) in between
+        Weighting::A => vec![low, bilinear([1.0, 0.0, 0.0], [w2 + w3, w2 * w3], k), high],
+        Weighting::C => vec![low, high],
+        Weighting::Z => return Vec::new(),
+    };
+    normalize(&mut sections, 1000.0, sample_rate);
+    sections
+}
+
+// (n2 s^2 + n1 s + n0) / (s^2 + d1 s + d0) through the bilinear transform
+// with s = k (1 - 1/z) / (1 + 1/z)
+fn bilinear([n2, n1, n0]: [f64; 3], [d1, d0]: [f64; 2], k: f64) -> Coefficients {
+    let denominator = k * k + d1 * k + d0;
+    Coefficients {
+        b0: (n2 * k * k + n1 * k + n0) / denominator,
+        b1: (2.0 * n0 - 2.0 * n2 * k * k) / denominator,
+        b2: (n2 * k * k - n1 * k + n0) / denominator,
+        a1: (2.0 * d0 - 2.0 * k * k) / denominator,
+        a2: (k * k - d1 * k + d0) / denominator,
+    }
+}
+
+// Scales the first section so that the cascade has unity gain at `frequency`
+fn normalize(sections: &mut [Coefficients], frequency: f64, sample_rate: u32) {
+    let gain: f64 = sections.iter().map(|section| section.magnitude(frequency, sample_rate)).product();
+    if let Some(first) = sections.first_mut() {
+        first.b0 /= gain;
+        first.b1 /= gain;
+        first.b2 /= gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(bandwidth: Bandwidth, weighting: Weighting) -> FilterBank {
+        FilterBank::with_config(
+            FilterBankConfigBuilder::default()
+                .bandwidth(bandwidth)
+                .sample_rate(48000)
+                .weighting(weighting)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn tone(frequency: f64) -> Vec<f32> {
+        (0..48000).map(|n| (2.0 * PI * frequency * n as f64 / 48000.0).sin() as f32).collect()
+    }
+
+    #[test]
+    fn test_band_levels() {
+        let thirds = octave_bands(Bandwidth::ThirdOctave, 20.0, 20000.0, 48000);
+        assert_eq!(thirds.len(), 31);
+        let labels: Vec<String> = thirds.iter().map(OctaveBand::label).collect();
+        assert_eq!((labels[0].as_str(), labels[2].as_str(), labels[17].as_str()), ("20", "31.5", "1k"));
+        assert_eq!((labels[18].as_str(), labels[28].as_str(), labels[30].as_str()), ("1.25k", "12.5k", "20k"));
+        let octaves = octave_bands(Bandwidth::Octave, 20.0, 20000.0, 48000);
+        assert_eq!(octaves.iter().map(|band| band.nominal).collect::<Vec<_>>(), vec![
+            31.5, 63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0
+        ]);
+        // The 20 kHz band would reach past Nyquist at 44.1 kHz
+        assert_eq!(octave_bands(Bandwidth::ThirdOctave, 20.0, 20000.0, 44100).len(), 30);
+
+        // Every band passes its middle and is 3 dB down at its edges
+        let filters = bank(Bandwidth::ThirdOctave, Weighting::Z);
+        for (band, filter) in filters.bands.iter().zip(&filters.filters) {
+            let gain = |frequency: f64| -> f64 {
+                let magnitude: f64 = filter.cascade.sections.iter().map(|(c, _)| c.magnitude(frequency, 48000)).product();
+                20.0 * magnitude.log10()
+            };
+            assert!(gain(band.centre).abs() < 1e-6, "{band:?}");
+            assert!((gain(band.lower) + 3.01).abs() < 0.05, "{band:?} {}", gain(band.lower));
+            assert!((gain(band.upper) + 3.01).abs() < 0.05, "{band:?} {}", gain(band.upper));
+            assert!(band.centre * 2.0 > 24000.0 || gain(band.centre * 2.0) < -30.0, "{band:?}");
+        }
+
+        // A full-scale 1 kHz sine lands in the 1 kHz band only
+        let mut filters = bank(Bandwidth::ThirdOctave, Weighting::Z);
+        filters.process(&tone(1000.0));
+        let levels = filters.levels_db();
+        assert!((levels[17] + 3.01).abs() < 0.1, "{levels:?}");
+        assert!(levels[16] < -20.0 && levels[18] < -20.0, "{levels:?}");
+        // The skirts of the neighbouring bands add a little to the total
+        assert!((filters.total_db() + 3.01).abs() < 0.2, "{}", filters.total_db());
+
+        // Weightings, against the tables of IEC 61672-1
+        // at the exact mid-frequencies of the 31.5, 100, 1k and 8k bands
+        for (index, a, c) in [(-15, -39.4, -3.0), (-10, -19.1, -0.3), (0, 0.0, 0.0), (9, -1.1, -3.0)] {
+            let frequency = 1000.0 * 10f64.powf(index as f64 / 10.0);
+            assert!((Weighting::A.gain_db(frequency) - a).abs() < 0.1, "A at {frequency}");
+            assert!((Weighting::C.gain_db(frequency) - c).abs() < 0.1, "C at {frequency}");
+            assert_eq!(Weighting::Z.gain_db(frequency), 0.0);
+        }
+        // The weighting filters follow the curves closely up to a few kHz
+        for weighting in [Weighting::A, Weighting::C] {
+            let filter = weighting_filter(weighting, 48000);
+            for frequency in [20.0, 31.5, 125.0, 1000.0, 2000.0] {
+                let magnitude: f64 = filter.iter().map(|c| c.magnitude(frequency, 48000)).product();
+                let error = 20.0 * magnitude.log10() - weighting.gain_db(frequency);
+                assert!(error.abs() < 0.1, "{weighting:?} at {frequency}: {error}");
+            }
+        }
+        // A 125 Hz tone is turned down by the A weighting in its band and in
+        // the total alike
+        let mut filters = bank(Bandwidth::Octave, Weighting::A);
+        filters.process(&tone(125.0));
+        assert!((filters.levels_db()[2] + 3.01 + 16.2).abs() < 0.2, "{:?}", filters.levels_db());
+        assert!((filters.total_db() + 3.01 + 16.2).abs() < 0.2, "{}", filters.total_db());
+        filters.set_weighting(Weighting::Z);
+        filters.reset();
+        assert!(filters.total_db() < -150.0);
+        filters.process(&tone(125.0));
+        assert!((filters.levels_db()[2] + 3.01).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!("1/3".parse::<Bandwidth>().unwrap(), Bandwidth::ThirdOctave);
+        assert_eq!("Octave".parse::<Bandwidth>().unwrap(), Bandwidth::Octave);
+        assert!("1/6".parse::<Bandwidth>().is_err());
+        assert_eq!("a".parse::<Weighting>().unwrap(), Weighting::A);
+        assert_eq!("Z".parse::<Weighting>().unwrap(), Weighting::Z);
+        assert!("b".parse::<Weighting>().is_err());
+    }
+}
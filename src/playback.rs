@@ -0,0 +1,104 @@
+use cpal::traits::StreamTrait;
+use cpal::{FromSample, SizedSample};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::cli::StepSpec;
+use crate::generator::{Generator, GeneratorConfigBuilder, Oscillator, ToneSequence};
+use crate::timing::{TimingMonitor, TimingSnapshot};
+use crate::virtual_audio::AudioBackend;
+
+// How often playback looks for the end of the sequence
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Allows for a slow start, but not for a stream that stopped calling back
+const START_MARGIN: Duration = Duration::from_secs(2);
+
+// The steps over and over, the last one cut short at `total`
+pub fn tone_sequence(
+    steps: &[StepSpec],
+    step_duration: Duration,
+    total: Duration,
+    sample_rate: u32,
+) -> anyhow::Result<ToneSequence> {
+    let durations: Vec<Duration> = steps.iter().map(|step| step.duration.unwrap_or(step_duration)).collect();
+    anyhow::ensure!(
+        total.is_zero() || durations.iter().any(|duration| !duration.is_zero()),
+        "The steps take no time"
+    );
+
+    let mut sequence = ToneSequence::new(sample_rate);
+    let mut elapsed = Duration::ZERO;
+    for (step, &duration) in steps.iter().zip(&durations).cycle() {
+        if elapsed >= total {
+            break;
+        }
+        let oscillators = step
+            .tones
+            .iter()
+            .map(|tone| Oscillator::new(tone.waveform, tone.frequency, tone.amplitude, sample_rate))
+            .collect();
+        let duration = duration.min(total - elapsed);
+        sequence.push(oscillators, duration);
+        elapsed += duration;
+    }
+    Ok(sequence)
+}
+
+// Plays `sequence` once on the device and returns when the device has played
+// it out, with the output's callback timing. `wait` lets time pass while the
+// sequence plays.
+pub fn play_sequence<B, T>(
+    device: &B,
+    config: &cpal::StreamConfig,
+    sequence: ToneSequence,
+    mut wait: impl FnMut(Duration),
+) -> anyhow::Result<TimingSnapshot>
+where
+    B: AudioBackend,
+    T: SizedSample + FromSample<f32> + std::ops::AddAssign + Send + 'static,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let sample_rate = config.sample_rate.0;
+    let length = Duration::from_secs_f64(sequence.len() as f64 / sample_rate as f64);
+    let mut generator = Generator::with_config(GeneratorConfigBuilder::default().channels(channels).build()?, sequence);
+
+    let mut monitor = TimingMonitor::new(sample_rate, channels);
+    let timing = monitor.handle();
+
+    // The first callback after the sequence has ended reports how long the
+    // device still needs to play out what it was given
+    let (finished_tx, finished) = mpsc::sync_channel(1);
+    let mut reported = false;
+
+    let stream = device.build_output(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            monitor.record_output(info, data.len());
+            let done = generator.signal().is_finished();
+            generator.fill_buffer(data);
+            if done && !reported {
+                let timestamp = info.timestamp();
+                let latency = timestamp.playback.duration_since(&timestamp.callback).unwrap_or_default();
+                let buffer = Duration::from_secs_f64((data.len() / channels) as f64 / sample_rate as f64);
+                let _ = finished_tx.try_send(latency + buffer);
+                reported = true;
+            }
+        },
+        |err| eprintln!("an error occurred on stream: {err}"),
+    )?;
+    stream.play()?;
+
+    let mut waited = Duration::ZERO;
+    let drain = loop {
+        if let Ok(drain) = finished.try_recv() {
+            break drain;
+        }
+        anyhow::ensure!(waited < length + START_MARGIN, "Playback did not finish");
+        wait(POLL_INTERVAL);
+        waited += POLL_INTERVAL;
+    };
+    wait(drain);
+
+    Ok(timing.snapshot())
+}
@@ -0,0 +1,42 @@
+use crate::virtual_audio::AudioBackend;
+
+// Samples of every block that are shown
+const PREVIEW_SAMPLES: usize = 20;
+
+// The size of a captured block and its first few samples
+pub fn describe_block(data: &[f32]) -> String {
+    let shown = &data[..data.len().min(PREVIEW_SAMPLES)];
+    format!("Received {} samples: {:?}", data.len(), shown)
+}
+
+// An input stream handing the description of every captured block to
+// `report`. The stream is not started.
+pub fn input_stream<B: AudioBackend>(
+    device: &B,
+    config: &cpal::StreamConfig,
+    mut report: impl FnMut(String) + Send + 'static,
+) -> anyhow::Result<B::Stream> {
+    device.build_input(
+        config,
+        move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            report(describe_block(data));
+        },
+        move |err| {
+            eprintln!("An error occurred on the input stream: {}", err);
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_block() {
+        assert_eq!(describe_block(&[0.5, -0.25]), "Received 2 samples: [0.5, -0.25]");
+        assert_eq!(describe_block(&[]), "Received 0 samples: []");
+        let long: Vec<f32> = (0..30).map(|i| i as f32).collect();
+        assert!(describe_block(&long).starts_with("Received 30 samples: [0.0, 1.0,"));
+        assert!(describe_block(&long).ends_with("18.0, 19.0]"));
+    }
+}
@@ -0,0 +1,417 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::device::Direction;
+use crate::inventory::{format_table, BufferSizeReport};
+use crate::timing::TimingMonitor;
+
+// Relative difference between the configured and measured sample rate
+// above which a configuration is reported as running at the wrong rate
+const RATE_TOLERANCE: f64 = 0.02;
+
+// What was asked of the device
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProbeRequest {
+    pub direction: Direction,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_format: String,
+    pub advertised_buffer_size: BufferSizeReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_buffer_size: Option<u32>,
+}
+
+// What the stream actually did while it ran
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Observation {
+    pub callbacks: u64,
+    pub min_callback_frames: usize,
+    pub max_callback_frames: usize,
+    pub mean_period_ms: f64,
+    pub jitter_ms: f64,
+    pub measured_sample_rate: f64,
+    pub missed_callbacks: u64,
+    pub stream_errors: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProbeResult {
+    pub request: ProbeRequest,
+    // Empty when the stream could not be built or started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observation: Option<Observation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub discrepancies: Vec<String>,
+}
+
+impl ProbeResult {
+    pub fn works(&self) -> bool {
+        self.error.is_none() && self.discrepancies.is_empty()
+    }
+}
+
+// Probe results for every device of every host, in the same shape as the
+// inventory so that it can be printed the same ways
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProbeReport {
+    pub devices: Vec<DeviceProbe>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DeviceProbe {
+    pub host: String,
+    pub device: String,
+    pub results: Vec<ProbeResult>,
+}
+
+impl ProbeReport {
+    // Probes the devices whose name contains `filter`, or all of them, with
+    // fixed buffers of `buffer_size` frames if given
+    pub fn collect(filter: Option<&str>, buffer_size: Option<u32>, duration: Duration) -> Self {
+        let mut devices = Vec::new();
+        for host_id in cpal::available_hosts() {
+            let Ok(host) = cpal::host_from_id(host_id) else {
+                continue;
+            };
+            let Ok(host_devices) = host.devices() else {
+                continue;
+            };
+            for device in host_devices {
+                let name = device.name().unwrap_or_else(|_| "<unknown>".to_string());
+                if filter.is_some_and(|filter| !name.contains(filter)) {
+                    continue;
+                }
+                devices.push(DeviceProbe {
+                    host: host_id.name().to_string(),
+                    device: name,
+                    results: probe_device(&device, buffer_size, duration),
+                });
+            }
+        }
+        Self { devices }
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    // One whitespace-aligned row per probed configuration
+    pub fn to_table(&self) -> String {
+        let mut rows = vec![
+            ["HOST", "DEVICE", "DIRECTION", "CHANNELS", "RATE", "FORMAT", "BUFFER", "REQUESTED", "CALLBACK", "MEASURED", "RESULT"]
+                .map(String::from)
+                .to_vec(),
+        ];
+
+        for device in &self.devices {
+            for result in &device.results {
+                let request = &result.request;
+                let (callback, measured) = match &result.observation {
+                    Some(observation) => (
+                        format!("{}-{}", observation.min_callback_frames, observation.max_callback_frames),
+                        format!("{:.0}", observation.measured_sample_rate),
+                    ),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                let outcome = match (&result.error, result.discrepancies.is_empty()) {
+                    (Some(error), _) => format!("failed: {error}"),
+                    (None, true) => "ok".to_string(),
+                    (None, false) => result.discrepancies.join("; "),
+                };
+                rows.push(vec![
+                    device.host.clone(),
+                    device.device.clone(),
+                    request.direction.to_string(),
+                    request.channels.to_string(),
+                    request.sample_rate.to_string(),
+                    request.sample_format.clone(),
+                    request.advertised_buffer_size.to_string(),
+                    request.requested_buffer_size.map_or("-".to_string(), |frames| frames.to_string()),
+                    callback,
+                    measured,
+                    outcome,
+                ]);
+            }
+        }
+
+        format_table(&rows)
+    }
+}
+
+// Probes the default configurations of a device and every advertised range
+// at its lowest and highest sample rate
+pub fn probe_device(device: &cpal::Device, buffer_size: Option<u32>, duration: Duration) -> Vec<ProbeResult> {
+    let mut configs = Vec::new();
+    if let Ok(config) = device.default_input_config() {
+        configs.push((Direction::Input, config));
+    }
+    if let Ok(ranges) = device.supported_input_configs() {
+        for range in ranges {
+            configs.push((Direction::Input, range.with_sample_rate(range.min_sample_rate())));
+            configs.push((Direction::Input, range.with_max_sample_rate()));
+        }
+    }
+    if let Ok(config) = device.default_output_config() {
+        configs.push((Direction::Output, config));
+    }
+    if let Ok(ranges) = device.supported_output_configs() {
+        for range in ranges {
+            configs.push((Direction::Output, range.with_sample_rate(range.min_sample_rate())));
+            configs.push((Direction::Output, range.with_max_sample_rate()));
+        }
+    }
+
+    let mut results: Vec<ProbeResult> = Vec::new();
+    for (direction, config) in configs {
+        let duplicate = results.iter().any(|result| {
+            result.request.direction == direction
+                && result.request.channels == config.channels()
+                && result.request.sample_rate == config.sample_rate().0
+                && result.request.sample_format == config.sample_format().to_string()
+        });
+        if !duplicate {
+            results.push(probe(device, direction, &config, buffer_size, duration));
+        }
+    }
+    results
+}
+
+// Opens, starts and runs a stream with the given configuration for
+// `duration`, feeding silence to outputs and discarding input
+pub fn probe(
+    device: &cpal::Device,
+    direction: Direction,
+    config: &cpal::SupportedStreamConfig,
+    requested_buffer_size: Option<u32>,
+    duration: Duration,
+) -> ProbeResult {
+    let request = ProbeRequest {
+        direction,
+        channels: config.channels(),
+        sample_rate: config.sample_rate().0,
+        sample_format: config.sample_format().to_string(),
+        advertised_buffer_size: config.buffer_size().into(),
+        requested_buffer_size,
+    };
+
+    match run_stream(device, direction, config, requested_buffer_size, duration) {
+        Ok(observation) => {
+            let discrepancies = find_discrepancies(&request, &observation);
+            ProbeResult {
+                request,
+                observation: Some(observation),
+                error: None,
+                discrepancies,
+            }
+        }
+        Err(err) => ProbeResult {
+            request,
+            observation: None,
+            error: Some(format!("{err:#}")),
+            discrepancies: vec!["advertised configuration could not be opened".to_string()],
+        },
+    }
+}
+
+fn run_stream(
+    device: &cpal::Device,
+    direction: Direction,
+    config: &cpal::SupportedStreamConfig,
+    requested_buffer_size: Option<u32>,
+    duration: Duration,
+) -> anyhow::Result<Observation> {
+    let mut stream_config = config.config();
+    if let Some(frames) = requested_buffer_size {
+        stream_config.buffer_size = cpal::BufferSize::Fixed(frames);
+    }
+    let channels = config.channels() as usize;
+    let mut monitor = TimingMonitor::new(config.sample_rate().0, channels);
+    let timing = monitor.handle();
+    let min_frames = Arc::new(AtomicUsize::new(usize::MAX));
+    let max_frames = Arc::new(AtomicUsize::new(0));
+    let (errors_tx, errors_rx) = std::sync::mpsc::channel();
+
+    let (callback_min, callback_max) = (min_frames.clone(), max_frames.clone());
+    let record_frames = move |samples: usize| {
+        callback_min.fetch_min(samples / channels, Ordering::Relaxed);
+        callback_max.fetch_max(samples / channels, Ordering::Relaxed);
+    };
+    let error_callback = move |err: cpal::StreamError| {
+        let _ = errors_tx.send(err.to_string());
+    };
+
+    let stream = match direction {
+        Direction::Input => device.build_input_stream_raw(
+            &stream_config,
+            config.sample_format(),
+            move |data: &cpal::Data, info: &cpal::InputCallbackInfo| {
+                monitor.record_input(info, data.len());
+                record_frames(data.len());
+            },
+            error_callback,
+            Some(duration.max(Duration::from_secs(1))),
+        )?,
+        Direction::Output => device.build_output_stream_raw(
+            &stream_config,
+            config.sample_format(),
+            move |data: &mut cpal::Data, info: &cpal::OutputCallbackInfo| {
+                monitor.record_output(info, data.len());
+                record_frames(data.len());
+                fill_silence(data);
+            },
+            error_callback,
+            Some(duration.max(Duration::from_secs(1))),
+        )?,
+    };
+    stream.play()?;
+    std::thread::sleep(duration);
+    drop(stream);
+
+    let snapshot = timing.snapshot();
+    let min_callback_frames = min_frames.load(Ordering::Relaxed);
+    // The last buffer's frames were delivered at the end of the elapsed time
+    let measured_sample_rate = if snapshot.elapsed.is_zero() {
+        0.0
+    } else {
+        (snapshot.frames as f64 - snapshot.buffer_period.as_secs_f64() * config.sample_rate().0 as f64)
+            / snapshot.elapsed.as_secs_f64()
+    };
+
+    Ok(Observation {
+        callbacks: snapshot.callbacks,
+        min_callback_frames: if min_callback_frames == usize::MAX { 0 } else { min_callback_frames },
+        max_callback_frames: max_frames.load(Ordering::Relaxed),
+        mean_period_ms: snapshot.mean_period.as_secs_f64() * 1000.0,
+        jitter_ms: snapshot.jitter.as_secs_f64() * 1000.0,
+        measured_sample_rate,
+        missed_callbacks: snapshot.missed_callbacks,
+        stream_errors: errors_rx.try_iter().collect(),
+    })
+}
+
+fn fill_silence(data: &mut cpal::Data) {
+    fn fill<T: cpal::SizedSample>(data: &mut cpal::Data) {
+        if let Some(samples) = data.as_slice_mut::<T>() {
+            samples.fill(T::EQUILIBRIUM);
+        }
+    }
+
+    match data.sample_format() {
+        cpal::SampleFormat::I8 => fill::<i8>(data),
+        cpal::SampleFormat::I16 => fill::<i16>(data),
+        cpal::SampleFormat::I32 => fill::<i32>(data),
+        cpal::SampleFormat::I64 => fill::<i64>(data),
+        cpal::SampleFormat::U8 => fill::<u8>(data),
+        cpal::SampleFormat::U16 => fill::<u16>(data),
+        cpal::SampleFormat::U32 => fill::<u32>(data),
+        cpal::SampleFormat::U64 => fill::<u64>(data),
+        cpal::SampleFormat::F32 => fill::<f32>(data),
+        cpal::SampleFormat::F64 => fill::<f64>(data),
+        _ => data.bytes_mut().fill(0),
+    }
+}
+
+// Compares what a stream did with what the device advertised for it
+pub fn find_discrepancies(request: &ProbeRequest, observation: &Observation) -> Vec<String> {
+    let mut discrepancies = Vec::new();
+
+    if observation.callbacks == 0 {
+        discrepancies.push("stream started but no callbacks arrived".to_string());
+        return discrepancies;
+    }
+    for error in &observation.stream_errors {
+        discrepancies.push(format!("stream error: {error}"));
+    }
+
+    if observation.measured_sample_rate > 0.0 {
+        let deviation = observation.measured_sample_rate / request.sample_rate as f64 - 1.0;
+        if deviation.abs() > RATE_TOLERANCE {
+            discrepancies.push(format!(
+                "runs at {:.0} Hz instead of {} Hz",
+                observation.measured_sample_rate, request.sample_rate
+            ));
+        }
+    }
+
+    if let BufferSizeReport::Range { min, max } = request.advertised_buffer_size
+        && (observation.min_callback_frames < min as usize || observation.max_callback_frames > max as usize)
+    {
+        discrepancies.push(format!(
+            "callbacks of {}-{} frames are outside the advertised {min}-{max}",
+            observation.min_callback_frames, observation.max_callback_frames
+        ));
+    }
+
+    if let Some(requested) = request.requested_buffer_size
+        && (observation.min_callback_frames != requested as usize || observation.max_callback_frames != requested as usize)
+    {
+        discrepancies.push(format!(
+            "requested {requested} frame buffers but got {}-{}",
+            observation.min_callback_frames, observation.max_callback_frames
+        ));
+    }
+
+    if observation.missed_callbacks > 0 {
+        discrepancies.push(format!("{} callbacks missed", observation.missed_callbacks));
+    }
+
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_discrepancies() {
+        let request = ProbeRequest {
+            direction: Direction::Input,
+            channels: 2,
+            sample_rate: 48000,
+            sample_format: "f32".to_string(),
+            advertised_buffer_size: BufferSizeReport::Range { min: 64, max: 1024 },
+            requested_buffer_size: None,
+        };
+        let mut observation = Observation {
+            callbacks: 100,
+            min_callback_frames: 256,
+            max_callback_frames: 256,
+            mean_period_ms: 5.33,
+            jitter_ms: 0.1,
+            measured_sample_rate: 48010.0,
+            missed_callbacks: 0,
+            stream_errors: Vec::new(),
+        };
+        assert!(find_discrepancies(&request, &observation).is_empty());
+
+        // The driver claims 48 kHz but delivers 44.1 kHz in oversized buffers
+        observation.measured_sample_rate = 44100.0;
+        observation.max_callback_frames = 2048;
+        let discrepancies = find_discrepancies(&request, &observation);
+        assert_eq!(
+            discrepancies,
+            [
+                "runs at 44100 Hz instead of 48000 Hz",
+                "callbacks of 256-2048 frames are outside the advertised 64-1024",
+            ]
+        );
+
+        // Fixed 256 frame buffers were asked for but not delivered
+        let requested = ProbeRequest { requested_buffer_size: Some(256), ..request.clone() };
+        assert_eq!(
+            find_discrepancies(&requested, &observation).last().unwrap(),
+            "requested 256 frame buffers but got 256-2048"
+        );
+
+        observation.callbacks = 0;
+        assert_eq!(find_discrepancies(&request, &observation).len(), 1);
+    }
+}
@@ -0,0 +1,58 @@
+// An effect applied in place to interleaved f32 blocks, as they arrive in a
+// data callback. Implementations know their channel count up front and keep
+// whatever state they need between blocks.
+pub trait Processor {
+    fn process(&mut self, buffer: &mut [f32]);
+
+    // Forgets the audio seen so far, e.g. filter memories, without touching
+    // the settings
+    fn reset(&mut self) {}
+}
+
+impl<P: Processor + ?Sized> Processor for Box<P> {
+    fn process(&mut self, buffer: &mut [f32]) {
+        (**self).process(buffer);
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+}
+
+// Processors run one after another on the same buffer
+#[derive(Default)]
+pub struct Chain {
+    processors: Vec<Box<dyn Processor + Send>>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, processor: impl Processor + Send + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl Processor for Chain {
+    fn process(&mut self, buffer: &mut [f32]) {
+        for processor in &mut self.processors {
+            processor.process(buffer);
+        }
+    }
+
+    fn reset(&mut self) {
+        for processor in &mut self.processors {
+            processor.reset();
+        }
+    }
+}
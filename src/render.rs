@@ -0,0 +1,193 @@
+use anyhow::Context;
+use derive_builder::Builder;
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WavFormat {
+    I16,
+    I24,
+    I32,
+    F32,
+}
+
+impl WavFormat {
+    fn spec(self, sample_rate: u32, channels: u16) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            WavFormat::I16 => (16, hound::SampleFormat::Int),
+            WavFormat::I24 => (24, hound::SampleFormat::Int),
+            WavFormat::I32 => (32, hound::SampleFormat::Int),
+            WavFormat::F32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec { channels, sample_rate, bits_per_sample, sample_format }
+    }
+}
+
+#[derive(Builder)]
+pub struct RenderConfig {
+    sample_rate: u32,
+    channels: u16,
+    duration: Duration,
+    #[builder(default = "WavFormat::F32")]
+    format: WavFormat,
+    // Frames asked of the source at a time, like an output callback would
+    #[builder(default = "1024")]
+    block_size: usize,
+}
+
+impl RenderConfig {
+    pub fn frames(&self) -> u64 {
+        (self.duration.as_secs_f64() * self.sample_rate as f64).round() as u64
+    }
+}
+
+// Pulls `duration` worth of interleaved audio from `source` and writes it as
+// a WAV file, as fast as the source can produce it. The source gets a zeroed
+// buffer each time, so sources that mix into it can be chained.
+pub fn render<W, F>(writer: W, config: &RenderConfig, mut source: F) -> anyhow::Result<u64>
+where
+    W: Write + Seek,
+    F: FnMut(&mut [f32]),
+{
+    let channels = config.channels as usize;
+    let spec = config.format.spec(config.sample_rate, config.channels);
+    let mut wav = hound::WavWriter::new(writer, spec)?;
+    let mut buffer = vec![0.0; config.block_size.max(1) * channels];
+    let total = config.frames();
+    let mut written = 0;
+
+    while written < total {
+        let frames = (total - written).min(config.block_size.max(1) as u64) as usize;
+        let block = &mut buffer[..frames * channels];
+        block.fill(0.0);
+        source(block);
+        match config.format {
+            WavFormat::F32 => {
+                for &sample in block.iter() {
+                    wav.write_sample(sample)?;
+                }
+            }
+            WavFormat::I16 | WavFormat::I24 | WavFormat::I32 => {
+                let max = ((1i64 << (spec.bits_per_sample - 1)) - 1) as f64;
+                for &sample in block.iter() {
+                    wav.write_sample((sample.clamp(-1.0, 1.0) as f64 * max).round() as i32)?;
+                }
+            }
+        }
+        written += frames as u64;
+    }
+
+    wav.finalize()?;
+    Ok(written)
+}
+
+pub fn render_to_file<F>(path: impl AsRef<Path>, config: &RenderConfig, source: F) -> anyhow::Result<u64>
+where
+    F: FnMut(&mut [f32]),
+{
+    let path = path.as_ref();
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    render(std::io::BufWriter::new(file), config, source)
+}
+
+// Interleaved samples of a WAV file, integer formats scaled to -1..1
+pub struct WavData {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+impl WavData {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1)
+    }
+}
+
+pub fn read_wav(path: impl AsRef<Path>) -> anyhow::Result<WavData> {
+    let path = path.as_ref();
+    let mut reader = hound::WavReader::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 * scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    Ok(WavData {
+        samples,
+        sample_rate: spec.sample_rate,
+        channels: spec.channels as usize,
+    })
+}
+
+// Writes interleaved samples as they are, such as a recording or a measured
+// impulse response
+pub fn write_wav(path: impl AsRef<Path>, data: &WavData, format: WavFormat) -> anyhow::Result<()> {
+    let config = RenderConfigBuilder::default()
+        .sample_rate(data.sample_rate)
+        .channels(data.channels as u16)
+        .duration(Duration::from_secs_f64(data.frames() as f64 / data.sample_rate as f64))
+        .format(format)
+        .build()?;
+    let mut samples = data.samples.iter();
+    render_to_file(path, &config, |buffer| {
+        for (output, &sample) in buffer.iter_mut().zip(&mut samples) {
+            *output = sample;
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TonePlayer, TonePlayerConfigBuilder};
+    use std::io::Cursor;
+
+    fn player() -> TonePlayer {
+        TonePlayer::with_config(
+            TonePlayerConfigBuilder::default()
+                .frequency(1000.0)
+                .sample_rate(8000)
+                .channels(2)
+                .factor(1.5)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_render_matches_callbacks() {
+        let config = RenderConfigBuilder::default()
+            .sample_rate(8000)
+            .channels(2)
+            .duration(Duration::from_millis(100))
+            .format(WavFormat::I16)
+            .block_size(300)
+            .build()
+            .unwrap();
+        let mut wav = Cursor::new(Vec::new());
+        let mut source = player();
+        assert_eq!(render(&mut wav, &config, |buffer| source.fill_buffer(buffer)).unwrap(), 800);
+
+        // The same player driven in one go, as a single callback would
+        let mut expected = vec![0.0f32; 1600];
+        player().fill_buffer(&mut expected);
+
+        wav.set_position(0);
+        let mut reader = hound::WavReader::new(wav).unwrap();
+        assert_eq!(reader.spec(), WavFormat::I16.spec(8000, 2));
+        let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        assert_eq!(samples.len(), 1600);
+        for (&sample, &value) in samples.iter().zip(&expected) {
+            assert_eq!(sample, (value.clamp(-1.0, 1.0) * 32767.0).round() as i16);
+        }
+        // The factor of 1.5 drives the peaks into clipping
+        assert_eq!(samples.iter().copied().max(), Some(i16::MAX));
+    }
+}
@@ -0,0 +1,312 @@
+use derive_builder::Builder;
+
+// Largest number of input frames kept around between calls to `process`
+const MAX_BUFFERED_FRAMES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    // Two-point linear interpolation, cheap but with audible aliasing
+    Linear,
+    // Windowed-sinc filters of increasing length and stopband attenuation
+    Low,
+    Medium,
+    High,
+}
+
+impl Quality {
+    // Returns (taps, phases, kaiser beta) of the polyphase filter
+    fn parameters(self) -> (usize, usize, f64) {
+        match self {
+            Quality::Linear => (2, 1, 0.0),
+            Quality::Low => (16, 64, 6.0),
+            Quality::Medium => (32, 128, 8.0),
+            Quality::High => (64, 256, 10.0),
+        }
+    }
+}
+
+#[derive(Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct ResamplerConfig {
+    input_rate: u32,
+    output_rate: u32,
+    channels: usize,
+    #[builder(default = "Quality::Medium")]
+    quality: Quality,
+}
+
+impl ResamplerConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if self.input_rate == Some(0) || self.output_rate == Some(0) {
+            return Err("The sample rates must be above 0 Hz".to_string());
+        }
+        if self.channels == Some(0) {
+            return Err("A resampler needs at least one channel".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Converts interleaved f32 blocks from one sample rate to another.
+//
+// The filter is a Kaiser-windowed sinc sampled at `phases` fractional offsets;
+// coefficients for the offsets in between are interpolated linearly, so any
+// ratio (including one that changes over time) can be handled.
+pub struct Resampler {
+    channels: usize,
+    taps: usize,
+    phases: usize,
+    // (phases + 1) rows of `taps` coefficients
+    kernel: Vec<f32>,
+    nominal_ratio: f64,
+    // Input frames advanced per output frame
+    step: f64,
+    // Interleaved input frames waiting to be consumed
+    buffer: Vec<f32>,
+    buffered_frames: usize,
+    // Position of the next output frame relative to the start of `buffer`
+    position: f64,
+}
+
+impl Resampler {
+    pub fn with_config(config: ResamplerConfig) -> Self {
+        let (taps, phases, beta) = config.quality.parameters();
+        let ratio = config.input_rate as f64 / config.output_rate as f64;
+        let kernel = if config.quality == Quality::Linear {
+            vec![1.0, 0.0, 0.0, 1.0]
+        } else {
+            // Leave a little room below Nyquist for the transition band
+            let cutoff = (1.0 / ratio).min(1.0) * 0.92;
+            sinc_kernel(taps, phases, cutoff, beta)
+        };
+
+        // Prime the history with silence so the first output frame is
+        // centred on the first input frame
+        let buffered_frames = taps / 2 - 1;
+        Self {
+            channels: config.channels,
+            taps,
+            phases,
+            kernel,
+            nominal_ratio: ratio,
+            step: ratio,
+            buffer: vec![0.0; (MAX_BUFFERED_FRAMES + taps) * config.channels],
+            buffered_frames,
+            position: 0.0,
+        }
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.step
+    }
+
+    // Scales the nominal conversion ratio, e.g. by 1.0001 to consume input
+    // 100 ppm faster than the configured rates imply
+    pub fn set_ratio_adjustment(&mut self, factor: f64) {
+        self.step = self.nominal_ratio * factor;
+    }
+
+    // Input frames taken by `process` that are still waiting to be
+    // turned into output
+    pub fn buffered_frames(&self) -> f64 {
+        (self.buffered_frames as f64 - self.position).max(0.0)
+    }
+
+    // Resamples as much of `input` as possible into `output`.
+    // Both slices are interleaved with the configured channel count.
+    // Returns the number of input frames consumed and output frames produced;
+    // unconsumed input should be passed again on the next call.
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) -> (usize, usize) {
+        let channels = self.channels;
+        let input_frames = input.len() / channels;
+        let output_frames = output.len() / channels;
+
+        let mut consumed = 0;
+        let mut produced = 0;
+
+        while produced < output_frames {
+            let index = self.position as usize;
+            if index + self.taps > self.buffered_frames {
+                if consumed == input_frames {
+                    break;
+                }
+                consumed += self.buffer_input(&input[consumed * channels..]);
+                continue;
+            }
+
+            let fraction = (self.position - index as f64) * self.phases as f64;
+            let phase = (fraction as usize).min(self.phases - 1);
+            let blend = (fraction - phase as f64) as f32;
+            let lower = &self.kernel[phase * self.taps..(phase + 1) * self.taps];
+            let upper = &self.kernel[(phase + 1) * self.taps..(phase + 2) * self.taps];
+
+            let frame = &mut output[produced * channels..(produced + 1) * channels];
+            frame.fill(0.0);
+            for (tap, (&a, &b)) in lower.iter().zip(upper).enumerate() {
+                let coefficient = a + (b - a) * blend;
+                let source = (index + tap) * channels;
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    *sample += coefficient * self.buffer[source + channel];
+                }
+            }
+
+            produced += 1;
+            self.position += self.step;
+        }
+
+        if consumed < input_frames {
+            // Output is full; keep whatever input still fits for later
+            consumed += self.buffer_input(&input[consumed * channels..]);
+        }
+
+        (consumed, produced)
+    }
+
+    // Appends as many frames of `input` as fit into the history buffer
+    // and returns how many were taken
+    fn buffer_input(&mut self, input: &[f32]) -> usize {
+        self.compact();
+        let channels = self.channels;
+        let capacity = self.buffer.len() / channels - self.buffered_frames;
+        let count = capacity.min(input.len() / channels);
+        let destination = self.buffered_frames * channels;
+        self.buffer[destination..destination + count * channels]
+            .copy_from_slice(&input[..count * channels]);
+        self.buffered_frames += count;
+        count
+    }
+
+    // Drops input frames that no future output frame depends on
+    fn compact(&mut self) {
+        let index = (self.position as usize).min(self.buffered_frames);
+        if index == 0 {
+            return;
+        }
+        let channels = self.channels;
+        self.buffer.copy_within(index * channels..self.buffered_frames * channels, 0);
+        self.buffered_frames -= index;
+        self.position -= index as f64;
+    }
+}
+
+fn sinc_kernel(taps: usize, phases: usize, cutoff: f64, beta: f64) -> Vec<f32> {
+    let half = (taps / 2) as f64;
+    let norm = bessel_i0(beta);
+    let mut kernel = Vec::with_capacity((phases + 1) * taps);
+    for phase in 0..=phases {
+        let offset = phase as f64 / phases as f64;
+        let row: Vec<f64> = (0..taps)
+            .map(|tap| {
+                // Distance from the interpolated point, in input frames
+                let x = tap as f64 - (half - 1.0) - offset;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let arg = std::f64::consts::PI * cutoff * x;
+                    arg.sin() / arg
+                };
+                let t = (x / half).clamp(-1.0, 1.0);
+                let window = bessel_i0(beta * (1.0 - t * t).sqrt()) / norm;
+                cutoff * sinc * window
+            })
+            .collect();
+        // Normalise every phase to unity gain at DC
+        let sum: f64 = row.iter().sum();
+        kernel.extend(row.iter().map(|&c| (c / sum) as f32));
+    }
+    kernel
+}
+
+// Zeroth-order modified Bessel function of the first kind
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= half / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::window::Window;
+    use std::time::Duration;
+
+    fn sine(frequency: f64, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    fn resample(input: &[f32], input_rate: u32, output_rate: u32, quality: Quality) -> Window {
+        let mut resampler = Resampler::with_config(
+            ResamplerConfigBuilder::default()
+                .input_rate(input_rate)
+                .output_rate(output_rate)
+                .channels(1)
+                .quality(quality)
+                .build()
+                .expect("Failed to build ResamplerConfig"),
+        );
+        let mut window = Window::with_duration(Duration::from_millis(200), output_rate);
+        let mut output = vec![0.0; 256];
+        // Feed in odd-sized blocks to exercise the buffering
+        for block in input.chunks(100) {
+            let mut offset = 0;
+            while offset < block.len() {
+                let (consumed, produced) = resampler.process(&block[offset..], &mut output);
+                window.add_samples(&output[..produced]);
+                offset += consumed;
+            }
+        }
+        window
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = |input_rate, output_rate, channels| {
+            ResamplerConfigBuilder::default()
+                .input_rate(input_rate)
+                .output_rate(output_rate)
+                .channels(channels)
+                .build()
+        };
+        assert!(config(44100, 48000, 2).is_ok());
+        assert!(config(0, 48000, 2).is_err());
+        assert!(config(44100, 0, 2).is_err());
+        assert!(config(44100, 48000, 0).is_err());
+    }
+
+    #[test]
+    fn test_passband_ripple() {
+        for quality in [Quality::Low, Quality::Medium, Quality::High] {
+            for frequency in [100.0, 1000.0, 5000.0, 15000.0] {
+                let input = sine(frequency, 44100, 44100 / 2);
+                let window = resample(&input, 44100, 48000, quality);
+                let amplitude = window.calculate_amplitude(frequency).unwrap();
+                let ripple_db = 20.0 * (amplitude / 0.5).log10();
+                assert!(ripple_db.abs() < 0.1, "{quality:?} at {frequency} Hz: {ripple_db} dB");
+            }
+        }
+    }
+
+    #[test]
+    fn test_aliasing_rejection() {
+        // 15 kHz is above the 11025 Hz Nyquist frequency of the output
+        // and would fold back to 7050 Hz without filtering
+        let input = sine(15000.0, 44100, 44100 / 2);
+        let alias_db = |quality| {
+            let window = resample(&input, 44100, 22050, quality);
+            20.0 * (window.calculate_amplitude(7050.0).unwrap() / 0.5).log10()
+        };
+        assert!(alias_db(Quality::Linear) > -20.0);
+        assert!(alias_db(Quality::Medium) < -60.0);
+        assert!(alias_db(Quality::High) < -80.0);
+    }
+}
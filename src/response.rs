@@ -0,0 +1,301 @@
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use crate::correlation::find_delay;
+use crate::fft::{real_fft, Complex};
+use crate::generator::{Mls, Signal};
+use crate::impulse::Sweep;
+
+// Played ahead of the steps so that they can be found in a recording
+const MARKER_ORDER: u32 = 12;
+const MARKER_GAP: Duration = Duration::from_millis(50);
+// Share of every step left for the system to settle before it is measured
+const SETTLE_FRACTION: f64 = 0.25;
+// Length of the impulse response a sweep is turned into
+pub const IMPULSE_RESPONSE_LENGTH: Duration = Duration::from_millis(500);
+
+// Frequencies spread evenly on a logarithmic axis, both ends included
+pub fn log_frequencies(from: f64, to: f64, count: usize) -> Vec<f64> {
+    let ratio = (to / from).ln();
+    (0..count)
+        .map(|i| from * (ratio * i as f64 / (count.max(2) - 1) as f64).exp())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResponsePoint {
+    pub frequency: f64,
+    // Gain and phase shift of the system at `frequency`
+    pub value: Complex,
+}
+
+impl ResponsePoint {
+    pub fn magnitude_db(&self) -> f64 {
+        20.0 * self.value.norm().max(1e-12).log10()
+    }
+
+    // -180 to 180 degrees
+    pub fn phase_degrees(&self) -> f64 {
+        self.value.arg().to_degrees()
+    }
+}
+
+// Magnitude and phase of a system at a set of frequencies, in increasing order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrequencyResponse {
+    points: Vec<ResponsePoint>,
+}
+
+impl FrequencyResponse {
+    pub fn new(mut points: Vec<ResponsePoint>) -> Self {
+        points.sort_by(|a, b| a.frequency.total_cmp(&b.frequency));
+        Self { points }
+    }
+
+    // Every FFT bin of an impulse response but DC. Frame `reference` is taken
+    // as time zero, so a response peaking there shows no phase shift from
+    // its delay.
+    pub fn from_impulse_response(impulse_response: &[f32], reference: usize, sample_rate: u32) -> Self {
+        let size = impulse_response.len().max(2).next_power_of_two();
+        let spectrum = real_fft(impulse_response, size);
+        let points = spectrum[1..=size / 2]
+            .iter()
+            .enumerate()
+            .map(|(index, &value)| {
+                let bin = index + 1;
+                let delay = Complex::from_polar(1.0, 2.0 * PI * bin as f64 * reference as f64 / size as f64);
+                ResponsePoint {
+                    frequency: bin as f64 * sample_rate as f64 / size as f64,
+                    value: value * delay,
+                }
+            })
+            .collect();
+        Self { points }
+    }
+
+    pub fn points(&self) -> &[ResponsePoint] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    // 1/`fraction` octave smoothing evaluated at `frequencies`: the power of
+    // the points within the band around each frequency is averaged for the
+    // magnitude, their complex values for the phase. Bands without any point
+    // take the nearest one.
+    pub fn smoothed(&self, fraction: f64, frequencies: &[f64]) -> Self {
+        if self.points.is_empty() {
+            return Self::default();
+        }
+        let half_band = 2f64.powf(0.5 / fraction);
+        let points = frequencies
+            .iter()
+            .map(|&frequency| {
+                let low = self.points.partition_point(|point| point.frequency < frequency / half_band);
+                let high = self.points.partition_point(|point| point.frequency <= frequency * half_band);
+                let band = if low < high {
+                    &self.points[low..high]
+                } else {
+                    let nearest = self.nearest(frequency);
+                    &self.points[nearest..=nearest]
+                };
+                let count = band.len() as f64;
+                let power = band.iter().map(|point| point.value.norm_sqr()).sum::<f64>() / count;
+                let sum = band.iter().fold(Complex::default(), |sum, point| sum + point.value);
+                ResponsePoint {
+                    frequency,
+                    value: Complex::from_polar(power.sqrt(), sum.arg()),
+                }
+            })
+            .collect();
+        Self { points }
+    }
+
+    fn nearest(&self, frequency: f64) -> usize {
+        let index = self.points.partition_point(|point| point.frequency < frequency);
+        let distance = |i: usize| (self.points[i].frequency / frequency).ln().abs();
+        match (index.checked_sub(1), index < self.points.len()) {
+            (Some(below), true) if distance(below) < distance(index) => below,
+            (Some(below), false) => below,
+            _ => index,
+        }
+    }
+}
+
+// A sine at each frequency in turn, behind a noise burst that locates them in
+// a recording. Every step is measured once it has settled, over a whole
+// number of periods, against what was played.
+pub struct SteppedSine {
+    frequencies: Vec<f64>,
+    sample_rate: u32,
+    marker: Vec<f32>,
+    // Frames from the start of the signal to the first step
+    lead: usize,
+    step: usize,
+    samples: Vec<f32>,
+}
+
+impl SteppedSine {
+    pub fn new(frequencies: Vec<f64>, step: Duration, sample_rate: u32) -> Self {
+        let marker = Mls::new(MARKER_ORDER).take_values(Mls::new(MARKER_ORDER).len());
+        let lead = marker.len() + (MARKER_GAP.as_secs_f64() * sample_rate as f64) as usize;
+        let step = (step.as_secs_f64() * sample_rate as f64) as usize;
+
+        let mut samples = marker.clone();
+        samples.resize(lead, 0.0);
+        for &frequency in &frequencies {
+            let omega = 2.0 * PI * frequency / sample_rate as f64;
+            samples.extend((0..step).map(|n| (omega * n as f64).sin() as f32));
+        }
+
+        Self { frequencies, sample_rate, marker, lead, step, samples }
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Finds the signal in `recording` and measures every step that was
+    // recorded in full
+    pub fn analyze(&self, recording: &[f32]) -> Option<FrequencyResponse> {
+        let start = find_delay(recording, &self.marker)?.lag;
+        let settle = (self.step as f64 * SETTLE_FRACTION) as usize;
+        let points = self
+            .frequencies
+            .iter()
+            .enumerate()
+            .map_while(|(index, &frequency)| {
+                let begin = self.lead + index * self.step + settle;
+                let period = self.sample_rate as f64 / frequency;
+                let periods = ((self.step - settle) as f64 / period).floor().max(1.0);
+                let end = begin + ((periods * period).round() as usize).min(self.step - settle);
+                let recorded = recording.get(start + begin..start + end)?;
+                let played = &self.samples[begin..end];
+                Some(ResponsePoint {
+                    frequency,
+                    value: demodulate(recorded, frequency, self.sample_rate) / demodulate(played, frequency, self.sample_rate),
+                })
+            })
+            .collect::<Vec<_>>();
+        (!points.is_empty()).then(|| FrequencyResponse::new(points))
+    }
+}
+
+// What a frequency response is measured with
+pub enum Excitation {
+    Sweep(Sweep),
+    Steps(SteppedSine),
+}
+
+impl Excitation {
+    pub fn samples(&self) -> &[f32] {
+        match self {
+            Excitation::Sweep(sweep) => sweep.samples(),
+            Excitation::Steps(steps) => steps.samples(),
+        }
+    }
+
+    pub fn analyze(&self, recording: &[f32], sample_rate: u32) -> Option<FrequencyResponse> {
+        match self {
+            Excitation::Sweep(sweep) => {
+                let length = (IMPULSE_RESPONSE_LENGTH.as_secs_f64() * sample_rate as f64) as usize;
+                let responses = sweep.analyze(recording, length, 0)?;
+                Some(FrequencyResponse::from_impulse_response(&responses.linear, responses.pre_roll, sample_rate))
+            }
+            Excitation::Steps(steps) => steps.analyze(recording),
+        }
+    }
+}
+
+// Complex amplitude of `frequency` in `samples`, phase relative to their start
+fn demodulate(samples: &[f32], frequency: f64, sample_rate: u32) -> Complex {
+    let omega = 2.0 * PI * frequency / sample_rate as f64;
+    samples
+        .iter()
+        .enumerate()
+        .fold(Complex::default(), |sum, (n, &sample)| {
+            sum + Complex::from_polar(sample as f64, -omega * n as f64)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::biquad::{Biquad, BiquadConfigBuilder, FilterType};
+    use crate::processor::Processor;
+
+    fn low_pass() -> Biquad {
+        Biquad::with_config(
+            BiquadConfigBuilder::default()
+                .filter_type(FilterType::LowPass)
+                .frequency(1000.0)
+                .sample_rate(48000)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_measured_responses() {
+        let expected = low_pass();
+
+        // A delayed low-pass measured through its impulse response
+        let mut impulse_response = vec![0.0f32; 8192];
+        impulse_response[100] = 1.0;
+        low_pass().process(&mut impulse_response);
+        let response = FrequencyResponse::from_impulse_response(&impulse_response, 100, 48000);
+        assert_eq!(response.points().len(), 4096);
+        for point in response.points().iter().step_by(37).take_while(|point| point.frequency < 10000.0) {
+            let wanted = expected.frequency_response(point.frequency);
+            assert!((point.value - wanted).norm() < 1e-3, "{point:?} {wanted:?}");
+        }
+
+        // The same with stepped sines, played into it 300 frames late. The
+        // phase is relative to where the marker arrives, which the filter
+        // delays as well, so it is checked on a plain delay.
+        let frequencies = log_frequencies(50.0, 10000.0, 12);
+        let stepped = SteppedSine::new(frequencies, Duration::from_millis(100), 48000);
+        let mut delayed = vec![0.0; 300];
+        delayed.extend_from_slice(stepped.samples());
+        delayed.resize(delayed.len() + 1000, 0.0);
+        let mut filtered = delayed.clone();
+        low_pass().process(&mut filtered);
+        let measured = stepped.analyze(&filtered).unwrap();
+        assert_eq!(measured.points().len(), 12);
+        for point in measured.points() {
+            let wanted = ResponsePoint { frequency: point.frequency, value: expected.frequency_response(point.frequency) };
+            assert!((point.magnitude_db() - wanted.magnitude_db()).abs() < 0.05, "{point:?} {wanted:?}");
+        }
+        delayed.iter_mut().for_each(|sample| *sample *= 0.5);
+        for point in stepped.analyze(&delayed).unwrap().points() {
+            assert!((point.magnitude_db() + 6.02).abs() < 0.01, "{point:?}");
+            assert!(point.phase_degrees().abs() < 0.1, "{point:?}");
+        }
+        // Steps that were not recorded are left out
+        assert_eq!(stepped.analyze(&delayed[..delayed.len() - 3000]).unwrap().points().len(), 11);
+
+        // Smoothing evens out a comb but keeps the overall level
+        let mut comb = vec![0.0f32; 4096];
+        comb[0] = 1.0;
+        comb[48] = 0.5;
+        let smoothed = FrequencyResponse::from_impulse_response(&comb, 0, 48000)
+            .smoothed(1.0, &log_frequencies(4000.0, 16000.0, 10));
+        for point in smoothed.points() {
+            assert!((point.magnitude_db() - 10.0 * 1.25f64.log10()).abs() < 0.4, "{point:?}");
+        }
+        // Below the resolution of the response, the nearest point stands in
+        let coarse = FrequencyResponse::from_impulse_response(&comb[..64], 0, 48000).smoothed(24.0, &[1000.0]);
+        assert_eq!(coarse.points()[0].frequency, 1000.0);
+        assert!(coarse.points()[0].magnitude_db().is_finite());
+    }
+}
@@ -0,0 +1,237 @@
+use derive_builder::Builder;
+use std::time::Duration;
+
+use crate::delay::{DelayLine, Interpolation};
+use crate::processor::Processor;
+
+// Freeverb's tunings in frames at 44.1 kHz, scaled to the actual rate
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+// Added to the tunings of every further channel so that they decorrelate
+const STEREO_SPREAD: usize = 23;
+const TUNING_RATE: f64 = 44100.0;
+const INPUT_GAIN: f32 = 0.015;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+#[derive(Builder)]
+pub struct ReverbConfig {
+    sample_rate: u32,
+    channels: usize,
+    // 0 to 1, from a small room to a hall
+    #[builder(default = "0.5")]
+    room_size: f32,
+    // 0 to 1, how much faster the highs die away than the lows
+    #[builder(default = "0.5")]
+    damping: f32,
+    // Gap between the dry sound and the start of the reverb
+    #[builder(default = "Duration::from_millis(10)")]
+    pre_delay: Duration,
+    // Share of the output that is reverb
+    #[builder(default = "0.25")]
+    mix: f32,
+    // 0 to 1, from the same reverb on every channel to fully separate ones
+    #[builder(default = "1.0")]
+    width: f32,
+}
+
+// Low-passed feedback comb filter
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filtered: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.index] = input + self.filtered * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * ALLPASS_FEEDBACK;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+// One channel's worth of parallel combs into series allpasses
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+// Freeverb: the input, mixed to mono and pre-delayed, rings through a tank
+// of eight combs and four allpasses per channel, each tank tuned slightly
+// apart. Everything is allocated up front, so processing does not allocate
+// and can run in a data callback.
+pub struct Reverb {
+    config: ReverbConfig,
+    pre_delay: DelayLine,
+    pre_delay_frames: f64,
+    tanks: Vec<Tank>,
+    // Output of every tank for the current frame
+    wet: Vec<f32>,
+}
+
+impl Reverb {
+    pub fn with_config(config: ReverbConfig) -> Self {
+        let scale = config.sample_rate as f64 / TUNING_RATE;
+        let frames = |tuning: usize| ((tuning as f64 * scale).round() as usize).max(1);
+        let tanks = (0..config.channels.max(1))
+            .map(|channel| Tank {
+                combs: COMB_TUNINGS
+                    .iter()
+                    .map(|&tuning| Comb {
+                        buffer: vec![0.0; frames(tuning + channel * STEREO_SPREAD)],
+                        index: 0,
+                        filtered: 0.0,
+                    })
+                    .collect(),
+                allpasses: ALLPASS_TUNINGS
+                    .iter()
+                    .map(|&tuning| Allpass {
+                        buffer: vec![0.0; frames(tuning + channel * STEREO_SPREAD)],
+                        index: 0,
+                    })
+                    .collect(),
+            })
+            .collect();
+        let pre_delay_frames = config.pre_delay.as_secs_f64() * config.sample_rate as f64;
+        Self {
+            pre_delay: DelayLine::new(pre_delay_frames.ceil() as usize + 1, Interpolation::Linear),
+            pre_delay_frames,
+            wet: vec![0.0; config.channels.max(1)],
+            tanks,
+            config,
+        }
+    }
+
+    pub fn set_room_size(&mut self, room_size: f32) {
+        self.config.room_size = room_size;
+    }
+
+    pub fn set_damping(&mut self, damping: f32) {
+        self.config.damping = damping;
+    }
+
+    pub fn set_mix(&mut self, mix: f32) {
+        self.config.mix = mix;
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.config.width = width;
+    }
+}
+
+impl Processor for Reverb {
+    fn process(&mut self, buffer: &mut [f32]) {
+        let channels = self.tanks.len();
+        // Freeverb's mapping of the controls onto the filters
+        let feedback = self.config.room_size.clamp(0.0, 1.0) * 0.28 + 0.7;
+        let damping = self.config.damping.clamp(0.0, 1.0) * 0.4;
+        let mix = self.config.mix;
+        let width = self.config.width.clamp(0.0, 1.0);
+        let (own, other) = if channels == 1 { (1.0, 0.0) } else { (width / 2.0 + 0.5, (1.0 - width) / 2.0) };
+
+        for frame in buffer.chunks_mut(channels) {
+            let mono = frame.iter().sum::<f32>() / frame.len() as f32;
+            let input = self.pre_delay.read(self.pre_delay_frames) * INPUT_GAIN;
+            self.pre_delay.push(mono);
+
+            for (tank, wet) in self.tanks.iter_mut().zip(&mut self.wet) {
+                let mut output = tank.combs.iter_mut().map(|comb| comb.process(input, feedback, damping)).sum::<f32>();
+                for allpass in &mut tank.allpasses {
+                    output = allpass.process(output);
+                }
+                *wet = output;
+            }
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let wet = own * self.wet[channel] + other * self.wet[(channel + 1) % channels];
+                *sample = *sample * (1.0 - mix) + wet * mix;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pre_delay.clear();
+        for tank in &mut self.tanks {
+            for comb in &mut tank.combs {
+                comb.buffer.fill(0.0);
+                comb.filtered = 0.0;
+            }
+            for allpass in &mut tank.allpasses {
+                allpass.buffer.fill(0.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse_response(room_size: f32, pre_delay: Duration) -> Vec<f32> {
+        let mut reverb = Reverb::with_config(
+            ReverbConfigBuilder::default()
+                .sample_rate(48000)
+                .channels(2)
+                .room_size(room_size)
+                .pre_delay(pre_delay)
+                .mix(1.0)
+                .build()
+                .unwrap(),
+        );
+        let mut buffer = vec![0.0f32; 2 * 96000];
+        buffer[0] = 1.0;
+        buffer[1] = 1.0;
+        for block in buffer.chunks_mut(256) {
+            reverb.process(block);
+        }
+        buffer
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn test_reverb_decay() {
+        let small = impulse_response(0.2, Duration::from_millis(20));
+        let large = impulse_response(0.9, Duration::from_millis(20));
+
+        // Nothing comes back before the pre-delay plus the shortest comb
+        let first = small.iter().position(|&x| x != 0.0).unwrap() / 2;
+        assert_eq!(first, 960 + 1215);
+
+        // Both tails die away, the larger room's more slowly
+        for response in [&small, &large] {
+            assert!(response.iter().all(|x| x.is_finite() && x.abs() < 1.0));
+            assert!(energy(&response[2 * 48000..]) < energy(&response[..2 * 48000]) / 10.0);
+        }
+        let tail = |response: &[f32]| energy(&response[2 * 24000..]) / energy(response);
+        assert!(tail(&large) > 10.0 * tail(&small), "{} {}", tail(&large), tail(&small));
+
+        // The channels are decorrelated
+        let (left, right): (Vec<f32>, Vec<f32>) = large.chunks(2).map(|frame| (frame[0], frame[1])).unzip();
+        let correlation = left.iter().zip(&right).map(|(l, r)| l * r).sum::<f32>() / (energy(&left) * energy(&right)).sqrt();
+        assert!(correlation.abs() < 0.5, "{correlation}");
+
+        // Without reverb in the mix the input passes unchanged
+        let mut dry = Reverb::with_config(ReverbConfigBuilder::default().sample_rate(48000).channels(1).mix(0.0).build().unwrap());
+        let mut buffer: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.1).sin()).collect();
+        let original = buffer.clone();
+        dry.process(&mut buffer);
+        assert_eq!(buffer, original);
+    }
+}
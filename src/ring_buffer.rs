@@ -0,0 +1,243 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+// Fixed-capacity single-producer/single-consumer queue of f32 samples.
+//
+// Neither side allocates, locks or retries after construction, so both halves
+// are safe to use from audio callbacks. Samples are stored as their bit
+// patterns in atomics; the read and write positions only ever grow and are
+// reduced modulo the capacity when indexing.
+struct Shared {
+    slots: Box<[AtomicU32]>,
+    write: AtomicUsize,
+    read: AtomicUsize,
+    overruns: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+impl Shared {
+    fn len(&self) -> usize {
+        let write = self.write.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Acquire);
+        write.wrapping_sub(read)
+    }
+}
+
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    assert!(capacity > 0, "Ring buffer capacity must be positive");
+    let shared = Arc::new(Shared {
+        slots: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+        underruns: AtomicUsize::new(0),
+    });
+    (
+        Producer { shared: shared.clone() },
+        Consumer { shared },
+    )
+}
+
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+impl Producer {
+    // Writes as many samples as there is room for and returns how many were
+    // written. A short write is counted as an overrun.
+    pub fn push_slice(&mut self, samples: &[f32]) -> usize {
+        self.push_iter(samples.iter().copied())
+    }
+
+    // Like `push_slice`, for samples that are picked out on the way, e.g.
+    // one channel of an interleaved buffer, without copying them first
+    pub fn push_iter(&mut self, samples: impl ExactSizeIterator<Item = f32>) -> usize {
+        self.push_whole(samples, 1)
+    }
+
+    // Like `push_slice` for interleaved samples, writing only whole frames so
+    // that the consumer never sees the channels shifted
+    pub fn push_frames(&mut self, samples: &[f32], channels: usize) -> usize {
+        self.push_whole(samples.iter().copied(), channels)
+    }
+
+    // Writes as many whole groups of `group` samples as there is room for
+    fn push_whole(&mut self, samples: impl ExactSizeIterator<Item = f32>, group: usize) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let write = shared.write.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+        let free = capacity - write.wrapping_sub(read);
+
+        let total = samples.len();
+        let count = total.min(free / group * group);
+        for (i, sample) in samples.take(count).enumerate() {
+            shared.slots[write.wrapping_add(i) % capacity].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        shared.write.store(write.wrapping_add(count), Ordering::Release);
+
+        if count < total {
+            shared.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        count
+    }
+
+    pub fn free_len(&self) -> usize {
+        self.shared.slots.len() - self.shared.len()
+    }
+
+    pub fn monitor(&self) -> RingBufferMonitor {
+        RingBufferMonitor { shared: self.shared.clone() }
+    }
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+impl Consumer {
+    // Reads up to `output.len()` samples and returns how many were read.
+    // A short read is counted as an underrun; check `len` first to drain
+    // without counting.
+    pub fn pop_slice(&mut self, output: &mut [f32]) -> usize {
+        let shared = &*self.shared;
+        let capacity = shared.slots.len();
+        let read = shared.read.load(Ordering::Relaxed);
+        let write = shared.write.load(Ordering::Acquire);
+        let available = write.wrapping_sub(read);
+
+        let count = output.len().min(available);
+        for (i, sample) in output[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(shared.slots[read.wrapping_add(i) % capacity].load(Ordering::Relaxed));
+        }
+        shared.read.store(read.wrapping_add(count), Ordering::Release);
+
+        if count < output.len() {
+            shared.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        count
+    }
+
+    // Drops up to `count` samples without reading them
+    pub fn skip(&mut self, count: usize) -> usize {
+        let shared = &*self.shared;
+        let read = shared.read.load(Ordering::Relaxed);
+        let count = count.min(shared.write.load(Ordering::Acquire).wrapping_sub(read));
+        shared.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn monitor(&self) -> RingBufferMonitor {
+        RingBufferMonitor { shared: self.shared.clone() }
+    }
+}
+
+// Read-only view of a ring buffer's fill level and xrun counters,
+// for reporting from threads other than the producer and consumer
+#[derive(Clone)]
+pub struct RingBufferMonitor {
+    shared: Arc<Shared>,
+}
+
+impl RingBufferMonitor {
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    pub fn fill_ratio(&self) -> f32 {
+        self.len() as f32 / self.capacity() as f32
+    }
+
+    pub fn overruns(&self) -> usize {
+        self.shared.overruns.load(Ordering::Relaxed)
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.shared.underruns.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        let monitor = consumer.monitor();
+        let mut output = [0.0; 3];
+
+        assert_eq!(producer.push_slice(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(consumer.pop_slice(&mut output[..2]), 2);
+        assert_eq!(output[..2], [1.0, 2.0]);
+
+        // Wraps around the end of the storage and overruns
+        assert_eq!(producer.push_slice(&[4.0, 5.0, 6.0, 7.0]), 3);
+        assert_eq!(monitor.len(), 4);
+        assert_eq!(monitor.overruns(), 1);
+
+        assert_eq!(consumer.skip(1), 1);
+        assert_eq!(consumer.pop_slice(&mut output), 3);
+        assert_eq!(output, [4.0, 5.0, 6.0]);
+        assert_eq!(monitor.underruns(), 0);
+
+        assert_eq!(consumer.pop_slice(&mut output), 0);
+        assert!(consumer.is_empty());
+        assert_eq!(monitor.underruns(), 1);
+
+        // The second channel of interleaved stereo, one sample too many
+        let stereo = [1.0, 10.0, 2.0, 20.0, 3.0, 30.0, 4.0, 40.0, 5.0, 50.0];
+        assert_eq!(producer.push_iter(stereo.iter().skip(1).step_by(2).copied()), 4);
+        assert_eq!(monitor.overruns(), 2);
+        assert_eq!(consumer.pop_slice(&mut output), 3);
+        assert_eq!(output, [10.0, 20.0, 30.0]);
+
+        // Three samples of room take one stereo frame, not one and a half
+        assert_eq!(producer.push_frames(&[1.0, 10.0, 2.0, 20.0], 2), 2);
+        assert_eq!(monitor.overruns(), 3);
+        assert_eq!(consumer.skip(1), 1);
+        assert_eq!(consumer.pop_slice(&mut output[..2]), 2);
+        assert_eq!(output[..2], [1.0, 10.0]);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn test_across_threads() {
+        let (mut producer, mut consumer) = ring_buffer(64);
+        let writer = std::thread::spawn(move || {
+            let mut next = 0;
+            while next < 10_000 {
+                let block: Vec<f32> = (next..next + 7).map(|i| i as f32).collect();
+                next += producer.push_slice(&block);
+            }
+        });
+
+        let mut expected = 0;
+        let mut output = [0.0; 5];
+        while expected < 10_000 {
+            let count = consumer.len().min(output.len());
+            consumer.pop_slice(&mut output[..count]);
+            for &sample in &output[..count] {
+                assert_eq!(sample, expected as f32);
+                expected += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+}
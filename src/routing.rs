@@ -0,0 +1,138 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::str::FromStr;
+
+// A single input channel to output channel connection with a linear gain
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct Route {
+    pub input: usize,
+    pub output: usize,
+    #[serde(default = "unity_gain")]
+    pub gain: f32,
+}
+
+fn unity_gain() -> f32 {
+    1.0
+}
+
+// Parses `IN:OUT`, `IN:OUT=GAIN` or `IN:OUT=GAINdB`, e.g. `0:1=0.5` or `2:0=-6dB`
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (channels, gain) = match s.split_once('=') {
+            Some((channels, gain)) => (channels, Some(gain.trim())),
+            None => (s, None),
+        };
+        let (input, output) = channels
+            .split_once(':')
+            .with_context(|| format!("Route '{s}' should look like IN:OUT[=GAIN]"))?;
+        let gain = match gain {
+            Some(gain) => match gain.strip_suffix("dB").or_else(|| gain.strip_suffix("db")) {
+                Some(db) => 10f32.powf(db.trim().parse::<f32>()? / 20.0),
+                None => gain.parse()?,
+            },
+            None => 1.0,
+        };
+        Ok(Self {
+            input: input.trim().parse().with_context(|| format!("Bad input channel in route '{s}'"))?,
+            output: output.trim().parse().with_context(|| format!("Bad output channel in route '{s}'"))?,
+            gain,
+        })
+    }
+}
+
+// Mixes interleaved frames of `inputs` channels into frames of `outputs`
+// channels, every output being a weighted sum of the inputs
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingMatrix {
+    inputs: usize,
+    outputs: usize,
+    // Row per output channel, column per input channel
+    gains: Vec<f32>,
+}
+
+impl RoutingMatrix {
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        Self {
+            inputs,
+            outputs,
+            gains: vec![0.0; inputs * outputs],
+        }
+    }
+
+    // Sends input channel `i` to output channel `i % outputs`, so mono
+    // inputs are spread across the outputs in turn
+    pub fn spread(inputs: usize, outputs: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(outputs > 0, "There are no output channels to spread the inputs across");
+        let mut matrix = Self::new(inputs, outputs);
+        for input in 0..inputs {
+            matrix.set(input, input % outputs, 1.0);
+        }
+        Ok(matrix)
+    }
+
+    pub fn with_routes(inputs: usize, outputs: usize, routes: &[Route]) -> anyhow::Result<Self> {
+        let mut matrix = Self::new(inputs, outputs);
+        for route in routes {
+            anyhow::ensure!(
+                route.input < inputs && route.output < outputs,
+                "Route {}:{} is outside of the {inputs}x{outputs} matrix",
+                route.input,
+                route.output
+            );
+            matrix.set(route.input, route.output, route.gain);
+        }
+        Ok(matrix)
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    pub fn set(&mut self, input: usize, output: usize, gain: f32) {
+        self.gains[output * self.inputs + input] = gain;
+    }
+
+    pub fn gain(&self, input: usize, output: usize) -> f32 {
+        self.gains[output * self.inputs + input]
+    }
+
+    pub fn process(&self, input: &[f32], output: &mut [f32]) {
+        for (in_frame, out_frame) in input.chunks_exact(self.inputs).zip(output.chunks_exact_mut(self.outputs)) {
+            for (sample, gains) in out_frame.iter_mut().zip(self.gains.chunks_exact(self.inputs)) {
+                *sample = in_frame.iter().zip(gains).map(|(&x, &g)| x * g).sum();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_matrix() {
+        let routes: Vec<Route> = ["0:0", "1:1=0.5", "2:0=-6dB", "2:1=-6dB"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let matrix = RoutingMatrix::with_routes(3, 2, &routes).unwrap();
+        assert!((matrix.gain(2, 1) - 0.5012).abs() < 1e-4);
+
+        let input = [1.0, 1.0, 1.0, 0.0, 2.0, 0.0];
+        let mut output = [0.0; 4];
+        matrix.process(&input, &mut output);
+        assert!((output[0] - 1.5012).abs() < 1e-4);
+        assert!((output[1] - 1.0012).abs() < 1e-4);
+        assert_eq!(output[2..], [0.0, 1.0]);
+
+        assert!(RoutingMatrix::with_routes(3, 2, &["0:2".parse().unwrap()]).is_err());
+        assert!(RoutingMatrix::spread(3, 0).is_err());
+        assert!("0-1".parse::<Route>().is_err());
+    }
+}
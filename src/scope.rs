@@ -0,0 +1,139 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::octave::FilterBank;
+use crate::processor::{Chain, Processor};
+use crate::ring_buffer::{ring_buffer, Consumer, Producer};
+use crate::timing::{TimingHandle, TimingMonitor};
+use crate::virtual_audio::AudioBackend;
+use crate::window::Window;
+
+// Length of the window the level is measured over
+const LEVEL_WINDOW: Duration = Duration::from_millis(100);
+// Frames taken from the input at a time
+const BLOCK_FRAMES: usize = 2048;
+
+// The input callback's end of a `Scope`: the callback timing and the
+// captured samples
+pub struct ScopeInput {
+    monitor: TimingMonitor,
+    producer: Producer,
+    channels: usize,
+}
+
+impl ScopeInput {
+    pub fn process(&mut self, data: &[f32], info: &cpal::InputCallbackInfo) {
+        self.monitor.record_input(info, data.len());
+        self.producer.push_frames(data, self.channels);
+    }
+}
+
+// An input stream feeding `input`, which is shared so that a supervisor can
+// hand it to every stream it builds. The lock is only contended while one
+// stream replaces another.
+pub fn input_stream<B, E>(
+    device: &B,
+    config: &cpal::StreamConfig,
+    input: Arc<Mutex<ScopeInput>>,
+    error: E,
+) -> anyhow::Result<B::Stream>
+where
+    B: AudioBackend,
+    E: FnMut(cpal::StreamError) + Send + 'static,
+{
+    device.build_input(
+        config,
+        move |data: &[f32], info: &cpal::InputCallbackInfo| {
+            if let Ok(mut input) = input.try_lock() {
+                input.process(data, info);
+            }
+        },
+        error,
+    )
+}
+
+// Captured audio the way an oscilloscope with a level meter and a real-time
+// analyser shows it: through the filters first, then into the level window,
+// the analyser and the latest samples for the trace
+pub struct Scope {
+    consumer: Consumer,
+    timing: TimingHandle,
+    channels: usize,
+    block: Vec<f32>,
+    history: Vec<f32>,
+    history_len: usize,
+    filters: Chain,
+    window: Window,
+    analyser: FilterBank,
+}
+
+impl Scope {
+    // Keeps the latest `history_len` samples for the trace, and room for a
+    // second of input between updates
+    pub fn new(
+        sample_rate: u32,
+        channels: usize,
+        history_len: usize,
+        filters: Chain,
+        analyser: FilterBank,
+    ) -> (Self, ScopeInput) {
+        let monitor = TimingMonitor::new(sample_rate, channels);
+        let (producer, consumer) = ring_buffer(sample_rate as usize * channels);
+        let scope = Self {
+            consumer,
+            timing: monitor.handle(),
+            channels,
+            block: vec![0.0; BLOCK_FRAMES * channels],
+            history: vec![0.0; history_len],
+            history_len,
+            filters,
+            window: Window::with_duration(LEVEL_WINDOW, sample_rate),
+            analyser,
+        };
+        (scope, ScopeInput { monitor, producer, channels })
+    }
+
+    // Takes in every whole frame captured since the last update
+    pub fn update(&mut self) {
+        loop {
+            let count = (self.consumer.len() / self.channels * self.channels).min(self.block.len());
+            if count == 0 {
+                break;
+            }
+            let block = &mut self.block[..count];
+            self.consumer.pop_slice(block);
+            self.filters.process(block);
+            self.window.add_samples(block);
+            self.analyser.process(block);
+            self.history.extend_from_slice(block);
+            if self.history.len() > self.history_len {
+                self.history.drain(..self.history.len() - self.history_len);
+            }
+        }
+    }
+
+    // The latest samples, oldest first
+    pub fn history(&self) -> &[f32] {
+        &self.history
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.window
+    }
+
+    pub fn analyser(&self) -> &FilterBank {
+        &self.analyser
+    }
+
+    pub fn analyser_mut(&mut self) -> &mut FilterBank {
+        &mut self.analyser
+    }
+
+    pub fn timing(&self) -> &TimingHandle {
+        &self.timing
+    }
+}
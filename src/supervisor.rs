@@ -0,0 +1,365 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use derive_builder::Builder;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::device::{default_device, named_device, Direction};
+use crate::virtual_audio::AudioBackend;
+
+// Error callback handed to the stream builder; it reports to the supervisor
+pub type ErrorCallback = Box<dyn FnMut(cpal::StreamError) + Send + 'static>;
+
+// Where a supervisor looks for its devices. It is moved to the supervisor
+// thread, so a cpal host is looked up by its id there.
+pub trait DeviceLookup: Send + 'static {
+    type Device: AudioBackend;
+
+    fn default_device(&self, direction: Direction) -> Option<Self::Device>;
+
+    // The device with exactly this name, if it is there right now
+    fn named_device(&self, direction: Direction, name: &str) -> anyhow::Result<Option<Self::Device>>;
+
+    fn device_name(&self, device: &Self::Device) -> anyhow::Result<String>;
+}
+
+impl DeviceLookup for cpal::HostId {
+    type Device = cpal::Device;
+
+    fn default_device(&self, direction: Direction) -> Option<cpal::Device> {
+        default_device(&cpal::host_from_id(*self).ok()?, direction)
+    }
+
+    fn named_device(&self, direction: Direction, name: &str) -> anyhow::Result<Option<cpal::Device>> {
+        named_device(&cpal::host_from_id(*self)?, direction, name)
+    }
+
+    fn device_name(&self, device: &cpal::Device) -> anyhow::Result<String> {
+        Ok(device.name()?)
+    }
+}
+
+#[derive(Builder)]
+pub struct SupervisorConfig {
+    direction: Direction,
+    // Device to open by name; the host's default device when empty
+    #[builder(default)]
+    device: Option<String>,
+    // Open the default device while the named one is missing
+    #[builder(default = "true")]
+    fallback_to_default: bool,
+    #[builder(default = "Duration::from_millis(100)")]
+    initial_backoff: Duration,
+    #[builder(default = "Duration::from_secs(5)")]
+    max_backoff: Duration,
+    // How often to look for the named device while on the fallback
+    #[builder(default = "Duration::from_secs(1)")]
+    recheck_interval: Duration,
+}
+
+// Exponentially growing delays between reconnection attempts
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, next: initial, attempts: 0 }
+    }
+
+    // The delay before the next attempt, doubling up to the maximum
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+        self.attempts = 0;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SupervisorEvent {
+    // The stream is running on this device
+    Connected { device: String, fallback: bool },
+    // The running stream reported an error and is being torn down
+    Disconnected { error: String },
+    // The named device is back; the fallback stream is being replaced
+    Returned { device: String },
+    // Opening a stream failed; the next attempt is after `delay`
+    Retrying { attempt: u32, delay: Duration, error: String },
+}
+
+impl fmt::Display for SupervisorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connected { device, fallback: false } => write!(f, "connected to {device}"),
+            Self::Connected { device, fallback: true } => write!(f, "connected to fallback device {device}"),
+            Self::Disconnected { error } => write!(f, "disconnected: {error}"),
+            Self::Returned { device } => write!(f, "{device} is back, switching to it"),
+            Self::Retrying { attempt, delay, error } => {
+                write!(f, "attempt {attempt} failed ({error}), retrying in {:.1} s", delay.as_secs_f64())
+            }
+        }
+    }
+}
+
+enum Control {
+    Error(cpal::StreamError),
+    Stop,
+}
+
+// Keeps a stream running across device errors.
+//
+// The stream lives on a supervisor thread, since cpal streams cannot always
+// be moved between threads. Devices are found through `lookup`, e.g. a cpal
+// host id or a `VirtualHost`. `build` is called with the device to open and an
+// error callback to pass to cpal; whenever the stream reports an error it is
+// dropped and `build` is called again, on the same device or the fallback,
+// with exponential backoff between failed attempts. While on the fallback,
+// the named device is looked for every `recheck_interval` and the stream
+// moved back to it once it reappears. Anything the data
+// callback needs to keep across rebuilds has to be shared with it, e.g.
+// behind a mutex that the callback only `try_lock`s.
+pub struct SupervisedStream {
+    control: Sender<Control>,
+    events: Receiver<SupervisorEvent>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SupervisedStream {
+    pub fn spawn<L, F>(config: SupervisorConfig, lookup: L, mut build: F) -> Self
+    where
+        L: DeviceLookup,
+        F: FnMut(&L::Device, ErrorCallback) -> anyhow::Result<<L::Device as AudioBackend>::Stream> + Send + 'static,
+    {
+        let (control, control_rx) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let error_tx = control.clone();
+        let thread = std::thread::spawn(move || {
+            let mut backoff = Backoff::new(config.initial_backoff, config.max_backoff);
+            loop {
+                let stream = find_device(&lookup, &config).and_then(|(device, fallback)| {
+                    let error_tx = error_tx.clone();
+                    let stream = build(&device, Box::new(move |err| {
+                        let _ = error_tx.send(Control::Error(err));
+                    }))?;
+                    stream.play()?;
+                    Ok((stream, lookup.device_name(&device)?, fallback))
+                });
+
+                match stream {
+                    Ok((stream, device, fallback)) => {
+                        backoff.reset();
+                        let _ = events_tx.send(SupervisorEvent::Connected { device, fallback });
+                        let event = loop {
+                            let control = if fallback {
+                                control_rx.recv_timeout(config.recheck_interval)
+                            } else {
+                                control_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                            };
+                            match control {
+                                Ok(Control::Error(err)) => break SupervisorEvent::Disconnected { error: err.to_string() },
+                                Err(RecvTimeoutError::Timeout) => {
+                                    if let Some(name) = &config.device
+                                        && lookup.named_device(config.direction, name).is_ok_and(|d| d.is_some())
+                                    {
+                                        break SupervisorEvent::Returned { device: name.clone() };
+                                    }
+                                }
+                                Ok(Control::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                            }
+                        };
+                        drop(stream);
+                        let _ = events_tx.send(event);
+                        // A failing stream often reports several errors
+                        loop {
+                            match control_rx.try_recv() {
+                                Ok(Control::Error(_)) => continue,
+                                Ok(Control::Stop) => return,
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        let delay = backoff.next_delay();
+                        let _ = events_tx.send(SupervisorEvent::Retrying {
+                            attempt: backoff.attempts(),
+                            delay,
+                            error: format!("{err:#}"),
+                        });
+                        // Errors from a stream that is already gone are stale
+                        let deadline = std::time::Instant::now() + delay;
+                        loop {
+                            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                            match control_rx.recv_timeout(remaining) {
+                                Ok(Control::Error(_)) => continue,
+                                Err(RecvTimeoutError::Timeout) => break,
+                                Ok(Control::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            control,
+            events,
+            thread: Some(thread),
+        }
+    }
+
+    // Events since the last call, without blocking
+    pub fn try_events(&self) -> Vec<SupervisorEvent> {
+        self.events.try_iter().collect()
+    }
+
+    // Blocks until the next event
+    pub fn next_event(&self) -> Option<SupervisorEvent> {
+        self.events.recv().ok()
+    }
+}
+
+impl Drop for SupervisedStream {
+    fn drop(&mut self) {
+        let _ = self.control.send(Control::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The device to open and whether it is the fallback for a missing one
+fn find_device<L: DeviceLookup>(lookup: &L, config: &SupervisorConfig) -> anyhow::Result<(L::Device, bool)> {
+    let default = || lookup.default_device(config.direction);
+    let Some(name) = &config.device else {
+        return default().map(|d| (d, false)).ok_or_else(|| anyhow::anyhow!("No default {} device", config.direction));
+    };
+
+    if let Some(device) = lookup.named_device(config.direction, name)? {
+        return Ok((device, false));
+    }
+    if config.fallback_to_default
+        && let Some(device) = default()
+    {
+        return Ok((device, true));
+    }
+    anyhow::bail!("No {} device named '{name}'", config.direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_audio::{VirtualClock, VirtualDevice, VirtualDeviceConfigBuilder, VirtualHost, WavSource};
+    use std::sync::{Arc, Mutex};
+
+    // The supervisor runs on wall-clock time, the devices on the virtual clock
+    fn next_event(stream: &SupervisedStream) -> SupervisorEvent {
+        stream.events.recv_timeout(Duration::from_secs(5)).expect("No supervisor event")
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_millis() as u64).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_reconnects_to_fallback_and_returns() {
+        let clock = VirtualClock::new();
+        let host = VirtualHost::new();
+        let device = |level: f32| {
+            let device = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().build().unwrap());
+            device.set_source(WavSource::from_samples(vec![level], 1, true));
+            device
+        };
+        let builtin = device(0.25);
+        let usb = device(0.5);
+        host.plug("Built-in", Direction::Input, &builtin);
+        host.plug("USB", Direction::Input, &usb);
+
+        // The level of the device the stream runs on
+        let level = Arc::new(Mutex::new(0.0));
+        let captured = level.clone();
+        let config = cpal::StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(48000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let stream = SupervisedStream::spawn(
+            SupervisorConfigBuilder::default()
+                .direction(Direction::Input)
+                .device(Some("USB".to_string()))
+                .initial_backoff(Duration::from_millis(5))
+                .max_backoff(Duration::from_millis(20))
+                .recheck_interval(Duration::from_millis(5))
+                .build()
+                .unwrap(),
+            host.clone(),
+            move |device, on_error| {
+                let captured = captured.clone();
+                device.build_input(
+                    &config,
+                    move |data: &[f32], _: &cpal::InputCallbackInfo| *captured.lock().unwrap() = data[0],
+                    on_error,
+                )
+            },
+        );
+        let connected = |device: &str, fallback| SupervisorEvent::Connected { device: device.to_string(), fallback };
+        let level_after = |duration| {
+            clock.advance(duration);
+            *level.lock().unwrap()
+        };
+        assert_eq!(next_event(&stream), connected("USB", false));
+        assert_eq!(level_after(Duration::from_millis(10)), 0.5);
+
+        // With nothing plugged in, attempts fail until the default is back
+        host.unplug("Built-in");
+        host.unplug("USB");
+        assert!(matches!(next_event(&stream), SupervisorEvent::Disconnected { .. }));
+        let SupervisorEvent::Retrying { attempt: 1, delay, error } = next_event(&stream) else {
+            panic!("Expected the first retry");
+        };
+        assert_eq!(delay, Duration::from_millis(5));
+        assert_eq!(error, "No input device named 'USB'");
+        host.plug("Built-in", Direction::Input, &builtin);
+        let event = loop {
+            match next_event(&stream) {
+                SupervisorEvent::Retrying { .. } => continue,
+                event => break event,
+            }
+        };
+        assert_eq!(event, connected("Built-in", true));
+        assert_eq!(level_after(Duration::from_millis(10)), 0.25);
+
+        // The named device comes back and the stream moves to it
+        host.plug("USB", Direction::Input, &usb);
+        assert_eq!(next_event(&stream), SupervisorEvent::Returned { device: "USB".to_string() });
+        assert_eq!(next_event(&stream), connected("USB", false));
+        assert_eq!(level_after(Duration::from_millis(10)), 0.5);
+
+        // Connecting reset the backoff
+        host.unplug("Built-in");
+        host.unplug("USB");
+        assert!(matches!(next_event(&stream), SupervisorEvent::Disconnected { .. }));
+        assert!(matches!(next_event(&stream), SupervisorEvent::Retrying { attempt: 1, .. }));
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// A callback arriving this many buffer periods after the previous one is
+// taken to mean that callbacks in between were missed
+const MISSED_THRESHOLD: f64 = 1.5;
+
+// Tracks the timing of a stream's data callbacks from the timestamps cpal
+// passes to them.
+//
+// The monitor lives inside the callback and only does arithmetic and atomic
+// stores, so it is safe to call on the audio thread; `TimingHandle` reads the
+// results from anywhere else.
+pub struct TimingMonitor {
+    sample_rate: u32,
+    channels: usize,
+    first_callback: Option<cpal::StreamInstant>,
+    last_callback: Option<cpal::StreamInstant>,
+    intervals: u64,
+    interval_sum: f64,
+    interval_sum_sq: f64,
+    stats: Arc<TimingStats>,
+}
+
+#[derive(Default)]
+struct TimingStats {
+    callbacks: AtomicU64,
+    frames: AtomicU64,
+    missed: AtomicU64,
+    // Nanoseconds, except for the f64 bit patterns of the period statistics
+    last_callback: AtomicU64,
+    buffer_period: AtomicU64,
+    mean_period: AtomicU64,
+    jitter: AtomicU64,
+    min_period: AtomicU64,
+    max_period: AtomicU64,
+    latency: AtomicU64,
+    max_latency: AtomicU64,
+}
+
+impl TimingMonitor {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let stats = TimingStats {
+            min_period: AtomicU64::new(u64::MAX),
+            ..Default::default()
+        };
+        Self {
+            sample_rate,
+            channels,
+            first_callback: None,
+            last_callback: None,
+            intervals: 0,
+            interval_sum: 0.0,
+            interval_sum_sq: 0.0,
+            stats: Arc::new(stats),
+        }
+    }
+
+    // Records an input callback delivering `samples` interleaved samples
+    pub fn record_input(&mut self, info: &cpal::InputCallbackInfo, samples: usize) {
+        let timestamp = info.timestamp();
+        self.record(timestamp.callback, timestamp.callback.duration_since(&timestamp.capture), samples);
+    }
+
+    // Records an output callback asked for `samples` interleaved samples
+    pub fn record_output(&mut self, info: &cpal::OutputCallbackInfo, samples: usize) {
+        let timestamp = info.timestamp();
+        self.record(timestamp.callback, timestamp.playback.duration_since(&timestamp.callback), samples);
+    }
+
+    fn record(&mut self, callback: cpal::StreamInstant, latency: Option<Duration>, samples: usize) {
+        let stats = &*self.stats;
+        let frames = samples / self.channels.max(1);
+        let buffer_period = frames as f64 / self.sample_rate as f64 * 1e9;
+
+        stats.callbacks.fetch_add(1, Ordering::Relaxed);
+        stats.frames.fetch_add(frames as u64, Ordering::Relaxed);
+        stats.buffer_period.store(buffer_period as u64, Ordering::Relaxed);
+
+        let first = *self.first_callback.get_or_insert(callback);
+        if let Some(elapsed) = callback.duration_since(&first) {
+            stats.last_callback.store(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        }
+
+        if let Some(interval) = self.last_callback.and_then(|last| callback.duration_since(&last)) {
+            let interval = interval.as_nanos() as f64;
+            if buffer_period > 0.0 && interval > buffer_period * MISSED_THRESHOLD {
+                let missed = (interval / buffer_period).round() as u64 - 1;
+                stats.missed.fetch_add(missed, Ordering::Relaxed);
+            }
+
+            self.intervals += 1;
+            self.interval_sum += interval;
+            self.interval_sum_sq += interval * interval;
+            let mean = self.interval_sum / self.intervals as f64;
+            let variance = (self.interval_sum_sq / self.intervals as f64 - mean * mean).max(0.0);
+            stats.mean_period.store(mean.to_bits(), Ordering::Relaxed);
+            stats.jitter.store(variance.sqrt().to_bits(), Ordering::Relaxed);
+            stats.min_period.fetch_min(interval as u64, Ordering::Relaxed);
+            stats.max_period.fetch_max(interval as u64, Ordering::Relaxed);
+        }
+        self.last_callback = Some(callback);
+
+        if let Some(latency) = latency {
+            let latency = latency.as_nanos() as u64;
+            stats.latency.store(latency, Ordering::Relaxed);
+            stats.max_latency.fetch_max(latency, Ordering::Relaxed);
+        }
+    }
+
+    pub fn handle(&self) -> TimingHandle {
+        TimingHandle { stats: self.stats.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct TimingHandle {
+    stats: Arc<TimingStats>,
+}
+
+impl TimingHandle {
+    pub fn snapshot(&self) -> TimingSnapshot {
+        let stats = &*self.stats;
+        let nanos = |value: &AtomicU64| Duration::from_nanos(value.load(Ordering::Relaxed));
+        let float_nanos = |value: &AtomicU64| Duration::from_secs_f64(f64::from_bits(value.load(Ordering::Relaxed)) / 1e9);
+        let min_period = stats.min_period.load(Ordering::Relaxed);
+        TimingSnapshot {
+            callbacks: stats.callbacks.load(Ordering::Relaxed),
+            frames: stats.frames.load(Ordering::Relaxed),
+            missed_callbacks: stats.missed.load(Ordering::Relaxed),
+            elapsed: nanos(&stats.last_callback),
+            buffer_period: nanos(&stats.buffer_period),
+            mean_period: float_nanos(&stats.mean_period),
+            jitter: float_nanos(&stats.jitter),
+            min_period: Duration::from_nanos(if min_period == u64::MAX { 0 } else { min_period }),
+            max_period: nanos(&stats.max_period),
+            latency: nanos(&stats.latency),
+            max_latency: nanos(&stats.max_latency),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingSnapshot {
+    pub callbacks: u64,
+    pub frames: u64,
+    pub missed_callbacks: u64,
+    // Stream time from the first callback to the latest one
+    pub elapsed: Duration,
+    // Duration of audio in the latest buffer
+    pub buffer_period: Duration,
+    // Statistics of the time between consecutive callbacks
+    pub mean_period: Duration,
+    pub jitter: Duration,
+    pub min_period: Duration,
+    pub max_period: Duration,
+    // Capture to callback for inputs, callback to playback for outputs
+    pub latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl fmt::Display for TimingSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{} callbacks, period {:.2} ms (buffer {:.2} ms, jitter {:.3} ms, {:.2}-{:.2} ms), \
+             latency {:.2} ms (max {:.2} ms), {} missed",
+            self.callbacks,
+            ms(self.mean_period),
+            ms(self.buffer_period),
+            ms(self.jitter),
+            ms(self.min_period),
+            ms(self.max_period),
+            ms(self.latency),
+            ms(self.max_latency),
+            self.missed_callbacks,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::{InputCallbackInfo, InputStreamTimestamp, StreamInstant};
+
+    #[test]
+    fn test_timing_monitor() {
+        // 480 frames at 48 kHz is a 10ms period
+        let mut monitor = TimingMonitor::new(48000, 2);
+        let handle = monitor.handle();
+        let callback_times_ms = [0, 10, 21, 30, 40, 70, 80];
+        for &ms in &callback_times_ms {
+            let callback = StreamInstant::new(1, ms * 1_000_000);
+            let capture = StreamInstant::new(1, ms * 1_000_000).sub(Duration::from_millis(3)).unwrap();
+            monitor.record_input(&InputCallbackInfo::new(InputStreamTimestamp { callback, capture }), 960);
+        }
+
+        let snapshot = handle.snapshot();
+        assert_eq!(snapshot.callbacks, 7);
+        assert_eq!(snapshot.frames, 7 * 480);
+        assert_eq!(snapshot.missed_callbacks, 2);
+        assert_eq!(snapshot.elapsed, Duration::from_millis(80));
+        assert_eq!(snapshot.buffer_period, Duration::from_millis(10));
+        assert!((snapshot.mean_period.as_secs_f64() - 0.080 / 6.0).abs() < 1e-6);
+        assert_eq!(snapshot.min_period, Duration::from_millis(9));
+        assert_eq!(snapshot.max_period, Duration::from_millis(30));
+        assert_eq!(snapshot.latency, Duration::from_millis(3));
+        assert!(snapshot.jitter > Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,602 @@
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, Sample, SizedSample};
+use derive_builder::Builder;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::device::Direction;
+use crate::generator::Signal;
+use crate::render::read_wav;
+use crate::supervisor::DeviceLookup;
+
+// The parts of a device the processing code needs: building streams of any
+// sample format and starting them. Implemented by `cpal::Device` for real
+// hardware and by `VirtualDevice` for headless runs.
+pub trait AudioBackend {
+    type Stream: StreamTrait;
+
+    fn build_input<T, D, E>(&self, config: &cpal::StreamConfig, data: D, error: E) -> anyhow::Result<Self::Stream>
+    where
+        T: SizedSample + FromSample<f32> + Send + 'static,
+        D: FnMut(&[T], &cpal::InputCallbackInfo) + Send + 'static,
+        E: FnMut(cpal::StreamError) + Send + 'static;
+
+    fn build_output<T, D, E>(&self, config: &cpal::StreamConfig, data: D, error: E) -> anyhow::Result<Self::Stream>
+    where
+        T: SizedSample + Send + 'static,
+        f32: FromSample<T>,
+        D: FnMut(&mut [T], &cpal::OutputCallbackInfo) + Send + 'static,
+        E: FnMut(cpal::StreamError) + Send + 'static;
+}
+
+impl AudioBackend for cpal::Device {
+    type Stream = cpal::Stream;
+
+    fn build_input<T, D, E>(&self, config: &cpal::StreamConfig, data: D, error: E) -> anyhow::Result<Self::Stream>
+    where
+        T: SizedSample + FromSample<f32> + Send + 'static,
+        D: FnMut(&[T], &cpal::InputCallbackInfo) + Send + 'static,
+        E: FnMut(cpal::StreamError) + Send + 'static,
+    {
+        Ok(self.build_input_stream(config, data, error, None)?)
+    }
+
+    fn build_output<T, D, E>(&self, config: &cpal::StreamConfig, data: D, error: E) -> anyhow::Result<Self::Stream>
+    where
+        T: SizedSample + Send + 'static,
+        f32: FromSample<T>,
+        D: FnMut(&mut [T], &cpal::OutputCallbackInfo) + Send + 'static,
+        E: FnMut(cpal::StreamError) + Send + 'static,
+    {
+        Ok(self.build_output_stream(config, data, error, None)?)
+    }
+}
+
+// Where a virtual input gets its samples from
+pub trait InputSource: Send {
+    // Fills an interleaved buffer with `channels` channels
+    fn fill(&mut self, buffer: &mut [f32], channels: usize);
+}
+
+// Generators play the same signal on every channel
+impl<S: Signal + Send> InputSource for S {
+    fn fill(&mut self, buffer: &mut [f32], channels: usize) {
+        for frame in buffer.chunks_mut(channels) {
+            frame.fill(self.next_value());
+        }
+    }
+}
+
+struct Silence;
+
+impl InputSource for Silence {
+    fn fill(&mut self, buffer: &mut [f32], _channels: usize) {
+        buffer.fill(0.0);
+    }
+}
+
+// Interleaved samples, e.g. from a WAV file. Stream channels beyond the
+// file's repeat its channels in turn; after the end comes silence unless
+// the samples loop.
+pub struct WavSource {
+    samples: Vec<f32>,
+    channels: usize,
+    position: usize,
+    looped: bool,
+}
+
+impl WavSource {
+    pub fn from_samples(samples: Vec<f32>, channels: usize, looped: bool) -> Self {
+        Self { samples, channels: channels.max(1), position: 0, looped }
+    }
+
+    pub fn open(path: impl AsRef<Path>, looped: bool) -> anyhow::Result<Self> {
+        let wav = read_wav(path)?;
+        Ok(Self::from_samples(wav.samples, wav.channels, looped))
+    }
+
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+}
+
+impl InputSource for WavSource {
+    fn fill(&mut self, buffer: &mut [f32], channels: usize) {
+        for frame in buffer.chunks_mut(channels) {
+            if self.looped && self.frames() > 0 {
+                self.position %= self.frames();
+            }
+            if self.position < self.frames() {
+                let source = &self.samples[self.position * self.channels..(self.position + 1) * self.channels];
+                for (channel, sample) in frame.iter_mut().enumerate() {
+                    *sample = source[channel % self.channels];
+                }
+                self.position += 1;
+            } else {
+                frame.fill(0.0);
+            }
+        }
+    }
+}
+
+// What an output device plays, arriving at an input as through a cable. Every
+// input frame takes the oldest frame played and not yet taken, or silence when
+// the input has caught up; frames taken here are gone from `take_captured`.
+pub struct Cable {
+    captured: Arc<Mutex<Vec<f32>>>,
+    channels: usize,
+}
+
+impl InputSource for Cable {
+    fn fill(&mut self, buffer: &mut [f32], channels: usize) {
+        let mut captured = self.captured.lock().expect("Virtual capture poisoned");
+        let frames = (captured.len() / self.channels).min(buffer.len() / channels);
+        for (frame, played) in buffer.chunks_mut(channels).zip(captured.chunks_exact(self.channels)) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = played[channel % self.channels];
+            }
+        }
+        buffer[frames * channels..].fill(0.0);
+        captured.drain(..frames * self.channels);
+    }
+}
+
+// Simulated time shared by virtual devices. Nothing happens until the clock
+// is advanced, which runs every due callback in order on the calling thread,
+// so a test can run minutes of audio in milliseconds and deterministically.
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+#[derive(Default)]
+struct ClockState {
+    now: Duration,
+    streams: Vec<Weak<Mutex<StreamState>>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+        loop {
+            let streams: Vec<Arc<Mutex<StreamState>>> = {
+                let mut clock = self.lock();
+                clock.streams.retain(|stream| stream.strong_count() > 0);
+                clock.streams.iter().filter_map(Weak::upgrade).collect()
+            };
+            let next = streams
+                .iter()
+                .filter_map(|stream| {
+                    let state = stream.lock().expect("Virtual stream callback panicked");
+                    state.playing.then_some((state.due, stream))
+                })
+                .min_by_key(|(due, _)| *due);
+            let Some((due, stream)) = next.filter(|(due, _)| *due <= target) else {
+                break;
+            };
+            self.lock().now = due;
+            stream.lock().expect("Virtual stream callback panicked").run();
+        }
+        self.lock().now = target;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ClockState> {
+        self.state.lock().expect("Virtual clock poisoned")
+    }
+}
+
+#[derive(Builder, Clone)]
+pub struct VirtualDeviceConfig {
+    // Frames per callback
+    #[builder(default = "256")]
+    buffer_size: usize,
+    // Callbacks arrive up to this much later than scheduled
+    #[builder(default)]
+    jitter: Duration,
+    // Probability that a callback never happens; inputs lose the buffer
+    // and outputs play silence
+    #[builder(default)]
+    dropout_rate: f64,
+    // How much faster than nominal the device clock runs
+    #[builder(default)]
+    drift_ppm: f64,
+    // Reported capture or playback latency
+    #[builder(default)]
+    latency: Duration,
+    #[builder(default = "1")]
+    seed: u64,
+}
+
+// A device on a `VirtualClock`. Inputs read from the source set with
+// `set_source`, silence by default; outputs append what they play to a
+// capture buffer. Clones are handles to the same device.
+#[derive(Clone)]
+pub struct VirtualDevice {
+    clock: VirtualClock,
+    config: VirtualDeviceConfig,
+    source: Arc<Mutex<Box<dyn InputSource>>>,
+    captured: Arc<Mutex<Vec<f32>>>,
+    streams: Arc<Mutex<Vec<Weak<Mutex<StreamState>>>>>,
+}
+
+impl VirtualDevice {
+    pub fn new(clock: &VirtualClock, config: VirtualDeviceConfig) -> Self {
+        Self {
+            clock: clock.clone(),
+            config,
+            source: Arc::new(Mutex::new(Box::new(Silence))),
+            captured: Default::default(),
+            streams: Default::default(),
+        }
+    }
+
+    pub fn set_source(&self, source: impl InputSource + 'static) {
+        *self.source.lock().expect("Virtual stream callback panicked") = Box::new(source);
+    }
+
+    // Everything output streams played since the last call, interleaved
+    pub fn take_captured(&self) -> Vec<f32> {
+        std::mem::take(&mut *self.captured.lock().expect("Virtual stream callback panicked"))
+    }
+
+    // Source for an input that hears this device's output streams, which
+    // play `channels` channels
+    pub fn cable(&self, channels: usize) -> Cable {
+        Cable { captured: self.captured.clone(), channels: channels.max(1) }
+    }
+
+    // Stops every stream with `DeviceNotAvailable`, as unplugging would
+    pub fn disconnect(&self) {
+        for stream in self.streams.lock().expect("Virtual device poisoned").iter().filter_map(Weak::upgrade) {
+            let mut state = stream.lock().expect("Virtual stream callback panicked");
+            state.playing = false;
+            (state.error)(cpal::StreamError::DeviceNotAvailable);
+        }
+    }
+
+    fn register(&self, config: &cpal::StreamConfig, kind: StreamKind, error: ErrorCallback) -> VirtualStream {
+        let channels = config.channels as usize;
+        let frames = self.config.buffer_size;
+        let period = frames as f64 / config.sample_rate.0 as f64 / (1.0 + self.config.drift_ppm * 1e-6);
+        let state = Arc::new(Mutex::new(StreamState {
+            channels,
+            period,
+            jitter: self.config.jitter,
+            dropout_rate: self.config.dropout_rate,
+            latency: self.config.latency,
+            rng: Rng(self.config.seed.max(1)),
+            playing: false,
+            start: Duration::ZERO,
+            scheduled: 0,
+            due: Duration::ZERO,
+            callbacks: 0,
+            dropouts: 0,
+            buffer: vec![0.0; frames * channels],
+            kind,
+            error,
+        }));
+        self.clock.lock().streams.push(Arc::downgrade(&state));
+        self.streams.lock().expect("Virtual device poisoned").push(Arc::downgrade(&state));
+        VirtualStream { clock: self.clock.clone(), state }
+    }
+}
+
+// Streams run on f32 buffers; other sample formats are converted in a buffer
+// of their own
+impl AudioBackend for VirtualDevice {
+    type Stream = VirtualStream;
+
+    fn build_input<T, D, E>(&self, config: &cpal::StreamConfig, mut data: D, error: E) -> anyhow::Result<Self::Stream>
+    where
+        T: SizedSample + FromSample<f32> + Send + 'static,
+        D: FnMut(&[T], &cpal::InputCallbackInfo) + Send + 'static,
+        E: FnMut(cpal::StreamError) + Send + 'static,
+    {
+        let mut samples = vec![T::EQUILIBRIUM; self.config.buffer_size * config.channels as usize];
+        let data = move |buffer: &[f32], info: &cpal::InputCallbackInfo| {
+            for (sample, &value) in samples.iter_mut().zip(buffer) {
+                *sample = T::from_sample(value);
+            }
+            data(&samples, info);
+        };
+        let kind = StreamKind::Input { data: Box::new(data), source: self.source.clone() };
+        Ok(self.register(config, kind, Box::new(error)))
+    }
+
+    fn build_output<T, D, E>(&self, config: &cpal::StreamConfig, mut data: D, error: E) -> anyhow::Result<Self::Stream>
+    where
+        T: SizedSample + Send + 'static,
+        f32: FromSample<T>,
+        D: FnMut(&mut [T], &cpal::OutputCallbackInfo) + Send + 'static,
+        E: FnMut(cpal::StreamError) + Send + 'static,
+    {
+        let mut samples = vec![T::EQUILIBRIUM; self.config.buffer_size * config.channels as usize];
+        let data = move |buffer: &mut [f32], info: &cpal::OutputCallbackInfo| {
+            samples.fill(T::EQUILIBRIUM);
+            data(&mut samples, info);
+            for (value, &sample) in buffer.iter_mut().zip(&samples) {
+                *value = f32::from_sample(sample);
+            }
+        };
+        let kind = StreamKind::Output { data: Box::new(data), captured: self.captured.clone() };
+        Ok(self.register(config, kind, Box::new(error)))
+    }
+}
+
+// Named virtual devices coming and going, for finding them the way a cpal
+// host would. The first device plugged in for a direction is its default.
+#[derive(Clone, Default)]
+pub struct VirtualHost {
+    devices: Arc<Mutex<Vec<(String, Direction, VirtualDevice)>>>,
+}
+
+impl VirtualHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn plug(&self, name: &str, direction: Direction, device: &VirtualDevice) {
+        self.lock().push((name.to_string(), direction, device.clone()));
+    }
+
+    // Removes the device and disconnects its streams
+    pub fn unplug(&self, name: &str) {
+        let removed: Vec<_> = {
+            let mut devices = self.lock();
+            let (removed, kept) = devices.drain(..).partition(|(n, _, _)| n == name);
+            *devices = kept;
+            removed
+        };
+        for (_, _, device) in removed {
+            device.disconnect();
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<(String, Direction, VirtualDevice)>> {
+        self.devices.lock().expect("Virtual host poisoned")
+    }
+}
+
+impl DeviceLookup for VirtualHost {
+    type Device = VirtualDevice;
+
+    fn default_device(&self, direction: Direction) -> Option<VirtualDevice> {
+        self.lock().iter().find(|(_, d, _)| *d == direction).map(|(_, _, device)| device.clone())
+    }
+
+    fn named_device(&self, direction: Direction, name: &str) -> anyhow::Result<Option<VirtualDevice>> {
+        Ok(self.lock().iter().find(|(n, d, _)| n == name && *d == direction).map(|(_, _, device)| device.clone()))
+    }
+
+    fn device_name(&self, device: &VirtualDevice) -> anyhow::Result<String> {
+        self.lock()
+            .iter()
+            .find(|(_, _, d)| Arc::ptr_eq(&d.streams, &device.streams))
+            .map(|(name, _, _)| name.clone())
+            .ok_or_else(|| anyhow::anyhow!("The device was unplugged"))
+    }
+}
+
+type ErrorCallback = Box<dyn FnMut(cpal::StreamError) + Send>;
+type InputCallback = Box<dyn FnMut(&[f32], &cpal::InputCallbackInfo) + Send>;
+type OutputCallback = Box<dyn FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Send>;
+
+enum StreamKind {
+    Input {
+        data: InputCallback,
+        source: Arc<Mutex<Box<dyn InputSource>>>,
+    },
+    Output {
+        data: OutputCallback,
+        captured: Arc<Mutex<Vec<f32>>>,
+    },
+}
+
+struct StreamState {
+    channels: usize,
+    // Seconds between callbacks on the simulated clock
+    period: f64,
+    jitter: Duration,
+    dropout_rate: f64,
+    latency: Duration,
+    rng: Rng,
+    playing: bool,
+    start: Duration,
+    scheduled: u64,
+    due: Duration,
+    callbacks: u64,
+    dropouts: u64,
+    buffer: Vec<f32>,
+    kind: StreamKind,
+    error: ErrorCallback,
+}
+
+impl StreamState {
+    fn run(&mut self) {
+        let now = self.due;
+        let dropped = self.rng.next_f64() < self.dropout_rate;
+        let callback = instant(now);
+        match &mut self.kind {
+            StreamKind::Input { data, source } => {
+                source.lock().expect("Virtual input source panicked").fill(&mut self.buffer, self.channels);
+                if !dropped {
+                    let capture = instant(now.saturating_sub(self.latency));
+                    data(&self.buffer, &cpal::InputCallbackInfo::new(cpal::InputStreamTimestamp { callback, capture }));
+                }
+            }
+            StreamKind::Output { data, captured } => {
+                self.buffer.fill(0.0);
+                if !dropped {
+                    let playback = instant(now + self.latency);
+                    data(&mut self.buffer, &cpal::OutputCallbackInfo::new(cpal::OutputStreamTimestamp { callback, playback }));
+                }
+                captured.lock().expect("Virtual capture poisoned").extend_from_slice(&self.buffer);
+            }
+        }
+        if dropped {
+            self.dropouts += 1;
+        } else {
+            self.callbacks += 1;
+        }
+
+        // Jitter delays single callbacks without moving the schedule
+        self.scheduled += 1;
+        let ideal = self.start + Duration::from_secs_f64(self.scheduled as f64 * self.period);
+        let jitter = self.jitter.mul_f64(self.rng.next_f64());
+        self.due = (ideal + jitter).max(now);
+    }
+}
+
+fn instant(time: Duration) -> cpal::StreamInstant {
+    cpal::StreamInstant::new(time.as_secs() as i64, time.subsec_nanos())
+}
+
+// Stream on a virtual device; callbacks run while its clock is advanced
+pub struct VirtualStream {
+    clock: VirtualClock,
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl VirtualStream {
+    // Callbacks delivered so far
+    pub fn callbacks(&self) -> u64 {
+        self.lock().callbacks
+    }
+
+    // Callbacks dropped so far
+    pub fn dropouts(&self) -> u64 {
+        self.lock().dropouts
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.state.lock().expect("Virtual stream callback panicked")
+    }
+}
+
+impl StreamTrait for VirtualStream {
+    fn play(&self) -> Result<(), cpal::PlayStreamError> {
+        let now = self.clock.now();
+        let mut state = self.lock();
+        if !state.playing {
+            state.playing = true;
+            state.start = now;
+            state.scheduled = 0;
+            state.due = now;
+        }
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<(), cpal::PauseStreamError> {
+        self.lock().playing = false;
+        Ok(())
+    }
+}
+
+// xorshift64*, enough for reproducible jitter and dropouts
+struct Rng(u64);
+
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::Sequence;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn test_virtual_streams() {
+        let clock = VirtualClock::new();
+        let config = cpal::StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48000),
+            buffer_size: cpal::BufferSize::Default,
+        };
+
+        // 480 frames is a 10ms period; the input clock runs 1000 ppm fast
+        let input = VirtualDevice::new(
+            &clock,
+            VirtualDeviceConfigBuilder::default().buffer_size(480).drift_ppm(1000.0).build().unwrap(),
+        );
+        let mut sequence = Sequence::new((0..480).map(|i| i as f32).collect());
+        sequence.rewind();
+        input.set_source(sequence);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let input_received = received.clone();
+        let input_stream = input
+            .build_input(&config, move |data: &[f32], _: &_| input_received.lock().unwrap().extend_from_slice(data), |_| {})
+            .unwrap();
+
+        let output = VirtualDevice::new(
+            &clock,
+            VirtualDeviceConfigBuilder::default()
+                .buffer_size(480)
+                .jitter(Duration::from_millis(3))
+                .dropout_rate(0.1)
+                .build()
+                .unwrap(),
+        );
+        let errors = Arc::new(AtomicU64::new(0));
+        let output_errors = errors.clone();
+        let output_stream = output
+            .build_output(
+                &config,
+                |data: &mut [f32], _: &_| data.fill(0.5),
+                move |_| {
+                    output_errors.fetch_add(1, Ordering::Relaxed);
+                },
+            )
+            .unwrap();
+
+        input_stream.play().unwrap();
+        output_stream.play().unwrap();
+        // A little past 10s so that the jittered last output callback is in
+        clock.advance(Duration::from_millis(10_005));
+
+        // Callbacks at 0, 10ms, ... 10s, and one more as the fast clock
+        // gains 10ms over that time
+        assert_eq!(input_stream.callbacks(), 1002);
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1002 * 960);
+            assert_eq!(&received[..4], [0.0, 0.0, 1.0, 1.0]);
+            assert!(received[960..].iter().all(|&s| s == 0.0));
+        }
+
+        let dropouts = output_stream.dropouts();
+        assert_eq!(output_stream.callbacks() + dropouts, 1001);
+        assert!((50..150).contains(&dropouts), "{dropouts} dropouts");
+        let captured = output.take_captured();
+        assert_eq!(captured.len(), 1001 * 960);
+        assert_eq!(captured.iter().filter(|&&s| s == 0.0).count() as u64, dropouts * 960);
+
+        // Other sample formats arrive as f32
+        let converted = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().build().unwrap());
+        let converted_stream = converted
+            .build_output(&config, |data: &mut [i16], _: &_| data.fill(i16::MIN / 2), |_| {})
+            .unwrap();
+        converted_stream.play().unwrap();
+        clock.advance(Duration::from_millis(1));
+        assert!(converted.take_captured().iter().all(|&s| s == -0.5));
+
+        output.disconnect();
+        assert_eq!(errors.load(Ordering::Relaxed), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(output_stream.callbacks() + output_stream.dropouts(), 1001);
+    }
+}
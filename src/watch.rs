@@ -0,0 +1,219 @@
+use cpal::traits::{DeviceTrait, HostTrait};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+// The names of every device and the default devices of every host at one
+// point in time. Comparing two snapshots yields the hot-plug changes
+// between them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceSnapshot {
+    // (host, device) pairs
+    devices: BTreeSet<(String, String)>,
+    default_inputs: BTreeMap<String, String>,
+    default_outputs: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceChange {
+    Added { host: String, device: String },
+    Removed { host: String, device: String },
+    DefaultInputChanged { host: String, from: Option<String>, to: Option<String> },
+    DefaultOutputChanged { host: String, from: Option<String>, to: Option<String> },
+}
+
+impl fmt::Display for DeviceChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |device: &Option<String>| device.clone().unwrap_or_else(|| "none".to_string());
+        match self {
+            Self::Added { host, device } => write!(f, "{host}: added {device}"),
+            Self::Removed { host, device } => write!(f, "{host}: removed {device}"),
+            Self::DefaultInputChanged { host, from, to } => {
+                write!(f, "{host}: default input changed from {} to {}", name(from), name(to))
+            }
+            Self::DefaultOutputChanged { host, from, to } => {
+                write!(f, "{host}: default output changed from {} to {}", name(from), name(to))
+            }
+        }
+    }
+}
+
+impl DeviceSnapshot {
+    // Only device names are queried, so this is cheap enough to poll
+    pub fn collect() -> Self {
+        let mut snapshot = Self::default();
+        for host_id in cpal::available_hosts() {
+            let Ok(host) = cpal::host_from_id(host_id) else {
+                continue;
+            };
+            let host_name = host_id.name().to_string();
+            if let Some(name) = host.default_input_device().and_then(|d| d.name().ok()) {
+                snapshot.default_inputs.insert(host_name.clone(), name);
+            }
+            if let Some(name) = host.default_output_device().and_then(|d| d.name().ok()) {
+                snapshot.default_outputs.insert(host_name.clone(), name);
+            }
+            if let Ok(devices) = host.devices() {
+                for device in devices {
+                    if let Ok(name) = device.name() {
+                        snapshot.devices.insert((host_name.clone(), name));
+                    }
+                }
+            }
+        }
+        snapshot
+    }
+
+    // Whether a device with this name exists on any host
+    pub fn contains(&self, device: &str) -> bool {
+        self.devices.iter().any(|(_, name)| name == device)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.devices.iter().map(|(host, name)| (host.as_str(), name.as_str()))
+    }
+
+    // Changes that turn `self` into `newer`: removals first, then additions,
+    // then default device changes
+    pub fn changes(&self, newer: &DeviceSnapshot) -> Vec<DeviceChange> {
+        let mut changes = Vec::new();
+        for (host, device) in self.devices.difference(&newer.devices) {
+            changes.push(DeviceChange::Removed { host: host.clone(), device: device.clone() });
+        }
+        for (host, device) in newer.devices.difference(&self.devices) {
+            changes.push(DeviceChange::Added { host: host.clone(), device: device.clone() });
+        }
+
+        let hosts: BTreeSet<&String> = self
+            .default_inputs
+            .keys()
+            .chain(newer.default_inputs.keys())
+            .chain(self.default_outputs.keys())
+            .chain(newer.default_outputs.keys())
+            .collect();
+        for host in hosts {
+            let (from, to) = (self.default_inputs.get(host), newer.default_inputs.get(host));
+            if from != to {
+                changes.push(DeviceChange::DefaultInputChanged {
+                    host: host.clone(),
+                    from: from.cloned(),
+                    to: to.cloned(),
+                });
+            }
+            let (from, to) = (self.default_outputs.get(host), newer.default_outputs.get(host));
+            if from != to {
+                changes.push(DeviceChange::DefaultOutputChanged {
+                    host: host.clone(),
+                    from: from.cloned(),
+                    to: to.cloned(),
+                });
+            }
+        }
+        changes
+    }
+}
+
+// Re-enumerates devices on a background thread and queues the changes with
+// the time they were noticed. The thread stops when the watcher is dropped.
+pub struct DeviceWatcher {
+    changes: Receiver<(SystemTime, DeviceChange)>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    initial: DeviceSnapshot,
+}
+
+impl DeviceWatcher {
+    pub fn spawn(interval: Duration) -> Self {
+        let initial = DeviceSnapshot::collect();
+        let (changes_tx, changes) = mpsc::channel();
+        let (stop, stop_rx) = mpsc::channel::<()>();
+        let mut previous = initial.clone();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let current = DeviceSnapshot::collect();
+                let now = SystemTime::now();
+                for change in previous.changes(&current) {
+                    if changes_tx.send((now, change)).is_err() {
+                        return;
+                    }
+                }
+                previous = current;
+            }
+        });
+        Self {
+            changes,
+            stop: Some(stop),
+            thread: Some(thread),
+            initial,
+        }
+    }
+
+    // The devices present when the watcher started
+    pub fn initial(&self) -> &DeviceSnapshot {
+        &self.initial
+    }
+
+    // Changes noticed since the last call, without blocking
+    pub fn try_changes(&self) -> Vec<(SystemTime, DeviceChange)> {
+        self.changes.try_iter().collect()
+    }
+
+    // Blocks until the next change
+    pub fn next_change(&self) -> Option<(SystemTime, DeviceChange)> {
+        self.changes.recv().ok()
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        // Disconnecting the stop channel wakes the thread up immediately
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(devices: &[&str], default_input: Option<&str>) -> DeviceSnapshot {
+        DeviceSnapshot {
+            devices: devices.iter().map(|d| ("ALSA".to_string(), d.to_string())).collect(),
+            default_inputs: default_input.map(|d| ("ALSA".to_string(), d.to_string())).into_iter().collect(),
+            default_outputs: [("ALSA".to_string(), "default".to_string())].into(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_changes() {
+        let before = snapshot(&["default", "USB Mic"], Some("USB Mic"));
+        let after = snapshot(&["default", "Headset"], Some("Headset"));
+        assert!(before.changes(&before).is_empty());
+        assert!(before.contains("USB Mic") && !after.contains("USB Mic"));
+
+        let changes = before.changes(&after);
+        assert_eq!(
+            changes,
+            [
+                DeviceChange::Removed { host: "ALSA".to_string(), device: "USB Mic".to_string() },
+                DeviceChange::Added { host: "ALSA".to_string(), device: "Headset".to_string() },
+                DeviceChange::DefaultInputChanged {
+                    host: "ALSA".to_string(),
+                    from: Some("USB Mic".to_string()),
+                    to: Some("Headset".to_string()),
+                },
+            ]
+        );
+        assert_eq!(changes[2].to_string(), "ALSA: default input changed from USB Mic to Headset");
+
+        let unplugged = snapshot(&["default"], None);
+        assert_eq!(
+            after.changes(&unplugged)[1].to_string(),
+            "ALSA: default input changed from Headset to none"
+        );
+    }
+}
@@ -4,6 +4,7 @@ use std::collections::VecDeque;
 pub struct Window {
     buffer: VecDeque<f32>,
     size: usize,
+    sample_rate: u32,
 }
 
 impl Window {
@@ -12,6 +13,7 @@ impl Window {
         Self {
             buffer: VecDeque::with_capacity(size),
             size,
+            sample_rate,
         }
     }
 
@@ -48,8 +50,64 @@ impl Window {
 
     pub fn calculate_dbfs(&self) -> Option<f32> {
         // add epsilon to avoid log(0)
-        let rms = self.calculate_rms()? + 1e-10;
-        Some(20.0 * rms.log10())
+        let rms = self.calculate_rms()? as f64 + 1e-10;
+        Some((20.0 * rms.log10()) as f32)
+    }
+
+    // Returns the amplitude of a sinusoid at the given frequency in Hz,
+    // measured with a Hann window so that nearby components do not leak in
+    pub fn calculate_amplitude(&self, frequency: f64) -> Option<f64> {
+        if !self.is_ready() {
+            return None;
+        }
+
+        let n = self.buffer.len();
+        let omega = 2.0 * std::f64::consts::PI * frequency / self.sample_rate as f64;
+
+        let mut real = 0.0;
+        let mut imag = 0.0;
+        let mut window_sum = 0.0;
+        for (i, &sample) in self.buffer.iter().enumerate() {
+            let w = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos();
+            let angle = omega * i as f64;
+            real += w * sample as f64 * angle.cos();
+            imag -= w * sample as f64 * angle.sin();
+            window_sum += w;
+        }
+
+        Some(2.0 * (real * real + imag * imag).sqrt() / window_sum)
+    }
+
+    // Returns the amplitude spectrum of the window as (frequency in Hz,
+    // amplitude) pairs up to the Nyquist frequency, Hann-windowed and scaled
+    // like `calculate_amplitude`
+    pub fn calculate_spectrum(&self) -> Option<Vec<(f64, f64)>> {
+        if !self.is_ready() || self.size == 0 {
+            return None;
+        }
+
+        let n = self.buffer.len();
+        let mut window_sum = 0.0;
+        let windowed: Vec<f32> = self
+            .buffer
+            .iter()
+            .enumerate()
+            .map(|(i, &sample)| {
+                let w = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos();
+                window_sum += w;
+                (w * sample as f64) as f32
+            })
+            .collect();
+        let size = n.next_power_of_two();
+        let spectrum = crate::fft::real_fft(&windowed, size);
+        let bin_width = self.sample_rate as f64 / size as f64;
+        Some(
+            spectrum[..=size / 2]
+                .iter()
+                .enumerate()
+                .map(|(bin, value)| (bin as f64 * bin_width, 2.0 * value.norm() / window_sum))
+                .collect(),
+        )
     }
 
     // Returns the frequency spectrum of the window
@@ -0,0 +1,399 @@
+use cpal::traits::StreamTrait;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use cpal_toy::aggregator::Aggregator;
+use cpal_toy::bridge::{Bridge, BridgeConfigBuilder};
+use cpal_toy::cli::StepSpec;
+use cpal_toy::distortion::{analyze_distortion, DistortionConfigBuilder};
+use cpal_toy::dynamics::{Dynamics, DynamicsConfigBuilder, DynamicsMode, Limiter, LimiterConfigBuilder};
+use cpal_toy::equalizer::{live_equalizer, Band, Equalizer};
+use cpal_toy::generator::{Mls, Signal};
+use cpal_toy::impulse::Sweep;
+use cpal_toy::latency::{LatencyConfigBuilder, LatencyMeter};
+use cpal_toy::loopback::{play_tone, Capture, Loopback, MAX_LATENCY, SETTLE};
+use cpal_toy::monitoring::{input_bridge, input_stream, output_stream};
+use cpal_toy::octave::{Bandwidth, FilterBank, FilterBankConfigBuilder};
+use cpal_toy::playback::{play_sequence, tone_sequence};
+use cpal_toy::preview;
+use cpal_toy::processor::Chain;
+use cpal_toy::response::{log_frequencies, Excitation, SteppedSine, IMPULSE_RESPONSE_LENGTH};
+use cpal_toy::routing::RoutingMatrix;
+use cpal_toy::scope::{self, Scope};
+use cpal_toy::timing::TimingMonitor;
+use cpal_toy::virtual_audio::{AudioBackend, VirtualClock, VirtualDevice, VirtualDeviceConfigBuilder, VirtualStream, WavSource};
+use cpal_toy::window::Window;
+
+fn config(sample_rate: u32) -> cpal::StreamConfig {
+    cpal::StreamConfig {
+        channels: 1,
+        sample_rate: cpal::SampleRate(sample_rate),
+        buffer_size: cpal::BufferSize::Default,
+    }
+}
+
+// The input-to-output path of stereo_mic_play: a jittery input on its own
+// drifting clock, bridged to an output at another rate
+#[test]
+fn test_bridge_between_virtual_devices() {
+    let clock = VirtualClock::new();
+    let input = VirtualDevice::new(
+        &clock,
+        VirtualDeviceConfigBuilder::default()
+            .buffer_size(441)
+            .drift_ppm(200.0)
+            .jitter(Duration::from_millis(2))
+            .build()
+            .unwrap(),
+    );
+    // A second of a 1 kHz tone loops seamlessly
+    let tone = (0..44100)
+        .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 44100.0).sin())
+        .collect();
+    input.set_source(WavSource::from_samples(tone, 1, true));
+    let output = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(480).build().unwrap());
+
+    let (mut bridge, mut producer) = Bridge::with_config(
+        BridgeConfigBuilder::default()
+            .input_rate(44100)
+            .output_rate(48000)
+            .channels(1)
+            // Two input buffers leave room for the resampler to look ahead
+            .target_fill(882)
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    let monitor = bridge.monitor();
+
+    let input_stream = input
+        .build_input(&config(44100), move |data: &[f32], _: &_| {
+            producer.push_slice(data);
+        }, |_| {})
+        .unwrap();
+    let output_stream = output
+        .build_output(&config(48000), move |data: &mut [f32], _: &_| bridge.read(data), |_| {})
+        .unwrap();
+    input_stream.play().unwrap();
+    output_stream.play().unwrap();
+
+    // Settle, then look at a steady second of output
+    clock.advance(Duration::from_secs(30));
+    let underruns = monitor.underruns();
+    output.take_captured();
+    clock.advance(Duration::from_secs(1));
+    let captured = output.take_captured();
+
+    let mut window = Window::with_duration(Duration::from_secs(1), 48000);
+    window.add_samples(&captured);
+    let amplitude = window.calculate_amplitude(1000.0).unwrap();
+    assert!((amplitude - 0.5).abs() < 0.01, "amplitude {amplitude}");
+    assert_eq!(monitor.underruns(), underruns);
+    assert!((monitor.drift_ppm() - 200.0).abs() < 50.0, "drift {} ppm", monitor.drift_ppm());
+}
+
+// Amplitude of `frequency` in the first channel of interleaved frames
+fn amplitude(samples: &[f32], channels: usize, frequency: f64, sample_rate: u32) -> f64 {
+    let mono: Vec<f32> = samples.iter().step_by(channels).copied().collect();
+    let mut window = Window::with_duration(Duration::from_secs_f64(mono.len() as f64 / sample_rate as f64), sample_rate);
+    window.add_samples(&mono);
+    window.calculate_amplitude(frequency).unwrap()
+}
+
+// beep: steps cycled up to the total length, played in 16-bit
+#[test]
+fn test_beep() {
+    let clock = VirtualClock::new();
+    let output = VirtualDevice::new(
+        &clock,
+        VirtualDeviceConfigBuilder::default().buffer_size(480).latency(Duration::from_millis(5)).build().unwrap(),
+    );
+    let steps: Vec<StepSpec> = ["1000:100ms", "2000@0.5"].iter().map(|s| s.parse().unwrap()).collect();
+    let sequence = tone_sequence(&steps, Duration::from_millis(50), Duration::from_millis(400), 48000).unwrap();
+    let config = cpal::StreamConfig {
+        channels: 2,
+        sample_rate: cpal::SampleRate(48000),
+        buffer_size: cpal::BufferSize::Default,
+    };
+    let timing = play_sequence::<_, i16>(&output, &config, sequence, |duration| clock.advance(duration)).unwrap();
+    assert!(timing.callbacks >= 40, "{timing}");
+    assert_eq!(timing.latency, Duration::from_millis(5));
+
+    // 100ms of 1 kHz, 50ms of 2 kHz at half level, then the same again
+    // until 400ms, and silence while the last buffer plays out
+    let played = output.take_captured();
+    let ms = |ms: usize| ms * 48 * 2;
+    assert!(played.len() >= ms(400));
+    assert!((amplitude(&played[..ms(100)], 2, 1000.0, 48000) - 1.0).abs() < 0.01);
+    assert!((amplitude(&played[ms(100)..ms(150)], 2, 2000.0, 48000) - 0.5).abs() < 0.01);
+    assert!((amplitude(&played[ms(150)..ms(250)], 2, 1000.0, 48000) - 1.0).abs() < 0.01);
+    assert!(played[ms(400)..].iter().all(|&s| s == 0.0));
+    assert_eq!(played[0], played[1]);
+}
+
+// microphone: the start of every captured block
+#[test]
+fn test_microphone() {
+    let clock = VirtualClock::new();
+    let input = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(480).build().unwrap());
+    input.set_source(WavSource::from_samples((0..480).map(|i| i as f32 / 480.0).collect(), 1, true));
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let reported = lines.clone();
+    let stream = preview::input_stream(&input, &config(48000), move |line| reported.lock().unwrap().push(line)).unwrap();
+    stream.play().unwrap();
+    clock.advance(Duration::from_millis(100));
+
+    let lines = lines.lock().unwrap();
+    assert!(lines.len() >= 10, "{} blocks", lines.len());
+    for line in lines.iter() {
+        assert!(line.starts_with("Received 480 samples: [0.0, 0.0020833334,"), "{line}");
+        assert_eq!(line.matches(", ").count(), 19);
+    }
+}
+
+// mic_oscilloscope: captured audio into the trace, the level meter and the
+// analyser
+#[test]
+fn test_mic_oscilloscope() {
+    let clock = VirtualClock::new();
+    let input = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(480).build().unwrap());
+    let tone = (0..48000)
+        .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / 48000.0).sin())
+        .collect();
+    input.set_source(WavSource::from_samples(tone, 1, true));
+
+    let analyser = FilterBank::with_config(
+        FilterBankConfigBuilder::default().bandwidth(Bandwidth::Octave).sample_rate(48000).build().unwrap(),
+    );
+    let (mut scope, scope_input) = Scope::new(48000, 1, 9600, Chain::new(), analyser);
+    let stream = scope::input_stream(&input, &config(48000), Arc::new(Mutex::new(scope_input)), |_| {}).unwrap();
+    stream.play().unwrap();
+
+    // Updated at about the redraw rate
+    for _ in 0..30 {
+        clock.advance(Duration::from_millis(33));
+        scope.update();
+    }
+
+    assert_eq!(scope.history().len(), 9600);
+    assert!(scope.history().iter().any(|&s| s > 0.49));
+    // A sine at half the full scale
+    let dbfs = scope.window().calculate_dbfs().unwrap();
+    assert!((dbfs + 9.0).abs() < 0.1, "{dbfs} dBFS");
+    let analyser = scope.analyser();
+    let levels = analyser.levels_db();
+    let (loudest, _) = levels.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).unwrap();
+    assert_eq!(analyser.bands()[loudest].nominal, 1000.0);
+    assert!(scope.timing().snapshot().callbacks >= 90);
+}
+
+// latency: an MLS through a cable from an output to an input
+#[test]
+fn test_latency() {
+    let clock = VirtualClock::new();
+    let output = VirtualDevice::new(
+        &clock,
+        VirtualDeviceConfigBuilder::default().buffer_size(256).latency(Duration::from_millis(3)).build().unwrap(),
+    );
+    let input = VirtualDevice::new(
+        &clock,
+        VirtualDeviceConfigBuilder::default().buffer_size(128).latency(Duration::from_millis(2)).build().unwrap(),
+    );
+    input.set_source(output.cable(1));
+
+    let mut meter = LatencyMeter::start(
+        &output,
+        &config(48000),
+        &input,
+        &config(48000),
+        LatencyConfigBuilder::default()
+            .reference(Mls::new(12).take_values(Mls::new(12).len()))
+            .max_latency(Duration::from_millis(100))
+            .build()
+            .unwrap(),
+        {
+            let clock = clock.clone();
+            move || clock.now()
+        },
+    )
+    .unwrap();
+
+    let mut latencies = Vec::new();
+    for _ in 0..3 {
+        let measurement = meter.measure(|duration| clock.advance(duration)).unwrap();
+        assert!(measurement.clarity > 8.0, "clarity {}", measurement.clarity);
+        // The cpal timestamps report what the devices are configured with
+        assert!((measurement.reported - 240.0).abs() < 0.5, "reported {}", measurement.reported);
+        latencies.push(measurement.latency);
+    }
+    // The cable delivers instantly, into the start of an input buffer, and
+    // the input callback's time belongs to the last frame of its buffer
+    assert!(latencies.iter().all(|latency| (latency + 127.0).abs() < 0.01), "{latencies:?}");
+}
+
+// stereo_mic_play: two inputs at different rates, one of them gated, mixed
+// to the two channels of an output through an equalizer that changes while
+// playing and a limiter
+#[test]
+fn test_stereo_mic_play() {
+    let clock = VirtualClock::new();
+    let output = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(240).build().unwrap());
+    let mut bridges = Vec::new();
+    let mut streams = Vec::new();
+    for (index, (sample_rate, frequency)) in [(44100, 500.0), (48000, 1000.0)].into_iter().enumerate() {
+        // 5ms buffers, well within the bridges' reserve
+        let input = VirtualDevice::new(
+            &clock,
+            VirtualDeviceConfigBuilder::default().buffer_size(sample_rate as usize / 200).build().unwrap(),
+        );
+        let tone = (0..sample_rate)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect();
+        input.set_source(WavSource::from_samples(tone, 1, true));
+        let gate = (index == 0).then(|| {
+            Dynamics::with_config(
+                DynamicsConfigBuilder::default()
+                    .mode(DynamicsMode::Gate)
+                    .sample_rate(sample_rate)
+                    .channels(1)
+                    .threshold_db(-50.0)
+                    .hold(Duration::from_millis(50))
+                    .release(Duration::from_millis(150))
+                    .build()
+                    .unwrap(),
+            )
+        });
+        let (bridge, producer) = input_bridge(sample_rate, 48000, 1).unwrap();
+        bridges.push(bridge);
+        let monitor = TimingMonitor::new(sample_rate, 1);
+        streams.push(input_stream(&input, &config(sample_rate), producer, gate, monitor, index).unwrap());
+    }
+
+    let stereo = cpal::StreamConfig { channels: 2, ..config(48000) };
+    let (equalizer, mut control) = live_equalizer(Equalizer::new(&[], 48000, 2).unwrap());
+    let mut processors = Chain::new();
+    processors.push(equalizer);
+    processors.push(Limiter::with_config(
+        LimiterConfigBuilder::default().sample_rate(48000).channels(2).ceiling_db(-1.0).build().unwrap(),
+    ));
+    let aggregator = Aggregator::new(bridges, RoutingMatrix::spread(2, 2).unwrap()).unwrap();
+    let monitors = aggregator.monitors();
+    let output_stream =
+        output_stream(&output, &stereo, aggregator, processors, TimingMonitor::new(48000, 2)).unwrap();
+    for stream in &streams {
+        stream.play().unwrap();
+    }
+    output_stream.play().unwrap();
+
+    // Settle, as the bridges take a while to lock onto the input clocks
+    clock.advance(Duration::from_secs(30));
+    output.take_captured();
+    clock.advance(Duration::from_secs(1));
+    let played = output.take_captured();
+    let underruns: Vec<usize> = monitors.iter().map(|monitor| monitor.underruns()).collect();
+    assert!((amplitude(&played, 2, 500.0, 48000) - 0.5).abs() < 0.01);
+    assert!((amplitude(&played[1..], 2, 1000.0, 48000) - 0.5).abs() < 0.01);
+    assert!(amplitude(&played[1..], 2, 500.0, 48000) < 0.01);
+
+    // 6 dB less at 1 kHz, and a little less at 500 Hz, once the output has
+    // taken the update
+    let update = Equalizer::new(&["peak:1000:-6dB:2".parse::<Band>().unwrap()], 48000, 2).unwrap();
+    let expected = |frequency| 0.5 * 10f64.powf(update.response_db(frequency) / 20.0);
+    let (expected_low, expected_high) = (expected(500.0), expected(1000.0));
+    control.replace(update);
+    control.flush();
+    clock.advance(Duration::from_secs(1));
+    control.flush();
+    assert!(!control.is_pending());
+    output.take_captured();
+    clock.advance(Duration::from_secs(1));
+    let played = output.take_captured();
+    assert!((amplitude(&played, 2, 500.0, 48000) - expected_low).abs() < 0.01);
+    assert!((amplitude(&played[1..], 2, 1000.0, 48000) - expected_high).abs() < 0.01);
+    assert!(monitors.iter().map(|monitor| monitor.underruns()).eq(underruns));
+}
+
+// distortion --loopback: a tone through a cable, analysed window after window
+#[test]
+fn test_distortion() {
+    let clock = VirtualClock::new();
+    let output = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(480).build().unwrap());
+    let input = VirtualDevice::new(&clock, VirtualDeviceConfigBuilder::default().buffer_size(256).build().unwrap());
+    input.set_source(output.cable(2));
+    let stereo = cpal::StreamConfig { channels: 2, ..config(48000) };
+
+    let _tone = play_tone(&output, &stereo, 997.0, 0.5).unwrap();
+    let mut capture = Capture::start(&input, &config(48000), 0, 96000).unwrap();
+    let config = DistortionConfigBuilder::default().sample_rate(48000).fundamental(Some(997.0)).build().unwrap();
+    clock.advance(SETTLE);
+    capture.discard();
+
+    let mut window = vec![0.0; 48000];
+    for _ in 0..2 {
+        capture.record(&mut window, |duration| clock.advance(duration)).unwrap();
+        let report = analyze_distortion(&window, &config).unwrap();
+        assert!((report.fundamental.frequency - 997.0).abs() < 1.0, "{report}");
+        // RMS of a sine at half the full scale
+        assert!((report.fundamental.rms - 0.5 / 2f64.sqrt()).abs() < 0.01, "{report}");
+        assert!(report.thd() < 1e-3, "{report}");
+    }
+}
+
+// An output looped back into an input through a cable, as the loopback
+// measurements use them
+fn loopback(clock: &VirtualClock, signal: &[f32], frames: usize) -> Loopback<VirtualStream> {
+    let output = VirtualDevice::new(clock, VirtualDeviceConfigBuilder::default().buffer_size(480).build().unwrap());
+    let input = VirtualDevice::new(clock, VirtualDeviceConfigBuilder::default().buffer_size(256).build().unwrap());
+    input.set_source(output.cable(2));
+    let stereo = cpal::StreamConfig { channels: 2, ..config(48000) };
+    let loopback = Loopback::start(&output, &stereo, &input, &config(48000), signal.to_vec(), 0.5, frames).unwrap();
+    clock.advance(SETTLE);
+    loopback
+}
+
+// measure_ir: a sweep through a cable deconvolves into a single click
+#[test]
+fn test_measure_ir() {
+    let clock = VirtualClock::new();
+    let sweep = Sweep::new(20.0, 20000.0, Duration::from_secs(1), 48000);
+    let length = 9600;
+    let wanted = sweep.len() + length + (MAX_LATENCY.as_secs_f64() * 48000.0) as usize;
+    let mut loopback = loopback(&clock, sweep.samples(), wanted);
+
+    let recording = loopback.measure(wanted, |duration| clock.advance(duration)).unwrap();
+    let responses = sweep.analyze(&recording, length, 2).unwrap();
+    // Within an output and an input buffer of the start
+    assert!(responses.delay < 480 + 256, "{}", responses.delay);
+    let linear = &responses.linear;
+    let peak = linear[responses.pre_roll];
+    assert!(peak > 0.7, "{peak}");
+    let energy = |response: &[f32]| response.iter().map(|&x| x as f64 * x as f64).sum::<f64>();
+    let tail = energy(&linear[responses.pre_roll + 48..]);
+    assert!(tail < energy(linear) * 1e-2, "{tail}");
+    for harmonic in &responses.harmonics {
+        // A perfect cable has none; what shows is the deconvolution's own
+        assert!(energy(harmonic) < energy(linear) * 1e-2);
+    }
+}
+
+// frequency_response: both excitations find a cable flat at 0 dB, the sweep
+// within what cutting its impulse response short costs at low frequencies
+#[test]
+fn test_frequency_response() {
+    let sweep = Excitation::Sweep(Sweep::new(20.0, 20000.0, Duration::from_secs(1), 48000));
+    let steps = Excitation::Steps(SteppedSine::new(log_frequencies(100.0, 10000.0, 7), Duration::from_millis(100), 48000));
+    for (excitation, tolerance_db) in [(sweep, 0.5), (steps, 0.01)] {
+        let clock = VirtualClock::new();
+        let frames = excitation.samples().len()
+            + ((IMPULSE_RESPONSE_LENGTH + MAX_LATENCY).as_secs_f64() * 48000.0) as usize;
+        let mut loopback = loopback(&clock, excitation.samples(), frames);
+        let recording = loopback.measure(frames, |duration| clock.advance(duration)).unwrap();
+        let response = excitation.analyze(&recording, loopback.sample_rate()).unwrap();
+        let points: Vec<_> =
+            response.points().iter().filter(|point| (100.0..=10000.0).contains(&point.frequency)).collect();
+        assert!(!points.is_empty());
+        for point in points {
+            assert!(point.magnitude_db().abs() < tolerance_db, "{point:?}");
+        }
+    }
+}